@@ -0,0 +1,16 @@
+//! Database shared by the fuzz targets, run them from `fuzz/` with
+//! `cargo fuzz run <target>` or point `UDGER_DB` to another database
+
+use std::env;
+use std::path::PathBuf;
+
+use udger::Udger;
+
+pub fn udger() -> Udger {
+    let path = env::var("UDGER_DB").unwrap_or_else(|_| "../data/udgerdb_v3_full.dat".into());
+    let mut udger = Udger::new();
+    udger
+        .init(PathBuf::from(&path), 1000)
+        .unwrap_or_else(|err| panic!("{}: {}", path, err));
+    udger
+}
@@ -0,0 +1,90 @@
+#![no_main]
+
+use std::ffi::{c_char, c_int, c_void, CString};
+use std::ptr;
+
+use libfuzzer_sys::fuzz_target;
+use udger::Udger;
+
+mod common;
+
+// the handles are opaque, as for a C caller
+type UdgerCallBack = unsafe extern "C" fn(info: *const c_void, data: *mut c_void) -> c_int;
+
+extern "C" {
+    fn udger_data_alloc(udger: *const c_void, data: *mut *mut c_void) -> c_int;
+    fn udger_parse_ua(
+        udger: *const c_void,
+        udger_data: *mut c_void,
+        ua: *const c_char,
+        cb: UdgerCallBack,
+        data: *mut c_void,
+    ) -> c_int;
+    fn udger_parse_ua_bytes(
+        udger: *const c_void,
+        udger_data: *mut c_void,
+        ua: *const u8,
+        ua_len: usize,
+        cb: UdgerCallBack,
+        data: *mut c_void,
+    ) -> c_int;
+    fn ua_info_get_ua_string(info: *const c_void, buf: *mut *const c_char, len: *mut usize);
+    fn ua_info_to_string(
+        info: *const c_void,
+        buf: *mut *mut c_char,
+        len: *mut usize,
+        pretty: bool,
+    ) -> c_int;
+    fn free(ptr: *mut c_void);
+}
+
+/// Read a field back like a C caller would
+unsafe extern "C" fn callback(info: *const c_void, _data: *mut c_void) -> c_int {
+    let (mut buf, mut len) = (ptr::null(), 0);
+    ua_info_get_ua_string(info, &mut buf, &mut len);
+    let ua = std::slice::from_raw_parts(buf as *const u8, len);
+    assert!(std::str::from_utf8(ua).is_ok());
+
+    for pretty in [false, true] {
+        let (mut json, mut len) = (ptr::null_mut(), 0);
+        assert_eq!(ua_info_to_string(info, &mut json, &mut len, pretty), 0);
+        let bytes = std::slice::from_raw_parts(json as *const u8, len);
+        assert_eq!(bytes.last(), Some(&0));
+        assert!(std::str::from_utf8(&bytes[..len - 1]).is_ok());
+        free(json as *mut c_void);
+    }
+    assert_eq!(
+        ua_info_to_string(info, ptr::null_mut(), ptr::null_mut(), false),
+        -1
+    );
+    0
+}
+
+thread_local! {
+    static UDGER: (Box<Udger>, *mut c_void) = {
+        let udger = Box::new(common::udger());
+        let mut data = ptr::null_mut();
+        let handle = &*udger as *const Udger as *const c_void;
+        assert_eq!(unsafe { udger_data_alloc(handle, &mut data) }, 0);
+        (udger, data)
+    };
+}
+
+fuzz_target!(|ua: &[u8]| {
+    UDGER.with(|(udger, data)| unsafe {
+        let udger = &**udger as *const Udger as *const c_void;
+        let rc = udger_parse_ua_bytes(
+            udger,
+            *data,
+            ua.as_ptr(),
+            ua.len(),
+            callback,
+            ptr::null_mut(),
+        );
+        assert!(rc == 0 || rc == -1);
+        if let Ok(ua) = CString::new(ua) {
+            let rc = udger_parse_ua(udger, *data, ua.as_ptr(), callback, ptr::null_mut());
+            assert!(rc == 0 || rc == -1);
+        }
+    })
+});
@@ -0,0 +1,24 @@
+#![no_main]
+
+use std::cell::RefCell;
+
+use libfuzzer_sys::fuzz_target;
+use udger::{Udger, UdgerData};
+
+mod common;
+
+thread_local! {
+    static UDGER: Udger = common::udger();
+    static DATA: RefCell<UdgerData> =
+        RefCell::new(UDGER.with(|udger| udger.alloc_udger_data().unwrap()));
+}
+
+fuzz_target!(|ua: &[u8]| {
+    UDGER.with(|udger| {
+        DATA.with(|data| {
+            let data = &mut data.borrow_mut();
+            let _ = udger.parse_ua_bytes(ua, data);
+            let _ = udger.parse_ua(&String::from_utf8_lossy(ua), data);
+        })
+    })
+});
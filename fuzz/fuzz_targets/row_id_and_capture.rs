@@ -0,0 +1,42 @@
+#![no_main]
+
+use std::cell::RefCell;
+
+use libfuzzer_sys::fuzz_target;
+use udger::fuzzing::RegexSequenceScratch;
+use udger::Udger;
+
+mod common;
+
+thread_local! {
+    static UDGER: Udger = common::udger();
+    static SCRATCHES: RefCell<Vec<RegexSequenceScratch>> = RefCell::new(UDGER.with(|udger| {
+        udger
+            .regex_sequences()
+            .iter()
+            .map(|sequence| sequence.alloc_scratch().unwrap())
+            .collect()
+    }));
+}
+
+// the first 8 bytes are 4 word ids, the rest the User-Agent
+fuzz_target!(|input: &[u8]| {
+    let (words, ua) = input.split_at(input.len().min(8));
+    let word_ids = words
+        .chunks_exact(2)
+        .map(|id| u16::from_le_bytes([id[0], id[1]]))
+        .collect::<Vec<u16>>();
+    UDGER.with(|udger| {
+        SCRATCHES.with(|scratches| {
+            let scratches = &mut scratches.borrow_mut();
+            for (sequence, scratch) in udger.regex_sequences().iter().zip(scratches.iter_mut()) {
+                let found = sequence
+                    .get_row_id_and_capture(&ua, scratch, &word_ids.iter())
+                    .unwrap();
+                if let Some((_, Some(capture))) = found {
+                    assert!(capture.start <= capture.end && capture.end <= ua.len());
+                }
+            }
+        })
+    })
+});
@@ -0,0 +1,31 @@
+#![no_main]
+
+use std::cell::RefCell;
+
+use libfuzzer_sys::fuzz_target;
+use udger::fuzzing::WordDetectorScratch;
+use udger::Udger;
+
+mod common;
+
+thread_local! {
+    static UDGER: Udger = common::udger();
+    static SCRATCHES: RefCell<Vec<WordDetectorScratch>> = RefCell::new(UDGER.with(|udger| {
+        udger
+            .word_detectors()
+            .iter()
+            .map(|detector| detector.alloc_scratch().unwrap())
+            .collect()
+    }));
+}
+
+fuzz_target!(|ua: &[u8]| {
+    UDGER.with(|udger| {
+        SCRATCHES.with(|scratches| {
+            let scratches = &mut scratches.borrow_mut();
+            for (detector, scratch) in udger.word_detectors().iter().zip(scratches.iter_mut()) {
+                detector.get_word_ids(&ua, scratch).unwrap();
+            }
+        })
+    })
+});
@@ -0,0 +1,558 @@
+//! Access log parsing and traffic breakdowns
+//!
+//! Supports the Nginx/Apache common and combined log formats, as well as
+//! user-supplied format strings in either Nginx (`$remote_addr`) or Apache
+//! (`%h`) syntax. Formats without a User-Agent field, like the common one,
+//! are accepted and their requests are reported unclassified.
+
+use std::collections::BTreeMap;
+use std::io::BufRead;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{UaInfo, Udger, UdgerData};
+
+/// Common log format, which has no User-Agent
+pub const COMMON: &str =
+    r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent"#;
+pub const COMBINED: &str = r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent""#;
+
+/// A log line field we know how to interpret
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    RemoteAddr,
+    RemoteUser,
+    Time,
+    Request,
+    Status,
+    Bytes,
+    Referer,
+    UserAgent,
+    ForwardedFor,
+    /// Any other field, matched but ignored
+    Other,
+}
+
+impl Field {
+    fn from_nginx(name: &str) -> Field {
+        match name {
+            "remote_addr" | "realip_remote_addr" => Field::RemoteAddr,
+            "remote_user" => Field::RemoteUser,
+            "time_local" | "time_iso8601" => Field::Time,
+            "request" => Field::Request,
+            "status" => Field::Status,
+            "body_bytes_sent" | "bytes_sent" => Field::Bytes,
+            "http_referer" => Field::Referer,
+            "http_user_agent" => Field::UserAgent,
+            "http_x_forwarded_for" => Field::ForwardedFor,
+            _ => Field::Other,
+        }
+    }
+
+    fn from_apache(directive: char, arg: Option<&str>) -> Field {
+        match (directive, arg) {
+            ('h', _) | ('a', _) => Field::RemoteAddr,
+            ('u', _) => Field::RemoteUser,
+            ('t', _) => Field::Time,
+            ('r', _) => Field::Request,
+            ('s', _) => Field::Status,
+            ('b', _) | ('B', _) | ('O', _) => Field::Bytes,
+            ('i', Some(header)) if header.eq_ignore_ascii_case("referer") => Field::Referer,
+            ('i', Some(header)) if header.eq_ignore_ascii_case("user-agent") => Field::UserAgent,
+            ('i', Some(header)) if header.eq_ignore_ascii_case("x-forwarded-for") => {
+                Field::ForwardedFor
+            }
+            _ => Field::Other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Item {
+    Literal(String),
+    Field(Field),
+}
+
+/// A compiled log format
+#[derive(Debug, Clone)]
+pub struct LogFormat {
+    items: Vec<Item>,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    /// Accepts `common`, `combined` or a format string
+    fn from_str(s: &str) -> Result<LogFormat> {
+        match s {
+            "common" => LogFormat::new(COMMON),
+            "combined" => LogFormat::new(COMBINED),
+            _ => LogFormat::new(s),
+        }
+    }
+}
+
+impl LogFormat {
+    /// Compile a Nginx `log_format` or Apache `LogFormat` string
+    pub fn new(format: &str) -> Result<LogFormat> {
+        let mut items = Vec::new();
+        let mut literal = String::new();
+        let mut chars = format.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            let field = match c {
+                '$' => {
+                    let mut name = String::new();
+                    while let Some(&c) = chars.peek() {
+                        if !(c.is_ascii_alphanumeric() || c == '_') {
+                            break;
+                        }
+                        name.push(c);
+                        chars.next();
+                    }
+                    if name.is_empty() {
+                        return Err(anyhow!("empty variable name in log format `{}`", format));
+                    }
+                    Field::from_nginx(&name)
+                }
+                '%' => {
+                    if chars.peek() == Some(&'%') {
+                        chars.next();
+                        literal.push('%');
+                        continue;
+                    }
+                    // skip status code conditions and the </> modifiers
+                    while let Some(&c) = chars.peek() {
+                        if !(c == '<' || c == '>' || c == '!' || c == ',' || c.is_ascii_digit()) {
+                            break;
+                        }
+                        chars.next();
+                    }
+                    let mut arg = None;
+                    if chars.peek() == Some(&'{') {
+                        chars.next();
+                        let mut value = String::new();
+                        loop {
+                            match chars.next() {
+                                None => {
+                                    return Err(anyhow!("unclosed `{{` in log format `{}`", format))
+                                }
+                                Some('}') => break,
+                                Some(c) => value.push(c),
+                            }
+                        }
+                        arg = Some(value);
+                    }
+                    let directive = chars
+                        .next()
+                        .ok_or_else(|| anyhow!("dangling `%` in log format `{}`", format))?;
+                    Field::from_apache(directive, arg.as_deref())
+                }
+                _ => {
+                    literal.push(c);
+                    continue;
+                }
+            };
+
+            if literal.is_empty() {
+                if let Some(Item::Field(_)) = items.last() {
+                    return Err(anyhow!(
+                        "fields must be separated by a literal in log format `{}`",
+                        format
+                    ));
+                }
+            } else {
+                items.push(Item::Literal(std::mem::take(&mut literal)));
+            }
+            items.push(Item::Field(field));
+        }
+        if !literal.is_empty() {
+            items.push(Item::Literal(literal));
+        }
+
+        Ok(LogFormat { items })
+    }
+
+    /// Parse a log line, returns None if it doesn't match the format
+    ///
+    /// Anything following the last item of the format is ignored, so logs with
+    /// extra trailing fields still match the common and combined formats.
+    pub fn parse<'a>(&self, line: &'a str) -> Option<LogRecord<'a>> {
+        let mut record = LogRecord::default();
+        let mut rest = line;
+        let mut quoted = false;
+
+        for (i, item) in self.items.iter().enumerate() {
+            match item {
+                Item::Literal(literal) => {
+                    rest = rest.strip_prefix(literal.as_str())?;
+                    quoted = literal.ends_with('"');
+                }
+                Item::Field(field) => {
+                    let end = match self.items.get(i + 1) {
+                        Some(Item::Literal(next)) => find_literal(rest, next, quoted)?,
+                        // a last unquoted field ends at the first trailing field
+                        _ if !quoted => rest.find(' ').unwrap_or(rest.len()),
+                        _ => rest.len(),
+                    };
+                    record.set(*field, &rest[..end])?;
+                    rest = &rest[end..];
+                }
+            }
+        }
+
+        Some(record)
+    }
+}
+
+/// Find the end of a field value, honoring backslash escapes inside quotes
+fn find_literal(s: &str, literal: &str, quoted: bool) -> Option<usize> {
+    if !quoted {
+        return s.find(literal);
+    }
+
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' {
+            i += 2;
+            continue;
+        }
+        if bytes[i..].starts_with(literal.as_bytes()) {
+            return Some(i);
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Fields extracted from a single log line, "-" values are reported as None
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LogRecord<'a> {
+    pub remote_addr: Option<&'a str>,
+    pub remote_user: Option<&'a str>,
+    pub time: Option<&'a str>,
+    pub request: Option<&'a str>,
+    pub status: Option<u16>,
+    pub bytes: u64,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    pub forwarded_for: Option<&'a str>,
+}
+
+impl<'a> LogRecord<'a> {
+    fn set(&mut self, field: Field, value: &'a str) -> Option<()> {
+        let value = match value {
+            "" | "-" => None,
+            value => Some(value),
+        };
+        match field {
+            Field::RemoteAddr => self.remote_addr = value,
+            Field::RemoteUser => self.remote_user = value,
+            Field::Time => self.time = value,
+            Field::Request => self.request = value,
+            Field::Status => self.status = value.map(|v| v.parse()).transpose().ok()?,
+            Field::Bytes => self.bytes = value.map_or(Ok(0), |v| v.parse()).ok()?,
+            Field::Referer => self.referer = value,
+            Field::UserAgent => self.user_agent = value,
+            Field::ForwardedFor => self.forwarded_for = value,
+            Field::Other => {}
+        }
+        Some(())
+    }
+
+    /// The client IP, the first X-Forwarded-For hop is preferred over the peer address
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        let forwarded = self
+            .forwarded_for
+            .and_then(|xff| xff.split(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+        forwarded.or_else(|| self.remote_addr.and_then(|ip| ip.parse().ok()))
+    }
+}
+
+/// Request count and byte total of a traffic slice
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Traffic {
+    pub requests: u64,
+    pub bytes: u64,
+}
+
+impl Traffic {
+    fn add(&mut self, bytes: u64) {
+        self.requests += 1;
+        self.bytes += bytes;
+    }
+
+    fn merge(&mut self, other: &Traffic) {
+        self.requests += other.requests;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Traffic breakdowns of an access log
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct LogReport {
+    /// All the lines matching the log format
+    pub total: Traffic,
+    /// Lines not matching the log format
+    pub malformed: u64,
+    /// Lines without User-Agent, included in `total`
+    pub no_user_agent: u64,
+    /// Lines whose User-Agent failed to be parsed, included in `total`
+    pub errors: u64,
+    pub ua_class: BTreeMap<String, Traffic>,
+    pub ua_family: BTreeMap<String, Traffic>,
+    pub os_family: BTreeMap<String, Traffic>,
+    pub device_class: BTreeMap<String, Traffic>,
+    /// Crawler requests only
+    pub crawler_category: BTreeMap<String, Traffic>,
+}
+
+const UNKNOWN: &str = "unknown";
+
+fn add_to(map: &mut BTreeMap<String, Traffic>, key: &str, bytes: u64) {
+    let key = if key.is_empty() { UNKNOWN } else { key };
+    match map.get_mut(key) {
+        Some(traffic) => traffic.add(bytes),
+        None => map.entry(key.to_string()).or_default().add(bytes),
+    }
+}
+
+fn merge_into(map: &mut BTreeMap<String, Traffic>, other: &BTreeMap<String, Traffic>) {
+    for (key, traffic) in other {
+        map.entry(key.clone()).or_default().merge(traffic);
+    }
+}
+
+impl LogReport {
+    /// Account a classified request
+    pub fn add(&mut self, info: &UaInfo, bytes: u64) {
+        self.total.add(bytes);
+        add_to(&mut self.ua_class, &info.ua_class, bytes);
+        add_to(&mut self.ua_family, &info.ua_family, bytes);
+        add_to(&mut self.os_family, &info.os_family, bytes);
+        add_to(&mut self.device_class, &info.device_class, bytes);
+        if !info.crawler_category.is_empty() {
+            add_to(&mut self.crawler_category, &info.crawler_category, bytes);
+        }
+    }
+
+    /// Account a request which could not be classified, as unknown in every
+    /// breakdown but the crawler one
+    pub fn add_unclassified(&mut self, bytes: u64) {
+        self.total.add(bytes);
+        add_to(&mut self.ua_class, UNKNOWN, bytes);
+        add_to(&mut self.ua_family, UNKNOWN, bytes);
+        add_to(&mut self.os_family, UNKNOWN, bytes);
+        add_to(&mut self.device_class, UNKNOWN, bytes);
+    }
+
+    /// Parse and account a single log line
+    pub fn add_line(
+        &mut self,
+        udger: &Udger,
+        data: &mut UdgerData,
+        format: &LogFormat,
+        line: &str,
+    ) {
+        let record = match format.parse(line) {
+            None => {
+                self.malformed += 1;
+                return;
+            }
+            Some(record) => record,
+        };
+
+        let ua = match record.user_agent {
+            None => {
+                self.no_user_agent += 1;
+                self.add_unclassified(record.bytes);
+                return;
+            }
+            Some(ua) => ua,
+        };
+
+        match udger.parse_ua(&ua, data) {
+            Err(_) => {
+                self.errors += 1;
+                self.add_unclassified(record.bytes);
+            }
+            Ok(info) => self.add(&info, record.bytes),
+        }
+    }
+
+    /// Merge the report of another log, or another part of the same log
+    pub fn merge(&mut self, other: &LogReport) {
+        self.total.merge(&other.total);
+        self.malformed += other.malformed;
+        self.no_user_agent += other.no_user_agent;
+        self.errors += other.errors;
+        merge_into(&mut self.ua_class, &other.ua_class);
+        merge_into(&mut self.ua_family, &other.ua_family);
+        merge_into(&mut self.os_family, &other.os_family);
+        merge_into(&mut self.device_class, &other.device_class);
+        merge_into(&mut self.crawler_category, &other.crawler_category);
+    }
+}
+
+/// Build a report of a whole log, on the calling thread
+pub fn analyze<R>(
+    udger: &Udger,
+    data: &mut UdgerData,
+    format: &LogFormat,
+    input: R,
+) -> Result<LogReport>
+where
+    R: BufRead,
+{
+    let mut report = LogReport::default();
+    for line in input.split(b'\n') {
+        let line = line?;
+        report.add_line(udger, data, format, &String::from_utf8_lossy(&line));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINE: &str = r#"192.0.2.7 - frank [10/Oct/2000:13:55:36 -0700] "GET /a\"b.gif HTTP/1.0" 200 2326 "http://www.example.com/start.html" "Mozilla/5.0 (Windows NT 10.0; WOW64; rv:40.0) Gecko/20100101 Firefox/40.0""#;
+
+    #[test]
+    fn test_parse_combined() {
+        let format = LogFormat::from_str("combined").unwrap();
+        let record = format.parse(LINE).unwrap();
+
+        assert_eq!(record.remote_addr, Some("192.0.2.7"));
+        assert_eq!(record.remote_user, Some("frank"));
+        assert_eq!(record.time, Some("10/Oct/2000:13:55:36 -0700"));
+        assert_eq!(record.request, Some(r#"GET /a\"b.gif HTTP/1.0"#));
+        assert_eq!(record.status, Some(200));
+        assert_eq!(record.bytes, 2326);
+        assert_eq!(record.referer, Some("http://www.example.com/start.html"));
+        assert_eq!(
+            record.user_agent,
+            Some("Mozilla/5.0 (Windows NT 10.0; WOW64; rv:40.0) Gecko/20100101 Firefox/40.0")
+        );
+        assert_eq!(record.client_ip(), Some("192.0.2.7".parse().unwrap()));
+
+        // trailing fields are ignored
+        let line = format!("{} \"203.0.113.1\"", LINE);
+        assert!(format.parse(&line).is_some());
+        assert!(format.parse("garbage").is_none());
+    }
+
+    #[test]
+    fn test_parse_apache_format() {
+        let format = LogFormat::new(
+            r#"%h %l %u %t "%r" %>s %b "%{Referer}i" "%{User-agent}i" "%{X-Forwarded-For}i""#,
+        )
+        .unwrap();
+        let line = format!("{} \"198.51.100.3, 10.0.0.1\"", LINE);
+        let record = format.parse(&line).unwrap();
+
+        assert_eq!(record.status, Some(200));
+        assert_eq!(record.forwarded_for, Some("198.51.100.3, 10.0.0.1"));
+        assert_eq!(record.client_ip(), Some("198.51.100.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_dash_values() {
+        let format = LogFormat::from_str("combined").unwrap();
+        let record = format
+            .parse(r#"::1 - - [10/Oct/2000:13:55:36 -0700] "-" 400 - "-" "-""#)
+            .unwrap();
+
+        assert_eq!(record.remote_user, None);
+        assert_eq!(record.bytes, 0);
+        assert_eq!(record.user_agent, None);
+        assert_eq!(record.client_ip(), Some("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_common() {
+        let format = LogFormat::from_str("common").unwrap();
+        let line =
+            r#"192.0.2.7 - frank [10/Oct/2000:13:55:36 -0700] "GET /a.gif HTTP/1.0" 200 2326"#;
+        let record = format.parse(line).unwrap();
+
+        assert_eq!(record.status, Some(200));
+        assert_eq!(record.bytes, 2326);
+        assert_eq!(record.user_agent, None);
+        // combined lines match as well, their User-Agent is ignored
+        assert_eq!(format.parse(LINE).unwrap().user_agent, None);
+    }
+
+    #[test]
+    fn test_invalid_format() {
+        assert!(LogFormat::new("$remote_addr$http_user_agent").is_err());
+        assert!(LogFormat::new("%{User-Agent").is_err());
+    }
+
+    #[test]
+    fn test_report() {
+        let mut report = LogReport::default();
+        let browser = UaInfo {
+            ua_class: String::from("Browser"),
+            ua_family: String::from("Firefox"),
+            os_family: String::from("Windows"),
+            device_class: String::from("Desktop"),
+            ..Default::default()
+        };
+        let crawler = UaInfo {
+            ua_class: String::from("Crawler"),
+            ua_family: String::from("Googlebot"),
+            crawler_category: String::from("Search engine bot"),
+            ..Default::default()
+        };
+        report.add(&browser, 100);
+        report.add(&browser, 50);
+        report.add(&crawler, 10);
+
+        let mut other = LogReport::default();
+        other.add_unclassified(5);
+        report.merge(&other);
+
+        assert_eq!(
+            report.total,
+            Traffic {
+                requests: 4,
+                bytes: 165
+            }
+        );
+        assert_eq!(
+            report.ua_class["Browser"],
+            Traffic {
+                requests: 2,
+                bytes: 150
+            }
+        );
+        assert_eq!(
+            report.ua_class["unknown"],
+            Traffic {
+                requests: 1,
+                bytes: 5
+            }
+        );
+        assert_eq!(report.os_family["unknown"].requests, 2);
+        assert_eq!(report.device_class["unknown"].bytes, 15);
+        // each breakdown accounts for all the requests
+        for breakdown in [
+            &report.ua_class,
+            &report.ua_family,
+            &report.os_family,
+            &report.device_class,
+        ] {
+            let mut sum = Traffic::default();
+            breakdown.values().for_each(|traffic| sum.merge(traffic));
+            assert_eq!(sum, report.total);
+        }
+        assert_eq!(report.crawler_category.len(), 1);
+        assert_eq!(report.crawler_category["Search engine bot"].bytes, 10);
+    }
+}
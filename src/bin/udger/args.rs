@@ -0,0 +1,111 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+
+/// Minimal command line parser
+///
+/// Supports `--name value`, `--name=value`, boolean `--flag` switches and
+/// positional arguments.
+#[derive(Debug, Default)]
+pub struct Args {
+    options: HashMap<String, String>,
+    flags: HashSet<String>,
+    positional: Vec<String>,
+}
+
+impl Args {
+    /// Parse arguments, `switches` lists the options which don't take a value
+    pub fn parse<I, S>(args: I, switches: &[&str]) -> Result<Args>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut parsed = Args::default();
+        let mut iter = args.into_iter();
+        while let Some(arg) = iter.next() {
+            let arg = arg.as_ref();
+            let name = match arg.strip_prefix("--") {
+                None => {
+                    parsed.positional.push(arg.to_string());
+                    continue;
+                }
+                Some(name) => name,
+            };
+
+            if let Some((name, value)) = name.split_once('=') {
+                parsed.options.insert(name.to_string(), value.to_string());
+            } else if switches.contains(&name) {
+                parsed.flags.insert(name.to_string());
+            } else {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| anyhow!("option --{} requires a value", name))?;
+                parsed
+                    .options
+                    .insert(name.to_string(), value.as_ref().to_string());
+            }
+        }
+        Ok(parsed)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(|v| v.as_str())
+    }
+
+    pub fn get_or<'a>(&'a self, name: &str, default: &'a str) -> &'a str {
+        self.get(name).unwrap_or(default)
+    }
+
+    pub fn get_parsed<T>(&self, name: &str, default: T) -> Result<T>
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+    {
+        match self.get(name) {
+            None => Ok(default),
+            Some(v) => v
+                .parse()
+                .map_err(|err| anyhow!("invalid value for --{}: {}", name, err)),
+        }
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.contains(name)
+    }
+
+    pub fn positional(&self) -> &[String] {
+        &self.positional
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        let args = Args::parse(
+            [
+                "--db",
+                "udger.dat",
+                "--threads=4",
+                "--pretty",
+                "input.jsonl",
+            ],
+            &["pretty"],
+        )
+        .unwrap();
+
+        assert_eq!(args.get("db"), Some("udger.dat"));
+        assert_eq!(args.get_parsed("threads", 1_usize).unwrap(), 4);
+        assert_eq!(args.get_parsed("cache", 10000_usize).unwrap(), 10000);
+        assert!(args.flag("pretty"));
+        assert_eq!(args.positional(), &["input.jsonl".to_string()]);
+    }
+
+    #[test]
+    fn test_parse_args_missing_value() {
+        assert!(Args::parse(["--db"], &[]).is_err());
+    }
+}
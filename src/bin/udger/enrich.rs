@@ -0,0 +1,197 @@
+use std::io::{self, BufWriter, Write};
+
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Value};
+
+use crate::args::Args;
+use crate::{load_udger, open_inputs, pool, worker_threads};
+
+pub const USAGE: &str = "\
+udger enrich --db <path> [options] [file...]
+
+Read newline-delimited JSON records from the given files (default: stdin), parse the
+User-Agent found at --field and write every record to stdout with the parse
+result nested under --key. Lines which are not JSON objects, or which don't
+contain a string at --field, are passed through unchanged.
+
+Options:
+    --field <path>    User-Agent field path [default: .user_agent]
+    --key <path>      output field path [default: .udger]
+    --threads <n>     worker threads [default: available parallelism]
+    --cache <n>       per-thread LRU cache capacity [default: 10000]";
+
+pub const SWITCHES: &[&str] = &[];
+
+/// Where to read the User-Agent from and where to write the result to
+#[derive(Debug)]
+pub struct EnrichOptions {
+    pub field: Vec<String>,
+    pub key: Vec<String>,
+}
+
+pub fn run(args: &Args) -> Result<()> {
+    let options = EnrichOptions {
+        field: parse_path(args.get_or("field", ".user_agent"))?,
+        key: parse_path(args.get_or("key", ".udger"))?,
+    };
+    let udger = load_udger(args)?;
+    let threads = worker_threads(args)?;
+
+    let input = open_inputs(args.positional())?;
+    let stdout = io::stdout();
+    let mut output = BufWriter::new(stdout.lock());
+
+    pool::map_lines(
+        &udger,
+        threads,
+        input,
+        |udger, data, line| {
+            enrich_line(line, &options, |ua| {
+                let info = udger.parse_ua(&ua, data).ok()?;
+                serde_json::to_value(&*info).ok()
+            })
+        },
+        |line| {
+            output.write_all(&line)?;
+            output.write_all(b"\n")?;
+            Ok(())
+        },
+    )?;
+
+    output.flush()?;
+    Ok(())
+}
+
+/// Parse a field path like `.http.user_agent` into its components
+pub fn parse_path(path: &str) -> Result<Vec<String>> {
+    let path = path.strip_prefix('.').unwrap_or(path);
+    let keys: Vec<String> = path.split('.').map(String::from).collect();
+    if keys.iter().any(|key| key.is_empty()) {
+        return Err(anyhow!("invalid field path `{}`", path));
+    }
+    Ok(keys)
+}
+
+fn lookup<'a>(value: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter()
+        .try_fold(value, |value, key| value.get(key.as_str()))
+}
+
+/// Insert `new` at `path`, creating intermediate objects when missing
+///
+/// Returns false if one of the parents exists but is not an object.
+fn insert(value: &mut Value, path: &[String], new: Value) -> bool {
+    let (last, parents) = match path.split_last() {
+        None => return false,
+        Some(v) => v,
+    };
+
+    let mut current = value;
+    for key in parents {
+        current = match current.as_object_mut() {
+            None => return false,
+            Some(obj) => obj
+                .entry(key.clone())
+                .or_insert_with(|| Value::Object(Map::new())),
+        };
+    }
+
+    match current.as_object_mut() {
+        None => false,
+        Some(obj) => {
+            obj.insert(last.clone(), new);
+            true
+        }
+    }
+}
+
+/// Enrich a single JSON line
+///
+/// `classify` turns the User-Agent into the value stored under the output key,
+/// returning None leaves the line untouched.
+pub fn enrich_line<F>(line: &[u8], options: &EnrichOptions, classify: F) -> Vec<u8>
+where
+    F: FnOnce(&str) -> Option<Value>,
+{
+    let mut record: Value = match serde_json::from_slice(line) {
+        Err(_) => return line.to_vec(),
+        Ok(record) => record,
+    };
+    if !record.is_object() {
+        return line.to_vec();
+    }
+
+    let ua = match lookup(&record, &options.field) {
+        Some(Value::String(ua)) => ua.clone(),
+        _ => return line.to_vec(),
+    };
+    let parsed = match classify(&ua) {
+        None => return line.to_vec(),
+        Some(parsed) => parsed,
+    };
+    if !insert(&mut record, &options.key, parsed) {
+        return line.to_vec();
+    }
+
+    match serde_json::to_vec(&record) {
+        Err(_) => line.to_vec(),
+        Ok(enriched) => enriched,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn options(field: &str, key: &str) -> EnrichOptions {
+        EnrichOptions {
+            field: parse_path(field).unwrap(),
+            key: parse_path(key).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_parse_path() {
+        assert_eq!(
+            parse_path(".http.user_agent").unwrap(),
+            ["http", "user_agent"]
+        );
+        assert_eq!(parse_path("user_agent").unwrap(), ["user_agent"]);
+        assert!(parse_path(".").is_err());
+        assert!(parse_path(".http..user_agent").is_err());
+    }
+
+    #[test]
+    fn test_enrich_line() {
+        let opts = options(".http.user_agent", ".enrich.udger");
+        let line = br#"{"ts":1,"http":{"user_agent":"Firefox"}}"#;
+
+        let enriched = enrich_line(line, &opts, |ua| Some(json!({ "ua": ua })));
+        let enriched: Value = serde_json::from_slice(&enriched).unwrap();
+        assert_eq!(enriched["enrich"]["udger"]["ua"], "Firefox");
+        assert_eq!(enriched["ts"], 1);
+    }
+
+    #[test]
+    fn test_enrich_line_passthrough() {
+        let opts = options(".http.user_agent", ".udger");
+        let classify = |_: &str| Some(json!({}));
+
+        for line in [
+            &b"not json"[..],
+            &b"[1, 2, 3]"[..],
+            &br#"{"http":{}}"#[..],
+            &br#"{"http":{"user_agent":42}}"#[..],
+            &b"\xff\xfe"[..],
+            &b""[..],
+        ] {
+            assert_eq!(enrich_line(line, &opts, classify), line);
+        }
+
+        let opts = options(".user_agent", ".user_agent.udger");
+        let line = br#"{"user_agent":"Firefox"}"#;
+        assert_eq!(enrich_line(line, &opts, classify), line);
+    }
+}
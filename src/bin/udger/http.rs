@@ -0,0 +1,778 @@
+//! Just enough HTTP/1.1 for the local services
+
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+
+use serde::Serialize;
+
+/// Upper bound of the request line and headers
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+/// Upper bound of request bodies
+pub const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// Malformed request, answered with the given status
+    Bad(u16, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Bad(status, msg) => write!(f, "{} {}", status, msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        // the body readers carry malformed body errors in an io::Error
+        err.downcast::<Error>().unwrap_or_else(Error::Io)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(err) => err,
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+fn bad(msg: &str) -> Error {
+    Error::Bad(400, msg.to_string())
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// First value of a header, names are compared case-insensitively
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+impl Request {
+    /// First value of a header, names are compared case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    /// First value of a query string parameter, percent-decoded
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            if percent_decode(key, true) == name {
+                Some(percent_decode(value, true))
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        match self.header("connection") {
+            Some(conn) if conn.eq_ignore_ascii_case("close") => false,
+            Some(conn) if conn.eq_ignore_ascii_case("keep-alive") => true,
+            _ => self.version == "HTTP/1.1",
+        }
+    }
+
+    /// Write the request as HTTP/1.1, with a Content-Length matching the body
+    pub fn write_to<W>(&self, writer: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        write!(writer, "{} {}", self.method, self.path)?;
+        if !self.query.is_empty() {
+            write!(writer, "?{}", self.query)?;
+        }
+        write!(writer, " HTTP/1.1\r\n")?;
+        for (name, value) in &self.headers {
+            write!(writer, "{}: {}\r\n", name, value)?;
+        }
+        if !self.body.is_empty() || matches!(self.method.as_str(), "POST" | "PUT" | "PATCH") {
+            write!(writer, "Content-Length: {}\r\n", self.body.len())?;
+        }
+        write!(writer, "\r\n")?;
+        writer.write_all(&self.body)
+    }
+}
+
+/// Read one CRLF (or LF) terminated line, without the terminator
+fn read_line<R>(reader: &mut R, budget: &mut usize) -> Result<Option<String>, Error>
+where
+    R: BufRead,
+{
+    let mut line = Vec::new();
+    let n = reader
+        .by_ref()
+        .take(*budget as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if n > *budget {
+        return Err(Error::Bad(431, String::from("request head too large")));
+    }
+    *budget -= n;
+    if line.last() != Some(&b'\n') {
+        return Err(bad("unexpected end of request"));
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| bad("request head is not valid UTF-8"))
+}
+
+/// Start line and headers of a message
+type Head = (String, Vec<(String, String)>);
+
+/// Read the start line and the headers of a message, None at end of stream
+fn read_head<R>(reader: &mut R) -> Result<Option<Head>, Error>
+where
+    R: BufRead,
+{
+    let mut budget = MAX_HEAD_SIZE;
+    let start_line = loop {
+        match read_line(reader, &mut budget)? {
+            None => return Ok(None),
+            // tolerate empty lines between pipelined messages
+            Some(line) if line.is_empty() => continue,
+            Some(line) => break line,
+        }
+    };
+
+    let mut headers: Vec<(String, String)> = Vec::new();
+    loop {
+        let line =
+            read_line(reader, &mut budget)?.ok_or_else(|| bad("unexpected end of message"))?;
+        if line.is_empty() {
+            break;
+        }
+        // obsolete line folding, RFC 9112 section 5.2
+        if line.starts_with(' ') || line.starts_with('\t') {
+            match headers.last_mut() {
+                None => return Err(bad("folded line without header")),
+                Some((_, value)) => {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                }
+            }
+            continue;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| bad("malformed header"))?;
+        if name.is_empty() || name.ends_with(' ') {
+            return Err(bad("malformed header name"));
+        }
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+    Ok(Some((start_line, headers)))
+}
+
+fn too_large() -> Error {
+    Error::Bad(413, String::from("body too large"))
+}
+
+/// How the end of a message body is found, RFC 9112 section 6.3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    Length(u64),
+    Chunked,
+    /// The body ends with the connection
+    Close,
+}
+
+/// Transfer codings of a message, in the order they were applied
+fn transfer_codings(headers: &[(String, String)]) -> Vec<&str> {
+    headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("transfer-encoding"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty())
+        .collect()
+}
+
+/// Length of the body from the `Content-Length` headers, repeated or listed
+/// values must all be the same, RFC 9112 section 6.3
+fn content_length(headers: &[(String, String)]) -> Result<Option<u64>, Error> {
+    let mut length = None;
+    for value in headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(str::trim)
+    {
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(bad("invalid content-length"));
+        }
+        let value = value.parse().map_err(|_| bad("invalid content-length"))?;
+        if length.is_some_and(|length| length != value) {
+            return Err(bad("conflicting content-length"));
+        }
+        length = Some(value);
+    }
+    Ok(length)
+}
+
+/// Framing of a message body given its headers
+fn framing(headers: &[(String, String)], request: bool) -> Result<Framing, Error> {
+    let codings = transfer_codings(headers);
+    if let Some(last) = codings.last() {
+        if last.eq_ignore_ascii_case("chunked") {
+            return Ok(Framing::Chunked);
+        } else if request {
+            return Err(Error::Bad(
+                501,
+                String::from("transfer-encoding is not supported"),
+            ));
+        }
+        return Ok(Framing::Close);
+    }
+    match content_length(headers)? {
+        Some(length) => Ok(Framing::Length(length)),
+        None if request => Ok(Framing::Length(0)),
+        None => Ok(Framing::Close),
+    }
+}
+
+/// Reader of a body with a known length, failing if the stream ends before
+struct LengthReader<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R> Read for LengthReader<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let max = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Reader of a chunked body, RFC 9112 section 7.1, trailers are discarded
+struct ChunkedReader<R> {
+    inner: R,
+    /// Bytes left in the current chunk
+    remaining: usize,
+    done: bool,
+}
+
+impl<R> ChunkedReader<R>
+where
+    R: BufRead,
+{
+    fn next_line(&mut self) -> Result<String, Error> {
+        let mut budget = MAX_HEAD_SIZE;
+        read_line(&mut self.inner, &mut budget)?.ok_or_else(|| bad("unexpected end of body"))
+    }
+
+    /// Read the size line of the next chunk, and the trailers after the last
+    fn next_chunk(&mut self) -> Result<(), Error> {
+        let line = self.next_line()?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        self.remaining = usize::from_str_radix(size, 16).map_err(|_| bad("invalid chunk size"))?;
+        if self.remaining == 0 {
+            while !self.next_line()?.is_empty() {}
+            self.done = true;
+        }
+        Ok(())
+    }
+}
+
+impl<R> Read for ChunkedReader<R>
+where
+    R: BufRead,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 && !self.done {
+            self.next_chunk()?;
+        }
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        let max = buf.len().min(self.remaining);
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= n;
+        if self.remaining == 0 && !self.next_line()?.is_empty() {
+            return Err(bad("malformed chunk").into());
+        }
+        Ok(n)
+    }
+}
+
+/// Reader of a body with the given framing, which follows in `reader`
+pub fn body_reader<'a, R>(reader: R, framing: Framing) -> Box<dyn Read + 'a>
+where
+    R: BufRead + 'a,
+{
+    match framing {
+        Framing::Length(remaining) => Box::new(LengthReader {
+            inner: reader,
+            remaining,
+        }),
+        Framing::Chunked => Box::new(ChunkedReader {
+            inner: reader,
+            remaining: 0,
+            done: false,
+        }),
+        Framing::Close => Box::new(reader),
+    }
+}
+
+/// Writer of a chunked body, each write is sent as a chunk
+pub struct ChunkedWriter<W>(pub W);
+
+impl<W> ChunkedWriter<W>
+where
+    W: Write,
+{
+    /// Write the last chunk, returns the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.0.write_all(b"0\r\n\r\n")?;
+        Ok(self.0)
+    }
+}
+
+impl<W> Write for ChunkedWriter<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write!(self.0, "{:x}\r\n", buf.len())?;
+        self.0.write_all(buf)?;
+        self.0.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Read a request body framed by the given headers
+fn read_body<R>(reader: &mut R, headers: &[(String, String)]) -> Result<Vec<u8>, Error>
+where
+    R: BufRead,
+{
+    let framing = framing(headers, true)?;
+    if matches!(framing, Framing::Length(length) if length > MAX_BODY_SIZE as u64) {
+        return Err(too_large());
+    }
+    let mut body = Vec::new();
+    body_reader(reader, framing)
+        .take(MAX_BODY_SIZE as u64 + 1)
+        .read_to_end(&mut body)?;
+    if body.len() > MAX_BODY_SIZE {
+        return Err(too_large());
+    }
+    Ok(body)
+}
+
+/// Read a request, returns None if the connection was closed before a new one
+pub fn read_request<R>(reader: &mut R) -> Result<Option<Request>, Error>
+where
+    R: BufRead,
+{
+    let (request_line, headers) = match read_head(reader)? {
+        None => return Ok(None),
+        Some(head) => head,
+    };
+
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/1.") => {
+            (method, target, version)
+        }
+        _ => return Err(bad("malformed request line")),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let body = read_body(reader, &headers)?;
+
+    Ok(Some(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        version: version.to_string(),
+        headers,
+        body,
+    }))
+}
+
+/// Status and headers of a response, whose body is left to be read
+#[derive(Debug, Clone)]
+pub struct ResponseHead {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub framing: Framing,
+}
+
+impl ResponseHead {
+    /// Whether the response has no body, whatever its headers say
+    pub fn bodiless(&self, head: bool) -> bool {
+        head || self.status == 204 || self.status == 304
+    }
+
+    /// Transfer codings of the body other than the chunked framing
+    pub fn codings(&self) -> Vec<&str> {
+        let mut codings = transfer_codings(&self.headers);
+        if self.framing == Framing::Chunked {
+            codings.pop();
+        }
+        codings
+    }
+}
+
+/// Read the head of the final response to a request, skipping the interim 1xx
+/// responses. `head` when it was a HEAD request
+pub fn read_response_head<R>(reader: &mut R, head: bool) -> Result<ResponseHead, Error>
+where
+    R: BufRead,
+{
+    loop {
+        let (status_line, headers) =
+            read_head(reader)?.ok_or_else(|| bad("connection closed without response"))?;
+
+        let mut parts = status_line.splitn(3, ' ');
+        let status = match (parts.next(), parts.next()) {
+            (Some(version), Some(status)) if version.starts_with("HTTP/1.") => status
+                .parse::<u16>()
+                .map_err(|_| bad("malformed status line"))?,
+            _ => return Err(bad("malformed status line")),
+        };
+        match status {
+            // the connection isn't HTTP anymore
+            101 => return Err(bad("unexpected switch of protocols")),
+            100..=199 => continue,
+            _ => {}
+        }
+        let mut response = ResponseHead {
+            status,
+            headers,
+            framing: Framing::Length(0),
+        };
+        if !response.bodiless(head) {
+            response.framing = framing(&response.headers, false)?;
+        }
+        return Ok(response);
+    }
+}
+
+/// Decode %XX escapes, and optionally `+` as space
+pub fn percent_decode(s: &str, plus_as_space: bool) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match hex {
+                    Some(b) => {
+                        decoded.push(b);
+                        i += 3;
+                        continue;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            b'+' if plus_as_space => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> Response {
+        Response {
+            status,
+            headers: vec![(String::from("Content-Type"), content_type.to_string())],
+            body,
+        }
+    }
+
+    pub fn json<T>(status: u16, value: &T) -> Response
+    where
+        T: Serialize + ?Sized,
+    {
+        match serde_json::to_vec(value) {
+            Err(err) => Response::error(500, &err.to_string()),
+            Ok(body) => Response::new(status, "application/json", body),
+        }
+    }
+
+    /// JSON error body, `{"error": "..."}`
+    pub fn error(status: u16, message: &str) -> Response {
+        Response::json(status, &serde_json::json!({ "error": message }))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    pub fn write_to<W>(&self, writer: &mut W, keep_alive: bool) -> io::Result<()>
+    where
+        W: Write,
+    {
+        let mut headers = self.headers.clone();
+        // a Content-Length is kept as is for bodiless answers to HEAD requests
+        if self.status != 204 && self.status != 304 && self.header("content-length").is_none() {
+            headers.push((String::from("Content-Length"), self.body.len().to_string()));
+        }
+        write_head(writer, self.status, &headers, keep_alive)?;
+        writer.write_all(&self.body)
+    }
+}
+
+/// Write the status line and headers of a response, its body is left to write
+pub fn write_head<W>(
+    writer: &mut W,
+    status: u16,
+    headers: &[(String, String)],
+    keep_alive: bool,
+) -> io::Result<()>
+where
+    W: Write,
+{
+    write!(writer, "HTTP/1.1 {} {}\r\n", status, reason(status))?;
+    for (name, value) in headers {
+        write!(writer, "{}: {}\r\n", name, value)?;
+    }
+    if !keep_alive {
+        write!(writer, "Connection: close\r\n")?;
+    }
+    write!(writer, "\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_read_request() {
+        let raw = b"POST /parse?ua=Mozilla%2F5.0+(X11) HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            User-Agent: curl/8.0\r\n\
+            X-Folded: a\r\n\
+            \tb\r\n\
+            Content-Length: 4\r\n\
+            Content-Length: 4, 4\r\n\
+            \r\n\
+            bodyGET /health HTTP/1.0\r\n\r\n";
+        let mut reader = Cursor::new(&raw[..]);
+
+        let request = read_request(&mut reader).unwrap().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/parse");
+        assert_eq!(request.query_param("ua").unwrap(), "Mozilla/5.0 (X11)");
+        assert_eq!(request.header("user-agent"), Some("curl/8.0"));
+        assert_eq!(request.header("x-folded"), Some("a b"));
+        assert_eq!(request.body, b"body");
+        assert!(request.keep_alive());
+
+        let request = read_request(&mut reader).unwrap().unwrap();
+        assert_eq!(request.path, "/health");
+        assert!(!request.keep_alive());
+
+        assert!(read_request(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_bad_request() {
+        for raw in [
+            &b"GET /\r\n\r\n"[..],
+            &b"GET / HTTP/1.1\r\nno colon\r\n\r\n"[..],
+            &b"GET / HTTP/1.1\r\nContent-Length: x\r\n\r\n"[..],
+            &b"GET / HTTP/1.1\r\nContent-Length: +0\r\n\r\n"[..],
+            &b"GET / HTTP/1.1\r\nContent-Length: 0\r\nContent-Length: 1\r\n\r\n"[..],
+            &b"GET / HTTP/1.1\r\nContent-Length: 1, 0\r\n\r\n"[..],
+            &b"GET / HTTP/1.1\r\nHost: x"[..],
+        ] {
+            let err = read_request(&mut Cursor::new(raw)).unwrap_err();
+            assert!(matches!(err, Error::Bad(400, _)), "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn test_read_chunked_request() {
+        let raw = b"POST /parse HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            3;ext=1\r\nabc\r\n2\r\nde\r\n0\r\nTrailer: x\r\n\r\n";
+        let request = read_request(&mut Cursor::new(&raw[..])).unwrap().unwrap();
+        assert_eq!(request.body, b"abcde");
+
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n";
+        let err = read_request(&mut Cursor::new(&raw[..])).unwrap_err();
+        assert!(matches!(err, Error::Bad(400, _)), "{:?}", err);
+
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n";
+        let err = read_request(&mut Cursor::new(&raw[..])).unwrap_err();
+        assert!(matches!(err, Error::Bad(501, _)), "{:?}", err);
+    }
+
+    /// Read a whole response, `head` when it answers a HEAD request
+    fn read_response(raw: &[u8], head: bool) -> Result<(ResponseHead, Vec<u8>), Error> {
+        let mut reader = Cursor::new(raw);
+        let response = read_response_head(&mut reader, head)?;
+        let mut body = Vec::new();
+        body_reader(&mut reader, response.framing).read_to_end(&mut body)?;
+        Ok((response, body))
+    }
+
+    #[test]
+    fn test_read_response() {
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+        let (response, body) = read_response(raw, false).unwrap();
+        assert_eq!((response.status, &body[..]), (200, &b"ok"[..]));
+
+        let raw = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 102 Processing\r\n\r\n\
+            HTTP/1.1 404 Not Found\r\nTransfer-Encoding: gzip, chunked\r\n\r\n\
+            2\r\nno\r\n1;ext\r\n!\r\n0\r\nTrailer: x\r\n\r\n";
+        let (response, body) = read_response(raw, false).unwrap();
+        assert_eq!((response.status, &body[..]), (404, &b"no!"[..]));
+        assert_eq!(response.codings(), ["gzip"]);
+
+        let raw = b"HTTP/1.0 200 OK\r\nTransfer-Encoding: gzip\r\n\r\nuntil the end";
+        let (response, body) = read_response(raw, false).unwrap();
+        assert_eq!(response.framing, Framing::Close);
+        assert_eq!(response.codings(), ["gzip"]);
+        assert_eq!(body, b"until the end");
+
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n";
+        let (response, body) = read_response(raw, true).unwrap();
+        assert!(body.is_empty());
+        let mut out = Vec::new();
+        write_head(&mut out, response.status, &response.headers, true).unwrap();
+        assert_eq!(out, b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n");
+
+        // truncated bodies are errors, not short bodies
+        for raw in [
+            &b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort"[..],
+            &b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nab"[..],
+            &b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nabc\r\n"[..],
+        ] {
+            assert!(read_response(raw, false).is_err());
+        }
+        assert!(read_response(b"SSH-2.0\r\n\r\n", false).is_err());
+        assert!(read_response(b"HTTP/1.1 101 Switching Protocols\r\n\r\n", false).is_err());
+    }
+
+    #[test]
+    fn test_chunked_writer() {
+        let mut writer = ChunkedWriter(Vec::new());
+        writer.write_all(b"hello ").unwrap();
+        writer.write_all(b"").unwrap();
+        writer.write_all(b"world").unwrap();
+        let out = writer.finish().unwrap();
+        assert_eq!(out, b"6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn test_write_request() {
+        let raw = b"POST /a?b=c HTTP/1.0\r\nHost: x\r\nContent-Length: 2\r\n\r\nhi";
+        let mut request = read_request(&mut Cursor::new(&raw[..])).unwrap().unwrap();
+        request.headers.retain(|(name, _)| name != "Content-Length");
+        let mut out = Vec::new();
+        request.write_to(&mut out).unwrap();
+        assert_eq!(
+            out,
+            b"POST /a?b=c HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\n\r\nhi"
+        );
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%20b+c", true), "a b c");
+        assert_eq!(percent_decode("a%20b+c", false), "a b+c");
+        assert_eq!(percent_decode("100%", true), "100%");
+        assert_eq!(percent_decode("%zz%4", true), "%zz%4");
+        assert_eq!(percent_decode("%C3%A9", true), "é");
+    }
+
+    #[test]
+    fn test_write_response() {
+        let mut out = Vec::new();
+        Response::new(200, "text/plain; charset=utf-8", b"ok".to_vec())
+            .write_to(&mut out, false)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Length: 2\r\nConnection: close\r\n\r\nok"
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use anyhow::Result;
+
+use udger::access_log::{LogFormat, LogReport, Traffic};
+
+use crate::args::Args;
+use crate::{load_udger, open_inputs, pool, worker_threads};
+
+pub const USAGE: &str = "\
+udger logstats --db <path> [options] [file...]
+
+Classify the User-Agent of every request found in access logs (default: stdin)
+and report request counts and byte totals by client class, client family,
+OS family, device class and crawler category.
+
+Options:
+    --format <format>  `common`, `combined`, or a Nginx/Apache log format string,
+                       requests of formats without User-Agent are reported
+                       unclassified [default: combined]
+    --top <n>          rows shown per breakdown [default: 20]
+    --json             print the full report as JSON
+    --threads <n>      worker threads [default: available parallelism]
+    --cache <n>        per-thread LRU cache capacity [default: 10000]";
+
+pub const SWITCHES: &[&str] = &["json"];
+
+pub fn run(args: &Args) -> Result<()> {
+    let format: LogFormat = args.get_or("format", "combined").parse()?;
+    let top = args.get_parsed("top", 20)?;
+    let udger = load_udger(args)?;
+    let threads = worker_threads(args)?;
+    let input = open_inputs(args.positional())?;
+
+    let reports = pool::fold_lines(
+        &udger,
+        threads,
+        input,
+        LogReport::default,
+        |udger, data, report, line| {
+            report.add_line(udger, data, &format, &String::from_utf8_lossy(line))
+        },
+    )?;
+    let mut report = LogReport::default();
+    for other in &reports {
+        report.merge(other);
+    }
+
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    if args.flag("json") {
+        serde_json::to_writer_pretty(&mut output, &report)?;
+        writeln!(output)?;
+    } else {
+        render(&mut output, &report, top)?;
+    }
+    Ok(())
+}
+
+/// Print the report as tables, keeping the `top` busiest rows of each breakdown
+fn render<W>(output: &mut W, report: &LogReport, top: usize) -> io::Result<()>
+where
+    W: Write,
+{
+    writeln!(
+        output,
+        "requests: {}, bytes: {}, malformed lines: {}, without User-Agent: {}, parse errors: {}",
+        report.total.requests,
+        report.total.bytes,
+        report.malformed,
+        report.no_user_agent,
+        report.errors
+    )?;
+
+    let breakdowns: [(&str, &BTreeMap<String, Traffic>); 5] = [
+        ("ua_class", &report.ua_class),
+        ("ua_family", &report.ua_family),
+        ("os_family", &report.os_family),
+        ("device_class", &report.device_class),
+        ("crawler_category", &report.crawler_category),
+    ];
+    for (title, breakdown) in breakdowns {
+        let mut rows: Vec<_> = breakdown.iter().collect();
+        rows.sort_by(|a, b| b.1.requests.cmp(&a.1.requests).then(a.0.cmp(b.0)));
+
+        writeln!(output)?;
+        writeln!(
+            output,
+            "{:<40} {:>12} {:>7} {:>16}",
+            title, "requests", "share", "bytes"
+        )?;
+        for (name, traffic) in rows.iter().take(top) {
+            let share = match report.total.requests {
+                0 => 0.0,
+                total => traffic.requests as f64 * 100.0 / total as f64,
+            };
+            writeln!(
+                output,
+                "{:<40} {:>12} {:>6.2}% {:>16}",
+                name, traffic.requests, share, traffic.bytes
+            )?;
+        }
+        if rows.len() > top {
+            writeln!(output, "... {} more", rows.len() - top)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use udger::UaInfo;
+
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut report = LogReport::default();
+        for family in ["Firefox", "Chrome", "Chrome"] {
+            let info = UaInfo {
+                ua_class: String::from("Browser"),
+                ua_family: String::from(family),
+                ..Default::default()
+            };
+            report.add(&info, 10);
+        }
+
+        let mut output = Vec::new();
+        render(&mut output, &report, 1).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.starts_with("requests: 3, bytes: 30,"));
+        assert!(output.contains("Chrome"));
+        assert!(!output.contains("Firefox"));
+        assert!(output.contains("... 1 more"));
+        assert!(output.contains("100.00%"));
+    }
+}
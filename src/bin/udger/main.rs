@@ -0,0 +1,152 @@
+//! Command line front-end of the udger parser
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+use udger::{Limits, Normalization, Udger};
+
+mod args;
+mod enrich;
+mod http;
+mod logstats;
+mod pcap;
+mod pool;
+mod proxy;
+mod serve;
+#[cfg(unix)]
+mod sidecar;
+
+use crate::args::Args;
+
+const USAGE: &str = "\
+usage: udger <command> [options]
+
+Commands:
+    enrich      add parse results to newline-delimited JSON records
+    logstats    break down access log traffic by client, OS and device
+    pcap        classify the HTTP requests of pcap and pcapng captures
+    proxy       reverse proxy adding classification headers to requests
+    serve       serve the parser over HTTP with a JSON API
+    sidecar     serve the parser on a Unix socket with a binary protocol
+
+Common options:
+    --db <path>       udger sqlite database (udgerdb_v3.dat)
+    --cache <n>       per-thread LRU cache capacity [default: 10000]
+    --threads <n>     worker threads [default: available parallelism]
+    --darwin <path>   CFNetwork/Darwin to iOS/macOS release mapping to load over
+                      the built-in one
+    --decode <how>    decode logged User-Agents: none, plus, url or both
+                      [default: none]
+    --max-length <n>  truncate User-Agents to n bytes, 0 for no limit
+                      [default: 1024]
+    --match-limit <n> PCRE match and recursion limit of the regexes
+                      [default: 10000000]
+    --budget <ms>     time budget of a parse, the remaining detectors are
+                      skipped once it ran out [default: none]
+
+Run `udger help <command>` for the options of a command.";
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("udger: {:#}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    let mut argv = std::env::args().skip(1);
+    let command = argv.next().unwrap_or_default();
+
+    match command.as_str() {
+        "enrich" => enrich::run(&Args::parse(argv, enrich::SWITCHES)?),
+        "logstats" => logstats::run(&Args::parse(argv, logstats::SWITCHES)?),
+        "pcap" => pcap::run(&Args::parse(argv, pcap::SWITCHES)?),
+        "proxy" => proxy::run(&Args::parse(argv, proxy::SWITCHES)?),
+        "serve" => serve::run(&Args::parse(argv, serve::SWITCHES)?),
+        #[cfg(unix)]
+        "sidecar" => sidecar::run(&Args::parse(argv, sidecar::SWITCHES)?),
+        "help" | "-h" | "--help" => {
+            let usage = match argv.next().as_deref() {
+                Some("enrich") => enrich::USAGE,
+                Some("logstats") => logstats::USAGE,
+                Some("pcap") => pcap::USAGE,
+                Some("proxy") => proxy::USAGE,
+                Some("serve") => serve::USAGE,
+                #[cfg(unix)]
+                Some("sidecar") => sidecar::USAGE,
+                _ => USAGE,
+            };
+            println!("{}", usage);
+            Ok(())
+        }
+        "" => Err(anyhow!("missing command\n\n{}", USAGE)),
+        _ => Err(anyhow!("unknown command `{}`\n\n{}", command, USAGE)),
+    }
+}
+
+/// Load the database given by `--db`, using `--cache` as LRU capacity, the
+/// Darwin release mapping given by `--darwin`, the normalization given by
+/// `--decode` and `--max-length` and the limits given by `--match-limit` and
+/// `--budget`
+pub fn load_udger(args: &Args) -> Result<Udger> {
+    let db_path = args
+        .get("db")
+        .ok_or_else(|| anyhow!("missing required option --db"))?;
+    let mut udger = Udger::new();
+    let match_limit = args.get_parsed("match-limit", 10_000_000)?;
+    udger.set_limits(Limits {
+        match_limit,
+        match_limit_recursion: match_limit,
+        time_budget: match args.get("budget") {
+            None => None,
+            Some(_) => Some(Duration::from_millis(args.get_parsed("budget", 0)?)),
+        },
+    });
+    udger.init(PathBuf::from(db_path), args.get_parsed("cache", 10000)?)?;
+    if let Some(path) = args.get("darwin") {
+        udger.load_darwin_versions(PathBuf::from(path))?;
+    }
+    let (plus_decode, url_decode) = match args.get_or("decode", "none") {
+        "none" => (false, false),
+        "plus" => (true, false),
+        "url" => (false, true),
+        "both" => (true, true),
+        decode => return Err(anyhow!("invalid --decode {}", decode)),
+    };
+    udger.set_normalization(Normalization {
+        plus_decode,
+        url_decode,
+        reject_control: true,
+        collapse_whitespace: true,
+        max_length: args.get_parsed("max-length", 1024)?,
+    });
+    Ok(udger)
+}
+
+/// Number of worker threads given by `--threads`
+pub fn worker_threads(args: &Args) -> Result<usize> {
+    let default = thread::available_parallelism().map_or(1, |n| n.get());
+    Ok(args.get_parsed("threads", default)?.max(1))
+}
+
+/// Open the input files one after another, stdin when none (or `-`) is given
+pub fn open_inputs(paths: &[String]) -> Result<Box<dyn BufRead + Send>> {
+    if paths.is_empty() {
+        return Ok(Box::new(BufReader::new(io::stdin())));
+    }
+
+    let mut input: Box<dyn Read + Send> = Box::new(io::empty());
+    for path in paths {
+        let next: Box<dyn Read + Send> = match path.as_str() {
+            "-" => Box::new(io::stdin()),
+            path => Box::new(File::open(path).map_err(|err| anyhow!("{}: {}", path, err))?),
+        };
+        input = Box::new(input.chain(next));
+    }
+    Ok(Box::new(BufReader::new(input)))
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use serde_json::Value;
+
+use udger::pcap::{CaptureReader, HttpExtractor, HttpRequest};
+
+use crate::args::Args;
+use crate::load_udger;
+
+pub const USAGE: &str = "\
+udger pcap --db <path> [options] [file...]
+
+Read pcap or pcapng captures from the given files (default: stdin), reassemble
+their TCP streams and classify the User-Agent of the HTTP/1.x requests found in
+them. Writes one JSON record per connection and User-Agent to stdout, with the
+client IP taken from X-Forwarded-For when present.
+
+Options:
+    --requests     write one record per request instead
+    --cache <n>    LRU cache capacity [default: 10000]";
+
+pub const SWITCHES: &[&str] = &["requests"];
+
+/// Requests of a connection sharing a User-Agent
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FlowRecord {
+    pub client: Option<SocketAddr>,
+    pub server: Option<SocketAddr>,
+    pub client_ip: Option<IpAddr>,
+    pub first_seen: f64,
+    pub last_seen: f64,
+    pub requests: u64,
+    pub host: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl FlowRecord {
+    fn new(request: &HttpRequest) -> FlowRecord {
+        FlowRecord {
+            client: request.client,
+            server: request.server,
+            client_ip: request.client_ip(),
+            first_seen: request.timestamp,
+            last_seen: request.timestamp,
+            requests: 0,
+            host: request.host.clone(),
+            user_agent: request.user_agent.clone(),
+        }
+    }
+}
+
+/// Connection and User-Agent
+type FlowKey = (Option<SocketAddr>, Option<SocketAddr>, Option<String>);
+
+/// Group requests by connection and User-Agent, in order of appearance
+#[derive(Debug, Default)]
+pub struct Flows {
+    records: Vec<FlowRecord>,
+    index: HashMap<FlowKey, usize>,
+}
+
+impl Flows {
+    pub fn add(&mut self, request: &HttpRequest) {
+        let key = (request.client, request.server, request.user_agent.clone());
+        let i = *self.index.entry(key).or_insert_with(|| {
+            self.records.push(FlowRecord::new(request));
+            self.records.len() - 1
+        });
+        let record = &mut self.records[i];
+        record.requests += 1;
+        record.last_seen = request.timestamp;
+    }
+
+    pub fn into_records(self) -> Vec<FlowRecord> {
+        self.records
+    }
+}
+
+pub fn run(args: &Args) -> Result<()> {
+    let udger = load_udger(args)?;
+    let mut data = udger.alloc_udger_data()?;
+    let mut classify = |ua: &str| {
+        let info = udger.parse_ua(&ua, &mut data).ok()?;
+        serde_json::to_value(&*info).ok()
+    };
+
+    let stdout = io::stdout();
+    let mut output = BufWriter::new(stdout.lock());
+    let mut write = |mut value: Value, ua: Option<&str>| -> Result<()> {
+        value["udger"] = ua.and_then(&mut classify).unwrap_or(Value::Null);
+        serde_json::to_writer(&mut output, &value)?;
+        output.write_all(b"\n")?;
+        Ok(())
+    };
+
+    let paths = match args.positional() {
+        [] => &[String::from("-")][..],
+        paths => paths,
+    };
+    for path in paths {
+        let input: Box<dyn Read> = match path.as_str() {
+            "-" => Box::new(io::stdin()),
+            path => Box::new(File::open(path).map_err(|err| anyhow!("{}: {}", path, err))?),
+        };
+        let capture = CaptureReader::new(BufReader::new(input))
+            .map_err(|err| anyhow!("{}: {}", path, err))?;
+
+        let mut extractor = HttpExtractor::new();
+        let mut flows = Flows::default();
+        for packet in capture {
+            let packet = packet.map_err(|err| anyhow!("{}: {}", path, err))?;
+            for request in extractor.push_packet(&packet) {
+                if args.flag("requests") {
+                    let mut value = serde_json::to_value(&request)?;
+                    value["client_ip"] = serde_json::to_value(request.client_ip())?;
+                    write(value, request.user_agent.as_deref())?;
+                } else {
+                    flows.add(&request);
+                }
+            }
+        }
+        for record in flows.into_records() {
+            write(serde_json::to_value(&record)?, record.user_agent.as_deref())?;
+        }
+    }
+
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(timestamp: f64, port: u16, ua: Option<&str>) -> HttpRequest {
+        HttpRequest {
+            timestamp,
+            client: Some(SocketAddr::from(([192, 0, 2, 1], port))),
+            server: Some(SocketAddr::from(([198, 51, 100, 2], 80))),
+            method: String::from("GET"),
+            target: String::from("/"),
+            host: Some(String::from("example.com")),
+            user_agent: ua.map(String::from),
+            forwarded_for: None,
+        }
+    }
+
+    #[test]
+    fn test_flows() {
+        let mut flows = Flows::default();
+        for request in [
+            request(1.0, 40000, Some("curl/8.0")),
+            request(2.0, 40001, Some("curl/8.0")),
+            request(3.0, 40000, Some("curl/8.0")),
+            request(4.0, 40000, None),
+        ] {
+            flows.add(&request);
+        }
+
+        let records = flows.into_records();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].requests, 2);
+        assert_eq!((records[0].first_seen, records[0].last_seen), (1.0, 3.0));
+        assert_eq!(records[0].client_ip, Some(IpAddr::from([192, 0, 2, 1])));
+        assert_eq!(records[1].client.unwrap().port(), 40001);
+        assert_eq!(records[2].user_agent, None);
+    }
+}
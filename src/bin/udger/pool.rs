@@ -0,0 +1,322 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Scope};
+
+use anyhow::{anyhow, Result};
+
+use udger::{UaInfo, Udger, UdgerData};
+
+/// Number of lines handed to a worker at once
+const BATCH_SIZE: usize = 512;
+/// Upper bound of the connections served at once by `serve_connections`
+pub const MAX_CONNECTIONS: usize = 1024;
+
+type Batch = (usize, Vec<Vec<u8>>);
+
+/// A User-Agent handed to the parse workers, with where to send its result
+type ParseJob = (String, Sender<Result<UaInfo>>);
+
+/// Handle on the workers started by `parse_workers`, cloned by every
+/// connection thread
+#[derive(Clone)]
+pub struct ParsePool {
+    job_tx: SyncSender<ParseJob>,
+}
+
+impl ParsePool {
+    /// Parse `ua` on one of the workers and wait for the result
+    pub fn parse(&self, ua: &str) -> Result<Rc<UaInfo>> {
+        let (res_tx, res_rx) = mpsc::channel();
+        self.job_tx
+            .send((ua.to_string(), res_tx))
+            .map_err(|_| anyhow!("no parse worker left"))?;
+        res_rx
+            .recv()
+            .map_err(|_| anyhow!("parse worker panicked"))?
+            .map(Rc::new)
+    }
+}
+
+/// Start `threads` workers parsing the User-Agents sent through the returned
+/// pool, each with its own `UdgerData`. They stop once every handle on the
+/// pool is dropped
+pub fn parse_workers<'scope>(
+    s: &'scope Scope<'scope, '_>,
+    udger: &'scope Udger,
+    threads: usize,
+) -> Result<ParsePool> {
+    let threads = threads.max(1);
+    let (job_tx, job_rx) = mpsc::sync_channel::<ParseJob>(threads * 2);
+    let job_rx = Arc::new(Mutex::new(job_rx));
+    let (ready_tx, ready_rx) = mpsc::channel::<Result<()>>();
+
+    for _ in 0..threads {
+        let job_rx = job_rx.clone();
+        let ready_tx = ready_tx.clone();
+        s.spawn(move || {
+            let mut data = match udger.alloc_udger_data() {
+                Err(err) => {
+                    let _ = ready_tx.send(Err(err));
+                    return;
+                }
+                Ok(data) => data,
+            };
+            let _ = ready_tx.send(Ok(()));
+            while let Some((ua, res_tx)) = next_job(&job_rx) {
+                let info = udger.parse_ua(&ua, &mut data).map(|info| (*info).clone());
+                let _ = res_tx.send(info);
+            }
+        });
+    }
+    drop(ready_tx);
+    // on error, dropping job_tx stops the workers which did start
+    for ready in ready_rx {
+        ready?;
+    }
+    Ok(ParsePool { job_tx })
+}
+
+fn next_job(job_rx: &Mutex<Receiver<ParseJob>>) -> Option<ParseJob> {
+    job_rx.lock().ok()?.recv().ok()
+}
+
+/// Serve every accepted connection on its own thread with `handle`, so that
+/// idle ones don't hold a parse worker. Past `MAX_CONNECTIONS` open at once,
+/// connections are handed to `reject` instead
+pub fn serve_connections<'scope, S, I, F, R>(
+    s: &'scope Scope<'scope, '_>,
+    incoming: I,
+    handle: F,
+    reject: R,
+) where
+    S: Send + 'scope,
+    I: Iterator<Item = io::Result<S>>,
+    F: Fn(S) + Send + Sync + 'scope,
+    R: Fn(S),
+{
+    let handle = Arc::new(handle);
+    let open = Arc::new(AtomicUsize::new(0));
+    for stream in incoming.flatten() {
+        if open.fetch_add(1, Ordering::Relaxed) >= MAX_CONNECTIONS {
+            open.fetch_sub(1, Ordering::Relaxed);
+            reject(stream);
+            continue;
+        }
+        let (handle, open) = (handle.clone(), open.clone());
+        s.spawn(move || {
+            handle(stream);
+            open.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
+
+/// Read `input` line by line and run `f` over every line on `threads` workers
+///
+/// Every worker allocates its own `UdgerData`, so each of them has its own
+/// scratch space and LRU cache. Results are handed to `consume` in input order.
+/// Line terminators are stripped before calling `f`.
+pub fn map_lines<R, T, F, C>(
+    udger: &Udger,
+    threads: usize,
+    input: R,
+    f: F,
+    mut consume: C,
+) -> Result<()>
+where
+    R: BufRead + Send,
+    T: Send,
+    F: Fn(&Udger, &mut UdgerData, &[u8]) -> T + Sync,
+    C: FnMut(T) -> Result<()>,
+{
+    let threads = threads.max(1);
+
+    thread::scope(|s| {
+        let (job_tx, job_rx) = mpsc::sync_channel::<Batch>(threads * 2);
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let (res_tx, res_rx) = mpsc::channel::<Result<(usize, Vec<T>)>>();
+
+        for _ in 0..threads {
+            let job_rx = job_rx.clone();
+            let res_tx = res_tx.clone();
+            let f = &f;
+            s.spawn(move || {
+                let mut data = match udger.alloc_udger_data() {
+                    Err(err) => {
+                        let _ = res_tx.send(Err(err));
+                        return;
+                    }
+                    Ok(data) => data,
+                };
+                while let Some((seq, lines)) = next_batch(&job_rx) {
+                    let results = lines.iter().map(|line| f(udger, &mut data, line)).collect();
+                    if res_tx.send(Ok((seq, results))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(res_tx);
+
+        let reader = s.spawn(move || read_batches(input, job_tx));
+
+        // workers finish batches out of order, keep them until it's their turn
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        for res in res_rx {
+            let (seq, results) = res?;
+            pending.insert(seq, results);
+            while let Some(results) = pending.remove(&next) {
+                for result in results {
+                    consume(result)?;
+                }
+                next += 1;
+            }
+        }
+
+        reader
+            .join()
+            .map_err(|_| anyhow!("input reader thread panicked"))?
+    })
+}
+
+/// Read `input` line by line and fold every line into a per-worker accumulator
+///
+/// Like `map_lines`, but without ordering: each of the `threads` workers
+/// starts from `init()` and the accumulators are returned once the input is
+/// exhausted, ready to be merged by the caller.
+pub fn fold_lines<R, A, I, F>(
+    udger: &Udger,
+    threads: usize,
+    input: R,
+    init: I,
+    f: F,
+) -> Result<Vec<A>>
+where
+    R: BufRead + Send,
+    A: Send,
+    I: Fn() -> A + Sync,
+    F: Fn(&Udger, &mut UdgerData, &mut A, &[u8]) + Sync,
+{
+    let threads = threads.max(1);
+
+    thread::scope(|s| {
+        let (job_tx, job_rx) = mpsc::sync_channel::<Batch>(threads * 2);
+        let job_rx = Arc::new(Mutex::new(job_rx));
+
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                let job_rx = job_rx.clone();
+                let (init, f) = (&init, &f);
+                s.spawn(move || -> Result<A> {
+                    let mut data = udger.alloc_udger_data()?;
+                    let mut acc = init();
+                    while let Some((_, lines)) = next_batch(&job_rx) {
+                        for line in &lines {
+                            f(udger, &mut data, &mut acc, line);
+                        }
+                    }
+                    Ok(acc)
+                })
+            })
+            .collect();
+        drop(job_rx);
+
+        let reader = s.spawn(move || read_batches(input, job_tx));
+
+        let accs = workers
+            .into_iter()
+            .map(|worker| {
+                worker
+                    .join()
+                    .map_err(|_| anyhow!("worker thread panicked"))?
+            })
+            .collect::<Result<Vec<A>>>()?;
+
+        reader
+            .join()
+            .map_err(|_| anyhow!("input reader thread panicked"))??;
+        Ok(accs)
+    })
+}
+
+fn next_batch(job_rx: &Mutex<Receiver<Batch>>) -> Option<Batch> {
+    job_rx.lock().ok()?.recv().ok()
+}
+
+/// Split `input` into numbered batches of lines, stops early once all workers are gone
+fn read_batches<R>(mut input: R, job_tx: SyncSender<Batch>) -> Result<()>
+where
+    R: BufRead,
+{
+    let mut seq = 0;
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    loop {
+        let mut line = Vec::new();
+        if input.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        if line.last() == Some(&b'\n') {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+        }
+        batch.push(line);
+        if batch.len() == BATCH_SIZE {
+            let full = std::mem::replace(&mut batch, Vec::with_capacity(BATCH_SIZE));
+            if job_tx.send((seq, full)).is_err() {
+                // all workers are gone, the error is reported by them
+                return Ok(());
+            }
+            seq += 1;
+        }
+    }
+    if !batch.is_empty() {
+        let _ = job_tx.send((seq, batch));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+
+    use super::*;
+
+    #[test]
+    fn test_serve_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = &listener;
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                // echo the lines of a connection until it's shut down
+                let echo = |stream: TcpStream| {
+                    let mut reader = BufReader::new(&stream);
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).unwrap() > 0 {
+                        (&stream).write_all(line.as_bytes()).unwrap();
+                        line.clear();
+                    }
+                };
+                serve_connections(s, listener.incoming().take(2), echo, |_| panic!("rejected"));
+            });
+
+            // an idle connection doesn't hold up the next one
+            let idle = TcpStream::connect(addr).unwrap();
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(b"ping\n").unwrap();
+            client.shutdown(Shutdown::Write).unwrap();
+            let mut echoed = String::new();
+            client.read_to_string(&mut echoed).unwrap();
+            assert_eq!(echoed, "ping\n");
+            drop(idle);
+        });
+    }
+}
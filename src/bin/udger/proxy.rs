@@ -0,0 +1,506 @@
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use udger::{UaInfo, Udger};
+
+use crate::args::Args;
+use crate::http::{self, Request, Response};
+use crate::{load_udger, pool, worker_threads};
+
+pub const USAGE: &str = "\
+udger proxy --db <path> [--upstream <addr>] [options]
+
+Reverse proxy adding the classification of the User-Agent to the requests it
+forwards to the upstream, as X-UA-Class, X-UA-Family, X-OS-Family,
+X-Device-Class and X-Crawler-Category headers. These headers are always removed
+from incoming requests, empty values are not sent.
+
+Requests to the auth path are answered directly, with the classification
+headers and a 204 status (403 when denied), for the nginx auth_request module:
+
+    location = /_udger/auth {
+        internal;
+        proxy_pass http://127.0.0.1:8081;
+        proxy_pass_request_body off;
+        proxy_set_header Content-Length \"\";
+    }
+    location / {
+        auth_request /_udger/auth;
+        auth_request_set $ua_class $upstream_http_x_ua_class;
+        proxy_set_header X-UA-Class $ua_class;
+        ...
+    }
+
+Options:
+    --listen <addr>       listen address [default: 127.0.0.1:8081]
+    --upstream <addr>     upstream host:port, without it only the auth path is served
+    --auth-path <path>    auth_request endpoint [default: /_udger/auth]
+    --deny <codes>        comma separated ua_class_code values answered with 403
+    --threads <n>         worker threads [default: available parallelism]
+    --cache <n>           per-thread LRU cache capacity [default: 10000]";
+
+pub const SWITCHES: &[&str] = &[];
+
+/// Idle keep-alive connections are closed after this delay
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Classification headers, in the order they are added
+const HEADERS: [&str; 5] = [
+    "X-UA-Class",
+    "X-UA-Family",
+    "X-OS-Family",
+    "X-Device-Class",
+    "X-Crawler-Category",
+];
+
+/// Connection-specific headers, RFC 9110 section 7.6.1
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+pub struct ProxyConfig {
+    pub upstream: Option<String>,
+    pub auth_path: String,
+    pub deny: Vec<String>,
+}
+
+pub fn run(args: &Args) -> Result<()> {
+    let config = ProxyConfig {
+        upstream: args.get("upstream").map(str::to_string),
+        auth_path: args.get_or("auth-path", "/_udger/auth").to_string(),
+        deny: args
+            .get("deny")
+            .map(|codes| {
+                codes
+                    .split(',')
+                    .map(|code| code.trim().to_string())
+                    .collect()
+            })
+            .unwrap_or_default(),
+    };
+    let udger = load_udger(args)?;
+    let threads = worker_threads(args)?;
+    let listener = TcpListener::bind(args.get_or("listen", "127.0.0.1:8081"))?;
+
+    eprintln!(
+        "udger: database {} loaded, proxying http://{} to {} with {} workers",
+        udger.db_version(),
+        listener.local_addr()?,
+        config.upstream.as_deref().unwrap_or("nothing"),
+        threads
+    );
+    serve(&udger, &listener, threads, &config)
+}
+
+/// Read each connection on its own thread and parse the User-Agents on
+/// `threads` workers, each with its own `UdgerData`, so that idle keep-alive
+/// connections and slow upstreams don't hold a worker
+pub fn serve(
+    udger: &Udger,
+    listener: &TcpListener,
+    threads: usize,
+    config: &ProxyConfig,
+) -> Result<()> {
+    thread::scope(|s| {
+        let parser = pool::parse_workers(s, udger, threads)?;
+        let handle = move |stream| {
+            let mut parse = |ua: &str| parser.parse(ua);
+            // a broken connection only concerns its client
+            let _ = handle_connection(stream, config, &mut parse);
+        };
+        pool::serve_connections(s, listener.incoming(), handle, |mut stream| {
+            let _ = Response::error(503, "too many connections").write_to(&mut stream, false);
+        });
+        Ok(())
+    })
+}
+
+/// Serve requests of a connection until it's closed or stops keeping alive
+pub fn handle_connection<F>(stream: TcpStream, config: &ProxyConfig, parse: &mut F) -> Result<()>
+where
+    F: FnMut(&str) -> Result<Rc<UaInfo>>,
+{
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    let peer = stream.peer_addr().ok().map(|addr| addr.ip());
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let request = match http::read_request(&mut reader) {
+            Ok(None) | Err(http::Error::Io(_)) => return Ok(()),
+            Err(http::Error::Bad(status, msg)) => {
+                Response::error(status, &msg).write_to(&mut writer, false)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Ok(Some(request)) => request,
+        };
+
+        let mut keep_alive = request.keep_alive();
+        match route(&request, peer, config, parse) {
+            Route::Answer(response) => response.write_to(&mut writer, keep_alive)?,
+            Route::Forward(forwarded, upstream) => {
+                keep_alive = relay(&request, &forwarded, upstream, &mut writer, keep_alive)?;
+            }
+        }
+        writer.flush()?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Classification headers of a parse result, without the empty ones
+fn classification(info: &UaInfo) -> Vec<(String, String)> {
+    let values = [
+        &info.ua_class,
+        &info.ua_family,
+        &info.os_family,
+        &info.device_class,
+        &info.crawler_category,
+    ];
+    HEADERS
+        .iter()
+        .zip(values)
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(name, value)| (name.to_string(), value.clone()))
+        .collect()
+}
+
+/// Headers of a message which may be forwarded to the next hop
+fn end_to_end(headers: &[(String, String)]) -> Vec<(String, String)> {
+    let listed: Vec<&str> = headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("connection"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(str::trim)
+        .collect();
+    headers
+        .iter()
+        .filter(|(name, _)| {
+            !HOP_BY_HOP
+                .iter()
+                .chain(&listed)
+                .any(|hop| name.eq_ignore_ascii_case(hop))
+        })
+        .cloned()
+        .collect()
+}
+
+/// What the proxy does with a request
+pub enum Route<'a> {
+    /// Answered by the proxy itself
+    Answer(Response),
+    /// Forwarded to the upstream as the given request
+    Forward(Request, &'a str),
+}
+
+/// Answer the auth endpoint, or prepare the request to forward upstream
+pub fn route<'a, F>(
+    request: &Request,
+    peer: Option<IpAddr>,
+    config: &'a ProxyConfig,
+    parse: &mut F,
+) -> Route<'a>
+where
+    F: FnMut(&str) -> Result<Rc<UaInfo>>,
+{
+    // a request failing to be parsed is forwarded unclassified
+    let info = request.header("user-agent").and_then(|ua| parse(ua).ok());
+    let added = info.as_deref().map(classification).unwrap_or_default();
+    let denied = info
+        .as_deref()
+        .is_some_and(|info| config.deny.contains(&info.ua_class_code));
+
+    if request.path == config.auth_path {
+        return Route::Answer(Response {
+            status: if denied { 403 } else { 204 },
+            headers: added,
+            body: Vec::new(),
+        });
+    }
+    if denied {
+        return Route::Answer(Response::error(403, "forbidden"));
+    }
+    let upstream = match &config.upstream {
+        None => return Route::Answer(Response::error(404, "not found")),
+        Some(upstream) => upstream,
+    };
+
+    let mut forwarded = request.clone();
+    forwarded.headers = end_to_end(&request.headers);
+    // the whole body was read already, the upstream must not wait for it
+    forwarded.headers.retain(|(name, _)| {
+        !name.eq_ignore_ascii_case("content-length")
+            && !name.eq_ignore_ascii_case("expect")
+            && !HEADERS
+                .iter()
+                .any(|header| name.eq_ignore_ascii_case(header))
+    });
+    if let Some(peer) = peer {
+        let chain = match request.header("x-forwarded-for") {
+            Some(chain) => format!("{}, {}", chain, peer),
+            None => peer.to_string(),
+        };
+        forwarded
+            .headers
+            .retain(|(name, _)| !name.eq_ignore_ascii_case("x-forwarded-for"));
+        forwarded
+            .headers
+            .push((String::from("X-Forwarded-For"), chain));
+    }
+    forwarded.headers.extend(added);
+    forwarded
+        .headers
+        .push((String::from("Connection"), String::from("close")));
+    Route::Forward(forwarded, upstream)
+}
+
+/// Send a request on a new upstream connection, returns the connection and the
+/// head of the final response
+fn forward(
+    request: &Request,
+    upstream: &str,
+) -> Result<(BufReader<TcpStream>, http::ResponseHead)> {
+    let stream = TcpStream::connect(upstream)?;
+    stream.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+    stream.set_write_timeout(Some(UPSTREAM_TIMEOUT))?;
+
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    request.write_to(&mut writer)?;
+    writer.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let response = http::read_response_head(&mut reader, request.method == "HEAD")?;
+    Ok((reader, response))
+}
+
+/// Forward a request upstream and stream the response back to the client,
+/// returns whether the client connection may be kept alive
+fn relay<W>(
+    request: &Request,
+    forwarded: &Request,
+    upstream: &str,
+    writer: &mut W,
+    keep_alive: bool,
+) -> Result<bool>
+where
+    W: Write,
+{
+    let (mut reader, response) = match forward(forwarded, upstream) {
+        Err(err) => {
+            Response::error(502, &format!("upstream: {}", err)).write_to(writer, keep_alive)?;
+            return Ok(keep_alive);
+        }
+        Ok(forwarded) => forwarded,
+    };
+    let mut headers = end_to_end(&response.headers);
+    // a Content-Length is kept as is for bodiless answers to HEAD requests
+    if response.bodiless(request.method == "HEAD") {
+        http::write_head(writer, response.status, &headers, keep_alive)?;
+        return Ok(keep_alive);
+    }
+
+    let mut body = http::body_reader(&mut reader, response.framing);
+    if let http::Framing::Length(_) = response.framing {
+        http::write_head(writer, response.status, &headers, keep_alive)?;
+        io::copy(&mut body, writer)?;
+        return Ok(keep_alive);
+    }
+    // of unknown length, the body is chunked again for HTTP/1.1 clients and
+    // ends with the connection for the others, keeping its other codings
+    let chunked = request.version == "HTTP/1.1";
+    let mut codings = response.codings();
+    if chunked {
+        codings.push("chunked");
+    }
+    headers.retain(|(name, _)| !name.eq_ignore_ascii_case("content-length"));
+    if !codings.is_empty() {
+        headers.push((String::from("Transfer-Encoding"), codings.join(", ")));
+    }
+    http::write_head(writer, response.status, &headers, keep_alive && chunked)?;
+    if chunked {
+        let mut writer = http::ChunkedWriter(writer);
+        io::copy(&mut body, &mut writer)?;
+        writer.finish()?;
+    } else {
+        io::copy(&mut body, writer)?;
+    }
+    Ok(keep_alive && chunked)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn fake_parse(ua: &str) -> Result<Rc<UaInfo>> {
+        let (class, code) = match ua {
+            "bot" => ("Crawler", "crawler"),
+            _ => ("Browser", "browser"),
+        };
+        Ok(Rc::new(UaInfo {
+            ua_class: class.to_string(),
+            ua_class_code: code.to_string(),
+            ua_family: String::from("Firefox"),
+            ..Default::default()
+        }))
+    }
+
+    fn request(raw: &str) -> Request {
+        http::read_request(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    /// Answer of the proxy to a request which isn't forwarded
+    fn handle(
+        request: &Request,
+        peer: Option<IpAddr>,
+        config: &ProxyConfig,
+        parse: &mut impl FnMut(&str) -> Result<Rc<UaInfo>>,
+    ) -> Response {
+        match route(request, peer, config, parse) {
+            Route::Answer(response) => response,
+            Route::Forward(..) => panic!("request forwarded"),
+        }
+    }
+
+    #[test]
+    fn test_auth_request() {
+        let config = ProxyConfig {
+            upstream: None,
+            auth_path: String::from("/auth"),
+            deny: vec![String::from("crawler")],
+        };
+        let parse = &mut fake_parse;
+
+        let res = handle(
+            &request("GET /auth HTTP/1.1\r\nUser-Agent: firefox\r\n\r\n"),
+            None,
+            &config,
+            parse,
+        );
+        assert_eq!(res.status, 204);
+        assert_eq!(res.header("x-ua-class"), Some("Browser"));
+        assert_eq!(res.header("x-ua-family"), Some("Firefox"));
+        assert_eq!(res.header("x-os-family"), None);
+
+        let res = handle(
+            &request("GET /auth HTTP/1.1\r\nUser-Agent: bot\r\n\r\n"),
+            None,
+            &config,
+            parse,
+        );
+        assert_eq!(res.status, 403);
+        assert_eq!(res.header("x-ua-class"), Some("Crawler"));
+
+        let res = handle(&request("GET /auth HTTP/1.1\r\n\r\n"), None, &config, parse);
+        assert_eq!((res.status, res.headers.len()), (204, 0));
+
+        let res = handle(&request("GET /page HTTP/1.1\r\n\r\n"), None, &config, parse);
+        assert_eq!(res.status, 404);
+    }
+
+    #[test]
+    fn test_end_to_end() {
+        let headers: Vec<(String, String)> = [
+            ("Host", "x"),
+            ("Connection", "keep-alive, X-Secret"),
+            ("Keep-Alive", "timeout=5"),
+            ("x-secret", "1"),
+            ("Accept", "*/*"),
+        ]
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+        let names: Vec<String> = end_to_end(&headers)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["Host", "Accept"]);
+    }
+
+    #[test]
+    fn test_localhost_proxy() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = ProxyConfig {
+            upstream: Some(upstream.local_addr().unwrap().to_string()),
+            auth_path: String::from("/_udger/auth"),
+            deny: Vec::new(),
+        };
+
+        thread::scope(|s| {
+            // echo the received head back in a chunked body, after an interim response
+            s.spawn(|| {
+                let (stream, _) = upstream.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let request = http::read_request(&mut reader).unwrap().unwrap();
+                let mut echo = Vec::new();
+                request.write_to(&mut echo).unwrap();
+                let mut writer = stream;
+                write!(
+                    writer,
+                    "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\n\
+                     Transfer-Encoding: identity, chunked\r\nX-Up: 1\r\n\r\n{:x}\r\n",
+                    echo.len()
+                )
+                .unwrap();
+                writer.write_all(&echo).unwrap();
+                writer.write_all(b"\r\n0\r\n\r\n").unwrap();
+            });
+            s.spawn(|| {
+                let (stream, _) = proxy.accept().unwrap();
+                handle_connection(stream, &config, &mut fake_parse).unwrap();
+            });
+
+            let mut client = TcpStream::connect(proxy.local_addr().unwrap()).unwrap();
+            client
+                .write_all(
+                    b"POST /page?q=1 HTTP/1.1\r\nUser-Agent: firefox\r\n\
+                      X-UA-Class: Spoofed\r\nConnection: close\r\nExpect: 100-continue\r\n\
+                      Content-Length: 4\r\n\r\nbody",
+                )
+                .unwrap();
+            let mut reader = BufReader::new(client);
+            let response = http::read_response_head(&mut reader, false).unwrap();
+            assert_eq!(response.status, 201);
+            assert_eq!(
+                response.headers,
+                [
+                    (String::from("X-Up"), String::from("1")),
+                    (
+                        String::from("Transfer-Encoding"),
+                        String::from("identity, chunked")
+                    ),
+                    (String::from("Connection"), String::from("close")),
+                ]
+            );
+            let mut echo = String::new();
+            http::body_reader(&mut reader, response.framing)
+                .read_to_string(&mut echo)
+                .unwrap();
+            assert!(echo.starts_with("POST /page?q=1 HTTP/1.1\r\n"));
+            assert!(echo.contains("X-Forwarded-For: 127.0.0.1\r\n"));
+            assert!(echo.contains("X-UA-Class: Browser\r\n"));
+            assert!(!echo.contains("Spoofed"));
+            assert!(!echo.contains("Expect"));
+            assert!(echo.ends_with("Content-Length: 4\r\n\r\nbody"));
+        });
+    }
+}
@@ -0,0 +1,683 @@
+use std::fmt::Write as _;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use udger::{HeadersInfo, Metrics, Rejected, UaInfo, Udger, UdgerData};
+
+use crate::args::Args;
+use crate::http::{self, Request, Response};
+use crate::{load_udger, worker_threads};
+
+pub const USAGE: &str = "\
+udger serve --db <path> [options]
+
+Serve the parser over HTTP:
+    GET  /parse?ua=<ua>    parse a single User-Agent
+    POST /parse            body: \"<ua>\", {\"ua\": \"<ua>\"}, [\"<ua>\", ...] or {\"uas\": [...]}
+                           a batch item which fails is {\"error\": \"<message>\"}
+    POST /parse/headers    body: {\"<header name>\": \"<value>\" or [\"<value>\", ...], ...}
+    GET  /health           status and loaded database version
+    GET  /metrics          Prometheus metrics
+
+Options:
+    --listen <addr>    listen address [default: 127.0.0.1:8080]
+    --threads <n>      worker threads [default: available parallelism]
+    --cache <n>        per-thread LRU cache capacity [default: 10000]";
+
+pub const SWITCHES: &[&str] = &[];
+
+/// Upper bound of User-Agents in a batch request
+const MAX_BATCH_SIZE: usize = 1000;
+/// Idle keep-alive connections are closed after this delay
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// Upper bound of the connections open at once, the others are answered 503
+const MAX_CONNECTIONS: usize = 1024;
+
+/// A request handed to the workers, with where to send its response
+type Job = (Request, Sender<Response>);
+
+const ENDPOINTS: [&str; 5] = ["/parse", "/parse/headers", "/health", "/metrics", "other"];
+
+/// Parsing of the requests
+pub trait Parser {
+    fn parse_ua(&mut self, ua: &str) -> Result<Rc<UaInfo>>;
+    fn parse_http_headers(&mut self, headers: &[u8]) -> Result<HeadersInfo>;
+}
+
+/// Parser of a worker, with its own `UdgerData`
+struct Worker<'a> {
+    udger: &'a Udger,
+    data: UdgerData,
+}
+
+impl Parser for Worker<'_> {
+    fn parse_ua(&mut self, ua: &str) -> Result<Rc<UaInfo>> {
+        self.udger.parse_ua(&ua, &mut self.data)
+    }
+
+    fn parse_http_headers(&mut self, headers: &[u8]) -> Result<HeadersInfo> {
+        self.udger.parse_http_headers(headers, &mut self.data)
+    }
+}
+
+/// Item of a batch response
+#[derive(Serialize)]
+#[serde(untagged)]
+enum BatchItem<'a> {
+    Parsed(&'a UaInfo),
+    Failed { error: String },
+}
+
+/// State shared by all the workers
+pub struct ServerState {
+    db_version: String,
+    started: Instant,
+    requests: [AtomicU64; ENDPOINTS.len()],
+    errors: AtomicU64,
+    parsed: AtomicU64,
+    parse_errors: AtomicU64,
+    /// Cache and detector metrics of the parser
+    parser: Option<Arc<Metrics>>,
+}
+
+impl ServerState {
+    pub fn new(db_version: &str) -> ServerState {
+        ServerState {
+            db_version: db_version.to_string(),
+            started: Instant::now(),
+            requests: Default::default(),
+            errors: AtomicU64::new(0),
+            parsed: AtomicU64::new(0),
+            parse_errors: AtomicU64::new(0),
+            parser: None,
+        }
+    }
+
+    pub fn with_parser_metrics(mut self, metrics: Arc<Metrics>) -> ServerState {
+        self.parser = Some(metrics);
+        self
+    }
+
+    fn count_request(&self, path: &str) {
+        let i = ENDPOINTS
+            .iter()
+            .position(|endpoint| *endpoint == path)
+            .unwrap_or(ENDPOINTS.len() - 1);
+        self.requests[i].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn metrics(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP udger_http_requests_total HTTP requests by endpoint."
+        );
+        let _ = writeln!(out, "# TYPE udger_http_requests_total counter");
+        for (endpoint, count) in ENDPOINTS.iter().zip(self.requests.iter()) {
+            let _ = writeln!(
+                out,
+                "udger_http_requests_total{{endpoint=\"{}\"}} {}",
+                endpoint,
+                count.load(Ordering::Relaxed)
+            );
+        }
+        let counters = [
+            (
+                "udger_http_errors_total",
+                "HTTP requests answered with an error status.",
+                &self.errors,
+            ),
+            (
+                "udger_parsed_user_agents_total",
+                "User-Agents parsed, including batch items.",
+                &self.parsed,
+            ),
+            (
+                "udger_parse_errors_total",
+                "User-Agents which failed to be parsed.",
+                &self.parse_errors,
+            ),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+        let _ = writeln!(
+            out,
+            "# HELP udger_uptime_seconds Seconds since the server started."
+        );
+        let _ = writeln!(out, "# TYPE udger_uptime_seconds gauge");
+        let _ = writeln!(
+            out,
+            "udger_uptime_seconds {}",
+            self.started.elapsed().as_secs()
+        );
+        let _ = writeln!(out, "# HELP udger_db_info Loaded udger database.");
+        let _ = writeln!(out, "# TYPE udger_db_info gauge");
+        let _ = writeln!(
+            out,
+            "udger_db_info{{version=\"{}\"}} 1",
+            self.db_version.replace('\\', "\\\\").replace('"', "\\\"")
+        );
+        if let Some(parser) = &self.parser {
+            out.push_str(&parser.snapshot().prometheus());
+        }
+        out
+    }
+}
+
+pub fn run(args: &Args) -> Result<()> {
+    let udger = load_udger(args)?;
+    let threads = worker_threads(args)?;
+    let listener = TcpListener::bind(args.get_or("listen", "127.0.0.1:8080"))?;
+    let state = ServerState::new(udger.db_version()).with_parser_metrics(udger.metrics().clone());
+
+    eprintln!(
+        "udger: database {} loaded, listening on http://{} with {} workers",
+        udger.db_version(),
+        listener.local_addr()?,
+        threads
+    );
+    serve(&udger, &listener, threads, &state)
+}
+
+/// Read each connection on its own thread and hand its requests to `threads`
+/// workers, each with its own `UdgerData`, so that idle keep-alive
+/// connections don't hold a worker
+pub fn serve(
+    udger: &Udger,
+    listener: &TcpListener,
+    threads: usize,
+    state: &ServerState,
+) -> Result<()> {
+    let threads = threads.max(1);
+    let open = AtomicUsize::new(0);
+
+    thread::scope(|s| {
+        let (job_tx, job_rx) = mpsc::sync_channel::<Job>(threads * 2);
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let (ready_tx, ready_rx) = mpsc::channel::<Result<()>>();
+
+        for _ in 0..threads {
+            let job_rx = job_rx.clone();
+            let ready_tx = ready_tx.clone();
+            s.spawn(move || {
+                let mut worker = match udger.alloc_udger_data() {
+                    Err(err) => {
+                        let _ = ready_tx.send(Err(err));
+                        return;
+                    }
+                    Ok(data) => Worker { udger, data },
+                };
+                let _ = ready_tx.send(Ok(()));
+                while let Some((request, response_tx)) = next_job(&job_rx) {
+                    let _ = response_tx.send(handle(&request, state, &mut worker));
+                }
+            });
+        }
+        drop(ready_tx);
+        // dropping job_tx on error stops the workers which did start
+        for ready in ready_rx {
+            ready?;
+        }
+
+        let open = &open;
+        for mut stream in listener.incoming().flatten() {
+            if open.fetch_add(1, Ordering::Relaxed) >= MAX_CONNECTIONS {
+                open.fetch_sub(1, Ordering::Relaxed);
+                state.errors.fetch_add(1, Ordering::Relaxed);
+                let _ = Response::error(503, "too many connections").write_to(&mut stream, false);
+                continue;
+            }
+            let job_tx = job_tx.clone();
+            s.spawn(move || {
+                let mut respond = |request| dispatch(&job_tx, request);
+                // a broken connection only concerns its client
+                let _ = handle_connection(stream, state, &mut respond);
+                open.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+        Ok(())
+    })
+}
+
+fn next_job(job_rx: &Mutex<Receiver<Job>>) -> Option<Job> {
+    job_rx.lock().ok()?.recv().ok()
+}
+
+/// Hand a request to the workers and wait for its response
+fn dispatch(job_tx: &SyncSender<Job>, request: Request) -> Response {
+    let (response_tx, response_rx) = mpsc::channel();
+    if job_tx.send((request, response_tx)).is_err() {
+        return Response::error(503, "no worker left");
+    }
+    response_rx
+        .recv()
+        .unwrap_or_else(|_| Response::error(500, "worker thread panicked"))
+}
+
+/// Serve requests of a connection until it's closed or stops keeping alive,
+/// `respond` answers each of them
+pub fn handle_connection<F>(stream: TcpStream, state: &ServerState, respond: &mut F) -> Result<()>
+where
+    F: FnMut(Request) -> Response,
+{
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let request = match http::read_request(&mut reader) {
+            Ok(None) | Err(http::Error::Io(_)) => return Ok(()),
+            Err(http::Error::Bad(status, msg)) => {
+                state.errors.fetch_add(1, Ordering::Relaxed);
+                Response::error(status, &msg).write_to(&mut writer, false)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Ok(Some(request)) => request,
+        };
+
+        let keep_alive = request.keep_alive();
+        let response = respond(request);
+        if response.status >= 400 {
+            state.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        response.write_to(&mut writer, keep_alive)?;
+        writer.flush()?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Route a request
+pub fn handle<P: Parser>(request: &Request, state: &ServerState, parser: &mut P) -> Response {
+    state.count_request(&request.path);
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/parse") => match request.query_param("ua") {
+            None => Response::error(400, "missing `ua` query parameter"),
+            Some(ua) => parse_one(&ua, state, parser),
+        },
+        ("POST", "/parse") => match serde_json::from_slice::<Value>(&request.body) {
+            Err(err) => Response::error(400, &format!("invalid JSON body: {}", err)),
+            Ok(Value::String(ua)) => parse_one(&ua, state, parser),
+            Ok(Value::Array(uas)) => parse_batch(&uas, state, parser),
+            Ok(Value::Object(obj)) => match (obj.get("ua"), obj.get("uas")) {
+                (Some(Value::String(ua)), None) => parse_one(ua, state, parser),
+                (None, Some(Value::Array(uas))) => parse_batch(uas, state, parser),
+                _ => Response::error(400, "expected an object with either `ua` or `uas`"),
+            },
+            Ok(_) => Response::error(400, "expected a string, an array or an object"),
+        },
+        ("POST", "/parse/headers") => match serde_json::from_slice::<Value>(&request.body) {
+            Err(err) => Response::error(400, &format!("invalid JSON body: {}", err)),
+            Ok(Value::Object(headers)) => match header_block(&headers) {
+                Err(msg) => Response::error(400, msg),
+                Ok(block) => parse_headers(&block, state, parser),
+            },
+            Ok(_) => Response::error(400, "expected an object of headers"),
+        },
+        ("GET", "/health") => Response::json(
+            200,
+            &serde_json::json!({
+                "status": "ok",
+                "db_version": state.db_version,
+                "uptime_seconds": state.started.elapsed().as_secs(),
+            }),
+        ),
+        ("GET", "/metrics") => Response::new(
+            200,
+            "text/plain; version=0.0.4",
+            state.metrics().into_bytes(),
+        ),
+        (_, "/parse" | "/parse/headers" | "/health" | "/metrics") => {
+            Response::error(405, "method not allowed")
+        }
+        _ => Response::error(404, "not found"),
+    }
+}
+
+/// Response to a parse error, rejected User-Agents are the client's fault
+fn parse_error(err: &anyhow::Error, state: &ServerState) -> Response {
+    state.parse_errors.fetch_add(1, Ordering::Relaxed);
+    let status = if err.is::<Rejected>() { 400 } else { 500 };
+    Response::error(status, &err.to_string())
+}
+
+fn parse_one<P: Parser>(ua: &str, state: &ServerState, parser: &mut P) -> Response {
+    state.parsed.fetch_add(1, Ordering::Relaxed);
+    match parser.parse_ua(ua) {
+        Err(err) => parse_error(&err, state),
+        Ok(info) => Response::json(200, &*info),
+    }
+}
+
+/// Raw header block of a JSON object of headers, whose values are strings or
+/// arrays of strings for repeated headers
+fn header_block(headers: &Map<String, Value>) -> Result<Vec<u8>, &'static str> {
+    let mut block = Vec::new();
+    for (name, value) in headers {
+        let values = match value {
+            Value::String(value) => vec![value.as_str()],
+            Value::Array(values) => values
+                .iter()
+                .map(|value| value.as_str().ok_or("header values must be strings"))
+                .collect::<Result<_, _>>()?,
+            _ => return Err("header values must be strings"),
+        };
+        let valid_name =
+            !name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic() && b != b':');
+        if !valid_name {
+            return Err("invalid header name");
+        }
+        for value in values {
+            if value.contains(['\r', '\n']) {
+                return Err("header values must be single lines");
+            }
+            block.extend_from_slice(name.as_bytes());
+            block.extend_from_slice(b": ");
+            block.extend_from_slice(value.as_bytes());
+            block.extend_from_slice(b"\r\n");
+        }
+    }
+    Ok(block)
+}
+
+fn parse_headers<P: Parser>(block: &[u8], state: &ServerState, parser: &mut P) -> Response {
+    match parser.parse_http_headers(block) {
+        Ok(HeadersInfo { ua_info: None, .. }) => Response::error(400, "no User-Agent header"),
+        Ok(HeadersInfo {
+            ua_info: Some(info),
+            ..
+        }) => {
+            state.parsed.fetch_add(1, Ordering::Relaxed);
+            Response::json(200, &*info)
+        }
+        Err(err) => {
+            state.parsed.fetch_add(1, Ordering::Relaxed);
+            parse_error(&err, state)
+        }
+    }
+}
+
+fn parse_batch<P: Parser>(uas: &[Value], state: &ServerState, parser: &mut P) -> Response {
+    if uas.len() > MAX_BATCH_SIZE {
+        return Response::error(
+            413,
+            &format!("batches are limited to {} User-Agents", MAX_BATCH_SIZE),
+        );
+    }
+    if !uas.iter().all(Value::is_string) {
+        return Response::error(400, "batch items must be strings");
+    }
+
+    // a failing item doesn't fail the others
+    let results: Vec<Result<Rc<UaInfo>>> = uas
+        .iter()
+        .filter_map(Value::as_str)
+        .map(|ua| {
+            state.parsed.fetch_add(1, Ordering::Relaxed);
+            parser.parse_ua(ua)
+        })
+        .collect();
+    let items: Vec<BatchItem> = results
+        .iter()
+        .map(|result| match result {
+            Ok(info) => BatchItem::Parsed(info),
+            Err(err) => {
+                state.parse_errors.fetch_add(1, Ordering::Relaxed);
+                BatchItem::Failed {
+                    error: err.to_string(),
+                }
+            }
+        })
+        .collect();
+    Response::json(200, &items)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, Read};
+    use std::net::TcpListener;
+
+    use udger::{HttpHeaders, Normalization};
+
+    use super::*;
+
+    /// Rejects control characters, the device User-Agent replaces the OS
+    struct FakeParser;
+
+    impl Parser for FakeParser {
+        fn parse_ua(&mut self, ua: &str) -> Result<Rc<UaInfo>> {
+            let normalization = Normalization {
+                reject_control: true,
+                ..Default::default()
+            };
+            normalization.apply(ua.as_bytes())?;
+            Ok(Rc::new(UaInfo {
+                ua_class: String::from("Browser"),
+                ua_string: ua.to_string(),
+                ..Default::default()
+            }))
+        }
+
+        fn parse_http_headers(&mut self, headers: &[u8]) -> Result<HeadersInfo> {
+            let headers = HttpHeaders::parse(headers);
+            let device_ua = headers.device_user_agent().map(str::to_string);
+            let ua_info = match headers.user_agent.as_deref() {
+                None => None,
+                Some(ua) => {
+                    let mut info = (*self.parse_ua(ua)?).clone();
+                    info.os = device_ua.clone().unwrap_or_default();
+                    Some(Rc::new(info))
+                }
+            };
+            Ok(HeadersInfo {
+                ua_info,
+                device_user_agent: device_ua,
+                client_ip: headers.client_ip(),
+                client_hints: Vec::new(),
+            })
+        }
+    }
+
+    fn request(method: &str, target: &str, body: &str) -> Request {
+        let raw = format!(
+            "{} {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            target,
+            body.len(),
+            body
+        );
+        http::read_request(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    fn body(response: &Response) -> Value {
+        serde_json::from_slice(&response.body).unwrap()
+    }
+
+    #[test]
+    fn test_routes() {
+        let state =
+            ServerState::new("20240101-01").with_parser_metrics(Arc::new(Metrics::default()));
+        let parse = &mut FakeParser;
+
+        let res = handle(
+            &request("GET", "/parse?ua=Mozilla%2F5.0", ""),
+            &state,
+            parse,
+        );
+        assert_eq!(res.status, 200);
+        assert_eq!(body(&res)["ua_string"], "Mozilla/5.0");
+
+        let res = handle(&request("POST", "/parse", r#"{"ua":"a"}"#), &state, parse);
+        assert_eq!(body(&res)["ua_string"], "a");
+
+        let res = handle(&request("POST", "/parse", r#"["a","b"]"#), &state, parse);
+        assert_eq!(body(&res)[1]["ua_string"], "b");
+
+        let res = handle(
+            &request("POST", "/parse", r#"{"uas":["c"]}"#),
+            &state,
+            parse,
+        );
+        assert_eq!(body(&res)[0]["ua_string"], "c");
+
+        let res = handle(
+            &request(
+                "POST",
+                "/parse/headers",
+                r#"{"USER-AGENT":"d","Accept":"*/*"}"#,
+            ),
+            &state,
+            parse,
+        );
+        assert_eq!(body(&res)["ua_string"], "d");
+
+        let res = handle(
+            &request(
+                "POST",
+                "/parse/headers",
+                r#"{"user-agent":["e","f"],"X-OperaMini-Phone-UA":"g"}"#,
+            ),
+            &state,
+            parse,
+        );
+        assert_eq!(body(&res)["ua_string"], "e");
+        assert_eq!(body(&res)["os"], "g");
+
+        // rejected User-Agents are client errors, and only fail their batch item
+        let res = handle(&request("GET", "/parse?ua=%01", ""), &state, parse);
+        assert_eq!(res.status, 400);
+        let res = handle(
+            &request("POST", "/parse", r#"["a","\u0001"]"#),
+            &state,
+            parse,
+        );
+        assert_eq!(res.status, 200);
+        assert_eq!(body(&res)[0]["ua_string"], "a");
+        assert!(body(&res)[1]["error"].is_string());
+
+        let res = handle(&request("GET", "/health", ""), &state, parse);
+        assert_eq!(body(&res)["db_version"], "20240101-01");
+
+        for (method, target, body, status) in [
+            ("GET", "/parse", "", 400),
+            ("POST", "/parse", "{", 400),
+            ("POST", "/parse", "[1]", 400),
+            ("POST", "/parse/headers", "{}", 400),
+            ("POST", "/parse/headers", r#"{"User-Agent":1}"#, 400),
+            (
+                "POST",
+                "/parse/headers",
+                r#"{"User-Agent":"a\r\nX-A: b"}"#,
+                400,
+            ),
+            ("POST", "/parse/headers", r#"{"User Agent":"a"}"#, 400),
+            ("DELETE", "/parse", "", 405),
+            ("GET", "/nope", "", 404),
+        ] {
+            let res = handle(&request(method, target, body), &state, parse);
+            assert_eq!(res.status, status, "{} {}", method, target);
+        }
+
+        let res = handle(&request("GET", "/metrics", ""), &state, parse);
+        let metrics = String::from_utf8(res.body).unwrap();
+        assert!(metrics.contains("udger_http_requests_total{endpoint=\"/parse\"} 10"));
+        assert!(metrics.contains("udger_parsed_user_agents_total 10"));
+        assert!(metrics.contains("udger_parse_errors_total 2"));
+        assert!(metrics.contains("udger_db_info{version=\"20240101-01\"} 1"));
+        assert!(metrics.contains("udger_parses_total 0"));
+    }
+
+    #[test]
+    fn test_localhost_keep_alive() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = ServerState::new("test");
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                let (stream, _) = listener.accept().unwrap();
+                let mut respond = |request: Request| handle(&request, &state, &mut FakeParser);
+                handle_connection(stream, &state, &mut respond).unwrap();
+            });
+
+            let mut client = TcpStream::connect(addr).unwrap();
+            client
+                .write_all(
+                    b"GET /parse?ua=x HTTP/1.1\r\n\r\n\
+                      GET /health HTTP/1.1\r\nConnection: close\r\n\r\n",
+                )
+                .unwrap();
+            let mut reader = BufReader::new(client);
+            let mut status = String::new();
+            reader.read_line(&mut status).unwrap();
+            assert_eq!(status, "HTTP/1.1 200 OK\r\n");
+            let mut rest = String::new();
+            reader.read_to_string(&mut rest).unwrap();
+            assert!(rest.contains("\"ua_string\":\"x\""));
+            assert!(rest.contains("\"status\":\"ok\""));
+        });
+    }
+
+    #[test]
+    fn test_localhost_idle_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = ServerState::new("test");
+        let (job_tx, job_rx) = mpsc::sync_channel::<Job>(1);
+        let job_rx = Mutex::new(job_rx);
+
+        thread::scope(|s| {
+            // a single worker
+            s.spawn(|| {
+                while let Some((request, response_tx)) = next_job(&job_rx) {
+                    let _ = response_tx.send(handle(&request, &state, &mut FakeParser));
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..2 {
+                    let (stream, _) = listener.accept().unwrap();
+                    let job_tx = job_tx.clone();
+                    let state = &state;
+                    s.spawn(move || {
+                        let mut respond = |request| dispatch(&job_tx, request);
+                        handle_connection(stream, state, &mut respond).unwrap();
+                    });
+                }
+                drop(job_tx);
+            });
+
+            // kept alive and idle
+            let idle = TcpStream::connect(addr).unwrap();
+            (&idle).write_all(b"GET /health HTTP/1.1\r\n\r\n").unwrap();
+            let mut status = String::new();
+            BufReader::new(&idle).read_line(&mut status).unwrap();
+            assert_eq!(status, "HTTP/1.1 200 OK\r\n");
+
+            let mut client = TcpStream::connect(addr).unwrap();
+            client
+                .write_all(b"GET /parse?ua=y HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.contains("\"ua_string\":\"y\""));
+            drop(idle);
+        });
+    }
+}
@@ -0,0 +1,226 @@
+use std::fs;
+use std::io::{BufReader, BufWriter, Write};
+use std::num::NonZeroUsize;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::{anyhow, Result};
+
+use udger::sidecar;
+use udger::{UaInfo, Udger};
+
+use crate::args::Args;
+use crate::{load_udger, pool, worker_threads};
+
+pub const USAGE: &str = "\
+udger sidecar --db <path> --socket <path> [options]
+
+Answer parse requests on a Unix domain socket, using the length-prefixed
+protocol of the `udger::sidecar` module. Each connection is read on its own
+thread and its User-Agents are parsed by --threads workers, so clients may keep
+their connections open while idle and pipeline their requests on them. Responses
+are shared between connections through a common cache.
+
+Options:
+    --socket <path>    Unix socket path, a stale socket file is replaced
+    --threads <n>      worker threads [default: available parallelism]
+    --cache <n>        shared and per-thread cache capacity [default: 10000]";
+
+pub const SWITCHES: &[&str] = &[];
+
+/// Encoded responses shared by all the workers
+pub struct SharedCache {
+    cache: Mutex<clru::CLruCache<String, Arc<Vec<u8>>>>,
+}
+
+impl SharedCache {
+    pub fn new(capacity: usize) -> Result<SharedCache> {
+        let capacity = NonZeroUsize::new(capacity)
+            .ok_or_else(|| anyhow!("cache capacity must be greater than zero"))?;
+        Ok(SharedCache {
+            cache: Mutex::new(clru::CLruCache::new(capacity)),
+        })
+    }
+
+    fn get(&self, ua: &str) -> Option<Arc<Vec<u8>>> {
+        self.cache.lock().ok()?.get(ua).cloned()
+    }
+
+    fn put(&self, ua: String, frame: Arc<Vec<u8>>) {
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.put(ua, frame);
+        }
+    }
+}
+
+pub fn run(args: &Args) -> Result<()> {
+    let path = args
+        .get("socket")
+        .ok_or_else(|| anyhow!("missing required option --socket"))?;
+    let udger = load_udger(args)?;
+    let threads = worker_threads(args)?;
+    let cache = SharedCache::new(args.get_parsed("cache", 10000)?)?;
+
+    remove_stale_socket(Path::new(path))?;
+    let listener = UnixListener::bind(path)?;
+    eprintln!(
+        "udger: database {} loaded, listening on {} with {} workers",
+        udger.db_version(),
+        path,
+        threads
+    );
+    serve(&udger, &listener, threads, &cache)
+}
+
+/// Remove a socket file left behind by a previous daemon, nobody listens on it
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Err(_) => return Ok(()),
+        Ok(metadata) => metadata,
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(anyhow!("{} exists and is not a socket", path.display()));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(anyhow!("{} is in use by another process", path.display()));
+    }
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Read each connection on its own thread and parse the User-Agents on
+/// `threads` workers, each with its own `UdgerData`, so that idle clients
+/// don't hold a worker
+pub fn serve(
+    udger: &Udger,
+    listener: &UnixListener,
+    threads: usize,
+    cache: &SharedCache,
+) -> Result<()> {
+    thread::scope(|s| {
+        let parser = pool::parse_workers(s, udger, threads)?;
+        let handle = move |stream| {
+            let mut parse = |ua: &str| parser.parse(ua);
+            // a broken connection only concerns its client
+            let _ = handle_client(stream, cache, &mut parse);
+        };
+        pool::serve_connections(s, listener.incoming(), handle, |mut stream| {
+            // the answer to the first request
+            let _ = stream.write_all(&sidecar::encode_response(Err("too many connections")));
+        });
+        Ok(())
+    })
+}
+
+/// Answer the requests of a client in order, until it disconnects
+pub fn handle_client<F>(stream: UnixStream, cache: &SharedCache, parse: &mut F) -> Result<()>
+where
+    F: FnMut(&str) -> Result<Rc<UaInfo>>,
+{
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    while let Some(ua) = sidecar::read_request(&mut reader)? {
+        let frame = match cache.get(&ua) {
+            Some(frame) => frame,
+            None => {
+                // neither errors, which may be transient, nor results cut
+                // short by the time budget are cached, as in `Udger::parse_ua`
+                let (frame, cached) = match parse(&ua) {
+                    Ok(info) => (
+                        Arc::new(sidecar::encode_response(Ok(&info))),
+                        info.parse_aborted.is_empty(),
+                    ),
+                    Err(err) => (
+                        Arc::new(sidecar::encode_response(Err(&err.to_string()))),
+                        false,
+                    ),
+                };
+                if cached {
+                    cache.put(ua, frame.clone());
+                }
+                frame
+            }
+        };
+        writer.write_all(&frame)?;
+
+        // pipelined requests are answered in a single write
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use udger::sidecar::SidecarClient;
+
+    use super::*;
+
+    #[test]
+    fn test_handle_client() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let cache = SharedCache::new(10).unwrap();
+        let calls = Cell::new(0);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mut client = SidecarClient::from_stream(client).unwrap();
+                let uas = ["a", "b", "a", "fail", "a"];
+                let infos: Vec<_> = uas.iter().map(|ua| client.parse(ua)).collect();
+                assert_eq!(infos[0].as_ref().unwrap().ua_string, "a");
+                assert_eq!(infos[1].as_ref().unwrap().ua_string, "b");
+                assert!(infos[3].is_err());
+
+                let many = client.parse_many(&["c", "a", "c"]).unwrap();
+                assert_eq!(many[2].ua_string, "c");
+
+                // the responses after an error are still read
+                assert!(client.parse_many(&["fail", "b"]).is_err());
+                assert_eq!(client.parse("a").unwrap().ua_string, "a");
+
+                for _ in 0..2 {
+                    assert_eq!(client.parse("slow").unwrap().parse_aborted, "os");
+                }
+            });
+
+            let mut parse = |ua: &str| {
+                calls.set(calls.get() + 1);
+                match ua {
+                    "fail" => Err(anyhow!("boom")),
+                    "slow" => Ok(Rc::new(UaInfo {
+                        parse_aborted: String::from("os"),
+                        ..Default::default()
+                    })),
+                    ua => Ok(Rc::new(UaInfo {
+                        ua_string: ua.to_string(),
+                        ..Default::default()
+                    })),
+                }
+            };
+            handle_client(server, &cache, &mut parse).unwrap();
+        });
+
+        // "a", "b" and "c" were answered from the cache after their first
+        // parse, the partial "slow" results weren't cached
+        assert_eq!(calls.get(), 7);
+    }
+
+    #[test]
+    fn test_remove_stale_socket() {
+        let path = std::env::temp_dir().join(format!("udger-test-{}.sock", std::process::id()));
+        let listener = UnixListener::bind(&path).unwrap();
+        assert!(remove_stale_socket(&path).is_err());
+        drop(listener);
+        remove_stale_socket(&path).unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -2,6 +2,7 @@ use std::ffi::{CStr, OsStr};
 use std::os::raw::{c_char, c_int, c_void};
 #[cfg(target_family = "unix")]
 use std::os::unix::ffi::OsStrExt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::path::PathBuf;
 
 use libc::size_t;
@@ -14,6 +15,9 @@ unsafe extern "C" fn udger_new(
     db_path: *const c_char,
     capacity: std::os::raw::c_uint,
 ) -> c_int {
+    if _udger.is_null() || db_path.is_null() {
+        return -1;
+    }
     let mut rc = 0;
     let mut u = Box::new(Udger::new());
 
@@ -24,9 +28,10 @@ unsafe extern "C" fn udger_new(
     #[cfg(target_family = "unix")]
     let path_buf = PathBuf::from(osstr);
 
-    match u.init(path_buf, capacity as usize) {
-        Ok(_) => {}
-        Err(_) => rc = -1,
+    // a panic must not unwind into C, the instance is returned uninitialized
+    match catch_unwind(AssertUnwindSafe(|| u.init(path_buf, capacity as usize))) {
+        Ok(Ok(_)) => {}
+        Ok(Err(_)) | Err(_) => rc = -1,
     }
     *_udger = Box::into_raw(u);
     rc
@@ -41,29 +46,69 @@ unsafe extern "C" fn udger_drop(udger: *mut Udger) {
 
 type UdgerCallBack = unsafe extern "C" fn(info: *const UaInfo, data: *mut c_void) -> c_int;
 
-#[no_mangle]
-unsafe extern "C" fn udger_parse_ua(
+/// Parse `ua` and pass the result to `cb`, a panic must not unwind into C and
+/// is reported as an error
+unsafe fn parse_ua_bytes(
     udger: *const Udger,
     udger_data: *mut UdgerData,
-    ua: *const c_char,
+    ua: &[u8],
     cb: UdgerCallBack,
     data: *mut c_void,
 ) -> c_int {
-    let ua = CStr::from_ptr(ua).to_string_lossy();
-    match (*udger).parse_ua(&ua, &mut *udger_data) {
-        Ok(info) => match cb(info.as_ref() as *const UaInfo, data) {
+    if udger.is_null() || udger_data.is_null() {
+        return -1;
+    }
+    let parsed = catch_unwind(AssertUnwindSafe(|| {
+        (*udger).parse_ua_bytes(ua, &mut *udger_data)
+    }));
+    match parsed {
+        Ok(Ok(info)) => match cb(info.as_ref() as *const UaInfo, data) {
             rc if rc >= 0 => 0,
             _ => -2,
         },
-        Err(_) => -1,
+        Ok(Err(_)) | Err(_) => -1,
     }
 }
 
+#[no_mangle]
+unsafe extern "C" fn udger_parse_ua(
+    udger: *const Udger,
+    udger_data: *mut UdgerData,
+    ua: *const c_char,
+    cb: UdgerCallBack,
+    data: *mut c_void,
+) -> c_int {
+    if ua.is_null() {
+        return -1;
+    }
+    parse_ua_bytes(udger, udger_data, CStr::from_ptr(ua).to_bytes(), cb, data)
+}
+
+#[no_mangle]
+unsafe extern "C" fn udger_parse_ua_bytes(
+    udger: *const Udger,
+    udger_data: *mut UdgerData,
+    ua: *const u8,
+    ua_len: size_t,
+    cb: UdgerCallBack,
+    data: *mut c_void,
+) -> c_int {
+    let ua = match (ua.is_null(), ua_len) {
+        (true, 0) => &[],
+        (true, _) => return -1,
+        (false, _) => std::slice::from_raw_parts(ua, ua_len),
+    };
+    parse_ua_bytes(udger, udger_data, ua, cb, data)
+}
+
 #[no_mangle]
 unsafe extern "C" fn udger_data_alloc(
     udger: *const Udger,
     mut _data: *mut *const UdgerData,
 ) -> c_int {
+    if udger.is_null() || _data.is_null() {
+        return -1;
+    }
     match (*udger).alloc_udger_data() {
         Err(_) => return -1,
         Ok(udata) => {
@@ -76,7 +121,7 @@ unsafe extern "C" fn udger_data_alloc(
 
 #[no_mangle]
 unsafe extern "C" fn udger_data_drop(data: *mut UdgerData) {
-    if data.is_null() {
+    if !data.is_null() {
         drop(Box::from_raw(data))
     }
 }
@@ -89,6 +134,14 @@ macro_rules! get_function {
             mut _buf: *mut *const c_char,
             len: *mut size_t,
         ) {
+            if _buf.is_null() || len.is_null() {
+                return;
+            }
+            if info.is_null() {
+                *_buf = std::ptr::null();
+                *len = 0;
+                return;
+            }
             *_buf = (*info).$field_name.as_ptr() as *const c_char;
             *len = (*info).$field_name.len();
         }
@@ -99,6 +152,8 @@ get_function!(ua_info_get_ua_class, ua_class);
 get_function!(ua_info_get_ua_class_code, ua_class_code);
 get_function!(ua_info_get_ua, ua);
 get_function!(ua_info_get_ua_engine, ua_engine);
+get_function!(ua_info_get_ua_engine_version, ua_engine_version);
+get_function!(ua_info_get_ua_engine_version_major, ua_engine_version_major);
 get_function!(ua_info_get_ua_version, ua_version);
 get_function!(ua_info_get_ua_version_major, ua_version_major);
 get_function!(ua_info_get_ua_version_minor, ua_version_minor);
@@ -120,19 +175,38 @@ get_function!(ua_info_get_ua_family_code, ua_family_code);
 get_function!(ua_info_get_ua_family_vendor, ua_family_vendor);
 get_function!(ua_info_get_ua_family_vendor_code, ua_family_vendor_code);
 get_function!(ua_info_get_ua_string, ua_string);
+get_function!(ua_info_get_ua_charset, ua_charset);
+get_function!(ua_info_get_ua_normalization, ua_normalization);
+get_function!(ua_info_get_parse_aborted, parse_aborted);
+
+#[no_mangle]
+unsafe extern "C" fn ua_info_get_regex_limit_errors(info: *const UaInfo) -> u32 {
+    if info.is_null() {
+        return 0;
+    }
+    (*info).regex_limit_errors
+}
 
 get_function!(ua_info_get_os_family, os_family);
 get_function!(ua_info_get_os_family_code, os_family_code);
 get_function!(ua_info_get_os, os);
 get_function!(ua_info_get_os_code, os_code);
+get_function!(ua_info_get_os_version, os_version);
+get_function!(ua_info_get_os_version_major, os_version_major);
+get_function!(ua_info_get_os_version_minor, os_version_minor);
 get_function!(ua_info_get_os_family_vendor, os_family_vendor);
 get_function!(ua_info_get_os_family_vendor_code, os_family_vendor_code);
+get_function!(ua_info_get_os_source, os_source);
 
 get_function!(ua_info_get_device_class, device_class);
 get_function!(ua_info_get_device_class_code, device_class_code);
 get_function!(ua_info_get_device_marketname, device_marketname);
 get_function!(ua_info_get_device_brand, device_brand);
 get_function!(ua_info_get_device_brand_code, device_brand_code);
+get_function!(ua_info_get_device_brand_rule, device_brand_rule);
+get_function!(ua_info_get_cpu_architecture, cpu_architecture);
+get_function!(ua_info_get_bitness, bitness);
+get_function!(ua_info_get_ua_locale, ua_locale);
 
 #[cfg(feature = "application")]
 get_function!(ua_info_get_application_name, application_name);
@@ -197,6 +271,9 @@ get_function!(ua_info_get_device_class_info_url, device_class_info_url);
 #[cfg(feature = "url")]
 get_function!(ua_info_get_device_brand_info_url, device_brand_info_url);
 
+/// Serialize `info` as JSON into a NUL-terminated buffer allocated with
+/// `malloc`, which the caller frees. `len` includes the NUL. Returns -1 on a
+/// null pointer, a serialization or an allocation failure
 #[no_mangle]
 unsafe extern "C" fn ua_info_to_string(
     info: *const UaInfo,
@@ -204,39 +281,26 @@ unsafe extern "C" fn ua_info_to_string(
     len: *mut size_t,
     pretty: bool,
 ) -> c_int {
-    match pretty {
-        true => {
-            match serde_json::to_string_pretty(&(*info)) {
-                Err(_) => todo!("Properly handle to_string error"),
-                Ok(s) => {
-                    *len = s.len() + 1;
-                    *output = libc::malloc(*len) as *mut c_char;
-                    libc::memset(*output as *mut std::os::raw::c_void, 0, *len);
-                    libc::memcpy(
-                        *output as *mut std::os::raw::c_void,
-                        s.as_ptr() as *mut std::os::raw::c_void,
-                        s.len(),
-                    );
-                }
-            };
-        }
-        false => {
-            match serde_json::to_string(&(*info)) {
-                Err(_) => todo!("Properly handle to_string error"),
-                Ok(s) => {
-                    *len = s.len() + 1;
-                    *output = libc::malloc(*len) as *mut c_char;
-                    libc::memset(*output as *mut std::os::raw::c_void, 0, *len);
-                    libc::memcpy(
-                        *output as *mut std::os::raw::c_void,
-                        s.as_ptr() as *mut std::os::raw::c_void,
-                        s.len(),
-                    );
-                }
-            };
-        }
+    if info.is_null() || output.is_null() || len.is_null() {
+        return -1;
     }
+    let json = catch_unwind(AssertUnwindSafe(|| match pretty {
+        true => serde_json::to_string_pretty(&*info),
+        false => serde_json::to_string(&*info),
+    }));
+    let json = match json {
+        Ok(Ok(json)) => json,
+        Ok(Err(_)) | Err(_) => return -1,
+    };
 
+    let buf = libc::malloc(json.len() + 1) as *mut c_char;
+    if buf.is_null() {
+        return -1;
+    }
+    std::ptr::copy_nonoverlapping(json.as_ptr() as *const c_char, buf, json.len());
+    *buf.add(json.len()) = 0;
+    *output = buf;
+    *len = json.len() + 1;
     0
 }
 
@@ -275,16 +339,24 @@ mod tests {
 
             test_get_function!(ua_info_get_device_brand, device_brand);
             test_get_function!(ua_info_get_device_brand_code, device_brand_code);
+            test_get_function!(ua_info_get_device_brand_rule, device_brand_rule);
+            test_get_function!(ua_info_get_cpu_architecture, cpu_architecture);
+            test_get_function!(ua_info_get_bitness, bitness);
+            test_get_function!(ua_info_get_ua_locale, ua_locale);
             test_get_function!(ua_info_get_device_class, device_class);
             test_get_function!(ua_info_get_device_class_code, device_class_code);
             test_get_function!(ua_info_get_device_marketname, device_marketname);
 
             test_get_function!(ua_info_get_os, os);
             test_get_function!(ua_info_get_os_code, os_code);
+            test_get_function!(ua_info_get_os_version, os_version);
+            test_get_function!(ua_info_get_os_version_major, os_version_major);
+            test_get_function!(ua_info_get_os_version_minor, os_version_minor);
             test_get_function!(ua_info_get_os_family, os_family);
             test_get_function!(ua_info_get_os_family_code, os_family_code);
             test_get_function!(ua_info_get_os_family_vendor, os_family_vendor);
             test_get_function!(ua_info_get_os_family_vendor_code, os_family_vendor_code);
+            test_get_function!(ua_info_get_os_source, os_source);
 
             test_get_function!(ua_info_get_ua, ua);
             test_get_function!(ua_info_get_ua, ua_string);
@@ -292,11 +364,18 @@ mod tests {
             test_get_function!(ua_info_get_ua_class, ua_class);
             test_get_function!(ua_info_get_ua_class_code, ua_class_code);
             test_get_function!(ua_info_get_ua_engine, ua_engine);
+            test_get_function!(ua_info_get_ua_engine_version, ua_engine_version);
+            test_get_function!(ua_info_get_ua_engine_version_major, ua_engine_version_major);
             test_get_function!(ua_info_get_ua_family, ua_family);
             test_get_function!(ua_info_get_ua_family_code, ua_family_code);
             test_get_function!(ua_info_get_ua_family_vendor, ua_family_vendor);
             test_get_function!(ua_info_get_ua_family_vendor_code, ua_family_vendor_code);
             test_get_function!(ua_info_get_ua_string, ua_string);
+            test_get_function!(ua_info_get_ua_charset, ua_charset);
+            test_get_function!(ua_info_get_ua_normalization, ua_normalization);
+            test_get_function!(ua_info_get_parse_aborted, parse_aborted);
+            assert_eq!(ua_info_get_regex_limit_errors(&info as *const UaInfo), 0);
+            assert_eq!(ua_info_get_regex_limit_errors(std::ptr::null()), 0);
             test_get_function!(
                 ua_info_get_ua_uptodate_current_version,
                 ua_uptodate_current_version
@@ -341,4 +420,71 @@ mod tests {
             }
         }
     }
+
+    unsafe extern "C" fn callback(_info: *const UaInfo, _data: *mut c_void) -> c_int {
+        0
+    }
+
+    #[test]
+    fn test_null() {
+        use std::ptr::{null, null_mut};
+
+        let udger = Udger::new();
+        unsafe {
+            assert_eq!(udger_new(null_mut(), null(), 10), -1);
+            assert_eq!(udger_data_alloc(null(), null_mut()), -1);
+            assert_eq!(udger_data_alloc(&udger, null_mut()), -1);
+            let ua = c"curl/8.0".as_ptr();
+            assert_eq!(
+                udger_parse_ua(&udger, null_mut(), ua, callback, null_mut()),
+                -1
+            );
+            assert_eq!(
+                udger_parse_ua(&udger, null_mut(), null(), callback, null_mut()),
+                -1
+            );
+            assert_eq!(
+                udger_parse_ua_bytes(&udger, null_mut(), null(), 1, callback, null_mut()),
+                -1
+            );
+            udger_data_drop(null_mut());
+
+            let mut buf: *const c_char = c"".as_ptr();
+            let mut len: size_t = 1;
+            ua_info_get_ua(null(), &mut buf, &mut len);
+            assert!(buf.is_null());
+            assert_eq!(len, 0);
+
+            let info = UaInfo::default();
+            ua_info_get_ua(&info, null_mut(), &mut len);
+            ua_info_get_ua(&info, &mut buf, null_mut());
+            ua_info_get_ua(null(), null_mut(), null_mut());
+
+            let mut output: *mut c_char = null_mut();
+            assert_eq!(ua_info_to_string(null(), &mut output, &mut len, false), -1);
+            assert_eq!(ua_info_to_string(&info, null_mut(), &mut len, false), -1);
+            assert_eq!(ua_info_to_string(&info, &mut output, null_mut(), true), -1);
+            assert!(output.is_null());
+        }
+    }
+
+    #[test]
+    fn test_to_string() {
+        let info = UaInfo {
+            ua: String::from("Firefox 40.0"),
+            ..Default::default()
+        };
+        for pretty in [false, true] {
+            let mut output: *mut c_char = std::ptr::null_mut();
+            let mut len: size_t = 0;
+            unsafe {
+                assert_eq!(ua_info_to_string(&info, &mut output, &mut len, pretty), 0);
+                let json = CStr::from_ptr(output).to_str().unwrap();
+                assert_eq!(json.len() + 1, len);
+                let parsed: serde_json::Value = serde_json::from_str(json).unwrap();
+                assert_eq!(parsed["ua"], "Firefox 40.0");
+                libc::free(output as *mut c_void);
+            }
+        }
+    }
 }
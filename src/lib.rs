@@ -3,12 +3,22 @@ extern crate lazy_static;
 
 use serde::{Deserialize, Serialize};
 
+pub mod access_log;
 pub mod ffi;
+pub mod pcap;
+pub mod sidecar;
+pub mod stats;
+pub mod tokens;
 mod udger;
-pub use crate::udger::{Udger, UdgerData};
+#[cfg(feature = "fuzzing")]
+pub use crate::udger::fuzzing;
+pub use crate::udger::{
+    Anomaly, Candidate, Explanation, HeadersInfo, HttpHeaders, LatencySnapshot, Limits, Metrics,
+    MetricsSnapshot, Normalization, RegexTrace, Rejected, Udger, UdgerData,
+};
 
 #[repr(C)]
-#[derive(Debug, Default, Deserialize, Serialize)]
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
 pub struct UaInfo {
     #[serde(skip_serializing)]
     pub class_id: Option<u32>,
@@ -18,6 +28,8 @@ pub struct UaInfo {
     pub ua_class_code: String,
     pub ua: String,
     pub ua_engine: String,
+    pub ua_engine_version: String,
+    pub ua_engine_version_major: String,
     pub ua_version: String,
     pub ua_version_major: String,
     pub ua_version_minor: String,
@@ -30,20 +42,46 @@ pub struct UaInfo {
     pub ua_family_code: String,
     pub ua_family_vendor: String,
     pub ua_family_vendor_code: String,
+    /// the User-Agent as given, before its normalization
     pub ua_string: String,
+    /// charset `ua_string` was decoded from, see `Udger::parse_ua_bytes`
+    pub ua_charset: String,
+    /// comma-separated normalization steps which changed the User-Agent:
+    /// `plus_decode`, `url_decode`, `whitespace` and `truncate`
+    pub ua_normalization: String,
+    /// number of regex rules skipped for hitting a PCRE match or recursion limit
+    pub regex_limit_errors: u32,
+    /// detector cut or first detector not run because the time budget of the
+    /// parse ran out: `client`, `os`, `application`, `device_class` or
+    /// `device_brand`. The fields of the detectors from there on are empty
+    pub parse_aborted: String,
 
     pub os_family: String,
     pub os_family_code: String,
     pub os: String,
     pub os_code: String,
+    pub os_version: String,
+    pub os_version_major: String,
+    pub os_version_minor: String,
     pub os_family_vendor: String,
     pub os_family_vendor_code: String,
+    /// `matched` by an OS regex, `darwin` from the CFNetwork or Darwin version of
+    /// a native app, or `inferred` from the client's only OS
+    pub os_source: String,
 
     pub device_class: String,
     pub device_class_code: String,
     pub device_marketname: String,
     pub device_brand: String,
     pub device_brand_code: String,
+    /// devicename rule which matched: `os`, `os_family` for the `-all-` rules of
+    /// the OS family, or `degraded` when the OS is unknown
+    pub device_brand_rule: String,
+
+    /// CPU architecture, bitness and locale told by UA tokens like `Win64; x64` or `en-US`
+    pub cpu_architecture: String,
+    pub bitness: String,
+    pub ua_locale: String,
 
     #[cfg(feature = "application")]
     pub application_name: String,
@@ -87,3 +125,53 @@ pub struct UaInfo {
     #[cfg(feature = "url")]
     pub device_brand_info_url: String,
 }
+
+impl UaInfo {
+    /// Replace the OS and device fields with those of another parse result
+    pub fn set_device_fields(&mut self, device: &UaInfo) {
+        macro_rules! copy {
+            ($($field:ident),*) => {
+                $(self.$field.clone_from(&device.$field);)*
+            };
+        }
+
+        copy!(
+            os_family,
+            os_family_code,
+            os,
+            os_code,
+            os_version,
+            os_version_major,
+            os_version_minor,
+            os_family_vendor,
+            os_family_vendor_code,
+            os_source,
+            device_class,
+            device_class_code,
+            device_marketname,
+            device_brand,
+            device_brand_code,
+            device_brand_rule,
+            cpu_architecture,
+            bitness,
+            ua_locale
+        );
+        #[cfg(feature = "icon")]
+        copy!(
+            os_icon,
+            os_icon_big,
+            device_class_icon,
+            device_class_icon_big,
+            device_brand_icon,
+            device_brand_icon_big
+        );
+        #[cfg(feature = "homepage")]
+        copy!(
+            os_homepage,
+            os_family_vendor_homepage,
+            device_brand_homepage
+        );
+        #[cfg(feature = "url")]
+        copy!(os_info_url, device_class_info_url, device_brand_info_url);
+    }
+}
@@ -0,0 +1,1083 @@
+//! HTTP/1.x requests from packet captures
+//!
+//! Reads pcap and pcapng files, decodes Ethernet, Linux cooked, loopback and
+//! raw IP frames down to TCP, reassembles the client side of each connection
+//! and extracts the heads of the HTTP/1.x requests sent on it. The client side
+//! is the one which sent the SYN, or for connections joined after their
+//! handshake, the one which sent a request line first. Streams joined in the
+//! middle of a request, or with lost segments, are resynchronized on the next
+//! request line.
+
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// Upper bound of a captured packet or pcapng block
+const MAX_BLOCK_SIZE: usize = 16 * 1024 * 1024;
+/// Upper bound of the unparsed bytes buffered per direction
+const MAX_BUFFER_SIZE: usize = 1024 * 1024;
+/// Out-of-order segments waiting for a gap to be filled, per direction
+const MAX_PENDING_SEGMENTS: usize = 64;
+/// Upper bound of the directions tracked at once
+const MAX_STREAMS: usize = 65536;
+/// Seconds without a segment after which a direction is forgotten, once
+/// `MAX_STREAMS` is reached
+const STREAM_IDLE_TIMEOUT: f64 = 300.0;
+
+const METHODS: [&[u8]; 9] = [
+    b"GET ",
+    b"POST ",
+    b"HEAD ",
+    b"PUT ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+];
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+/// A captured frame
+#[derive(Debug, Clone)]
+pub struct Packet {
+    /// Seconds since the Unix epoch
+    pub timestamp: f64,
+    pub link_type: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Interface {
+    link_type: u32,
+    /// Timestamp units per second
+    resolution: f64,
+}
+
+enum Format {
+    Pcap {
+        big_endian: bool,
+        interface: Interface,
+    },
+    PcapNg {
+        big_endian: bool,
+        interfaces: Vec<Interface>,
+    },
+}
+
+/// Packets of a pcap or pcapng stream, the format is detected from its magic
+pub struct CaptureReader<R> {
+    reader: R,
+    format: Format,
+}
+
+fn u16_at(buf: &[u8], at: usize, big_endian: bool) -> u16 {
+    let bytes = [buf[at], buf[at + 1]];
+    if big_endian {
+        u16::from_be_bytes(bytes)
+    } else {
+        u16::from_le_bytes(bytes)
+    }
+}
+
+fn u32_at(buf: &[u8], at: usize, big_endian: bool) -> u32 {
+    let bytes = [buf[at], buf[at + 1], buf[at + 2], buf[at + 3]];
+    if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    }
+}
+
+/// Read exactly `buf.len()` bytes, false on a clean end of stream
+fn read_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) if read == 0 => return Ok(false),
+            Ok(0) => return Err(anyhow!("truncated capture")),
+            Ok(n) => read += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(true)
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut reader: R) -> Result<CaptureReader<R>> {
+        let mut magic = [0; 4];
+        if !read_or_eof(&mut reader, &mut magic)? {
+            return Err(anyhow!("empty capture"));
+        }
+
+        let format = match u32::from_le_bytes(magic) {
+            0x0A0D0D0A => {
+                let format = Format::PcapNg {
+                    big_endian: false,
+                    interfaces: Vec::new(),
+                };
+                let mut capture = CaptureReader { reader, format };
+                capture.read_section_header()?;
+                return Ok(capture);
+            }
+            magic => {
+                let (big_endian, resolution) = match magic {
+                    0xA1B2C3D4 => (false, 1e6),
+                    0xD4C3B2A1 => (true, 1e6),
+                    0xA1B23C4D => (false, 1e9),
+                    0x4D3CB2A1 => (true, 1e9),
+                    _ => return Err(anyhow!("not a pcap or pcapng capture")),
+                };
+                let mut header = [0; 20];
+                if !read_or_eof(&mut reader, &mut header)? {
+                    return Err(anyhow!("truncated capture"));
+                }
+                Format::Pcap {
+                    big_endian,
+                    interface: Interface {
+                        link_type: u32_at(&header, 16, big_endian) & 0x0FFF_FFFF,
+                        resolution,
+                    },
+                }
+            }
+        };
+        Ok(CaptureReader { reader, format })
+    }
+
+    /// Next packet, None at the end of the capture
+    pub fn next_packet(&mut self) -> Result<Option<Packet>> {
+        match self.format {
+            Format::Pcap {
+                big_endian,
+                interface,
+            } => {
+                let mut header = [0; 16];
+                if !read_or_eof(&mut self.reader, &mut header)? {
+                    return Ok(None);
+                }
+                let seconds = u32_at(&header, 0, big_endian) as f64;
+                let fraction = u32_at(&header, 4, big_endian) as f64;
+                let length = u32_at(&header, 8, big_endian) as usize;
+                if length > MAX_BLOCK_SIZE {
+                    return Err(anyhow!("packet of {} bytes", length));
+                }
+                let mut data = vec![0; length];
+                if !read_or_eof(&mut self.reader, &mut data)? {
+                    return Err(anyhow!("truncated capture"));
+                }
+                Ok(Some(Packet {
+                    timestamp: seconds + fraction / interface.resolution,
+                    link_type: interface.link_type,
+                    data,
+                }))
+            }
+            Format::PcapNg { .. } => self.next_pcapng_packet(),
+        }
+    }
+
+    /// Read the remainder of a block whose type was read, returns its body
+    fn read_block(&mut self, big_endian: bool) -> Result<Vec<u8>> {
+        let mut length = [0; 4];
+        if !read_or_eof(&mut self.reader, &mut length)? {
+            return Err(anyhow!("truncated capture"));
+        }
+        let length = u32_at(&length, 0, big_endian) as usize;
+        if !(12..=MAX_BLOCK_SIZE).contains(&length) || length & 3 != 0 {
+            return Err(anyhow!("invalid pcapng block length {}", length));
+        }
+        // body and trailing length
+        let mut body = vec![0; length - 8];
+        if !read_or_eof(&mut self.reader, &mut body)? {
+            return Err(anyhow!("truncated capture"));
+        }
+        body.truncate(length - 12);
+        Ok(body)
+    }
+
+    /// Read a section header block, after its type
+    fn read_section_header(&mut self) -> Result<()> {
+        let mut length = [0; 4];
+        let mut magic = [0; 4];
+        if !read_or_eof(&mut self.reader, &mut length)?
+            || !read_or_eof(&mut self.reader, &mut magic)?
+        {
+            return Err(anyhow!("truncated capture"));
+        }
+        let big_endian = match u32::from_le_bytes(magic) {
+            0x1A2B3C4D => false,
+            0x4D3C2B1A => true,
+            _ => return Err(anyhow!("invalid pcapng byte-order magic")),
+        };
+        let length = u32_at(&length, 0, big_endian) as usize;
+        if !(28..=MAX_BLOCK_SIZE).contains(&length) {
+            return Err(anyhow!("invalid pcapng section header length {}", length));
+        }
+        let mut rest = vec![0; length - 12];
+        if !read_or_eof(&mut self.reader, &mut rest)? {
+            return Err(anyhow!("truncated capture"));
+        }
+        self.format = Format::PcapNg {
+            big_endian,
+            interfaces: Vec::new(),
+        };
+        Ok(())
+    }
+
+    fn next_pcapng_packet(&mut self) -> Result<Option<Packet>> {
+        loop {
+            let big_endian = match self.format {
+                Format::PcapNg { big_endian, .. } => big_endian,
+                Format::Pcap { .. } => unreachable!(),
+            };
+            let mut block_type = [0; 4];
+            if !read_or_eof(&mut self.reader, &mut block_type)? {
+                return Ok(None);
+            }
+            let block_type = u32_at(&block_type, 0, big_endian);
+            if block_type == 0x0A0D0D0A {
+                self.read_section_header()?;
+                continue;
+            }
+
+            let body = self.read_block(big_endian)?;
+            let interfaces = match &mut self.format {
+                Format::PcapNg { interfaces, .. } => interfaces,
+                Format::Pcap { .. } => unreachable!(),
+            };
+            let (interface, timestamp, data) = match block_type {
+                // interface description
+                1 if body.len() >= 8 => {
+                    interfaces.push(Interface {
+                        link_type: u16_at(&body, 0, big_endian) as u32,
+                        resolution: ts_resolution(&body[8..], big_endian),
+                    });
+                    continue;
+                }
+                // enhanced packet
+                6 if body.len() >= 20 => {
+                    let captured = u32_at(&body, 12, big_endian) as usize;
+                    let timestamp = (u32_at(&body, 4, big_endian) as u64) << 32
+                        | u32_at(&body, 8, big_endian) as u64;
+                    let data = body.get(20..20 + captured);
+                    (u32_at(&body, 0, big_endian) as usize, timestamp, data)
+                }
+                // simple packet, no timestamp
+                3 if body.len() >= 4 => {
+                    let original = u32_at(&body, 0, big_endian) as usize;
+                    let data = &body[4..];
+                    (0, 0, Some(&data[..original.min(data.len())]))
+                }
+                // obsolete packet
+                2 if body.len() >= 20 => {
+                    let captured = u32_at(&body, 12, big_endian) as usize;
+                    let timestamp = (u32_at(&body, 4, big_endian) as u64) << 32
+                        | u32_at(&body, 8, big_endian) as u64;
+                    let data = body.get(20..20 + captured);
+                    (u16_at(&body, 0, big_endian) as usize, timestamp, data)
+                }
+                _ => continue,
+            };
+
+            let interface = interfaces
+                .get(interface)
+                .ok_or_else(|| anyhow!("packet on undeclared interface {}", interface))?;
+            let data = data.ok_or_else(|| anyhow!("invalid pcapng packet length"))?;
+            return Ok(Some(Packet {
+                timestamp: timestamp as f64 / interface.resolution,
+                link_type: interface.link_type,
+                data: data.to_vec(),
+            }));
+        }
+    }
+}
+
+/// Timestamp units per second from the if_tsresol option, microseconds by default
+fn ts_resolution(mut options: &[u8], big_endian: bool) -> f64 {
+    while options.len() >= 4 {
+        let code = u16_at(options, 0, big_endian);
+        let length = u16_at(options, 2, big_endian) as usize;
+        let value = match options.get(4..4 + length) {
+            None => break,
+            Some(value) => value,
+        };
+        match (code, value) {
+            (0, _) => break,
+            (9, [resolution]) if resolution & 0x80 == 0 => return 10f64.powi(*resolution as i32),
+            (9, [resolution]) => return 2f64.powi((resolution & 0x7F) as i32),
+            _ => {}
+        }
+        options = options
+            .get((4 + length).div_ceil(4) * 4..)
+            .unwrap_or_default();
+    }
+    1e6
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<Packet>;
+
+    fn next(&mut self) -> Option<Result<Packet>> {
+        self.next_packet().transpose()
+    }
+}
+
+/// A TCP segment of a captured packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment<'a> {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub seq: u32,
+    pub syn: bool,
+    pub ack: bool,
+    /// FIN or RST
+    pub fin: bool,
+    pub payload: &'a [u8],
+}
+
+/// Decode a frame down to its TCP segment, None for anything else
+pub fn decode_tcp(link_type: u32, frame: &[u8]) -> Option<Segment<'_>> {
+    let (ethertype, packet) = match link_type {
+        LINKTYPE_ETHERNET => {
+            let mut ethertype = u16::from_be_bytes([*frame.get(12)?, *frame.get(13)?]);
+            let mut offset = 14;
+            // 802.1Q and 802.1ad tags
+            while ethertype == 0x8100 || ethertype == 0x88A8 {
+                ethertype = u16::from_be_bytes([*frame.get(offset + 2)?, *frame.get(offset + 3)?]);
+                offset += 4;
+            }
+            (Some(ethertype), frame.get(offset..)?)
+        }
+        LINKTYPE_LINUX_SLL => (
+            Some(u16::from_be_bytes([*frame.get(14)?, *frame.get(15)?])),
+            frame.get(16..)?,
+        ),
+        LINKTYPE_LINUX_SLL2 => (
+            Some(u16::from_be_bytes([*frame.first()?, *frame.get(1)?])),
+            frame.get(20..)?,
+        ),
+        // address family in host byte order, the IP version tells anyway
+        LINKTYPE_NULL => (None, frame.get(4..)?),
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => (None, frame),
+        _ => return None,
+    };
+
+    let version = packet.first()? >> 4;
+    let (src, dst, tcp) = match (ethertype, version) {
+        (Some(0x0800) | None, 4) => decode_ipv4(packet)?,
+        (Some(0x86DD) | None, 6) => decode_ipv6(packet)?,
+        _ => return None,
+    };
+
+    let offset = (*tcp.get(12)? >> 4) as usize * 4;
+    let flags = *tcp.get(13)?;
+    Some(Segment {
+        src: SocketAddr::new(src, u16::from_be_bytes([tcp[0], tcp[1]])),
+        dst: SocketAddr::new(dst, u16::from_be_bytes([tcp[2], tcp[3]])),
+        seq: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+        syn: flags & 0x02 != 0,
+        ack: flags & 0x10 != 0,
+        fin: flags & 0x05 != 0,
+        payload: tcp.get(offset.max(20)..)?,
+    })
+}
+
+fn decode_ipv4(packet: &[u8]) -> Option<(IpAddr, IpAddr, &[u8])> {
+    let header = (packet[0] & 0x0F) as usize * 4;
+    let total = u16::from_be_bytes([*packet.get(2)?, *packet.get(3)?]) as usize;
+    // fragments are not reassembled
+    let fragment = u16::from_be_bytes([*packet.get(6)?, *packet.get(7)?]) & 0x3FFF;
+    if *packet.get(9)? != 6 || fragment != 0 || header < 20 {
+        return None;
+    }
+    let src: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+    let dst: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+    // the captured length may include link layer padding
+    let tcp = packet.get(header..total.min(packet.len()))?;
+    Some((Ipv4Addr::from(src).into(), Ipv4Addr::from(dst).into(), tcp))
+}
+
+fn decode_ipv6(packet: &[u8]) -> Option<(IpAddr, IpAddr, &[u8])> {
+    let length = u16::from_be_bytes([*packet.get(4)?, *packet.get(5)?]) as usize;
+    let src: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+    let dst: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+    let mut next = *packet.get(6)?;
+    let mut offset = 40;
+    let end = (40 + length).min(packet.len());
+    loop {
+        match next {
+            6 => break,
+            // hop-by-hop, routing and destination options
+            0 | 43 | 60 => {
+                next = *packet.get(offset)?;
+                offset += (*packet.get(offset + 1)? as usize + 1) * 8;
+            }
+            _ => return None,
+        }
+    }
+    let tcp = packet.get(offset..end)?;
+    Some((Ipv6Addr::from(src).into(), Ipv6Addr::from(dst).into(), tcp))
+}
+
+/// Head of a captured HTTP request
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct HttpRequest {
+    /// Capture time of the segment completing the head
+    pub timestamp: f64,
+    pub client: Option<SocketAddr>,
+    pub server: Option<SocketAddr>,
+    pub method: String,
+    pub target: String,
+    pub host: Option<String>,
+    pub user_agent: Option<String>,
+    pub forwarded_for: Option<String>,
+}
+
+impl HttpRequest {
+    /// First X-Forwarded-For address, or else the TCP client
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        let forwarded = self
+            .forwarded_for
+            .as_deref()
+            .and_then(|xff| xff.split(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+        forwarded.or_else(|| self.client.map(|addr| addr.ip()))
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Offset of the first request line at a line start
+fn find_request_line(buf: &[u8]) -> Option<usize> {
+    (0..buf.len()).find(|&i| {
+        (i == 0 || buf[i - 1] == b'\n') && METHODS.iter().any(|method| buf[i..].starts_with(method))
+    })
+}
+
+/// Parse a request head, without its terminating empty line
+fn parse_head(head: &[u8]) -> Option<(HttpRequest, usize)> {
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.split('\n').map(|line| line.trim_end_matches('\r'));
+
+    let mut parts = lines.next()?.split(' ');
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+            (method, target)
+        }
+        _ => return None,
+    };
+    let mut request = HttpRequest {
+        method: method.to_string(),
+        target: target.to_string(),
+        ..Default::default()
+    };
+
+    // unfold obsolete line folding first
+    let mut headers: Vec<String> = Vec::new();
+    for line in lines {
+        match headers.last_mut() {
+            Some(last) if line.starts_with(' ') || line.starts_with('\t') => {
+                last.push(' ');
+                last.push_str(line.trim());
+            }
+            _ => headers.push(line.to_string()),
+        }
+    }
+
+    let mut body = 0;
+    for header in &headers {
+        let (name, value) = match header.split_once(':') {
+            None => continue,
+            Some((name, value)) => (name.trim(), value.trim().to_string()),
+        };
+        if name.eq_ignore_ascii_case("user-agent") {
+            request.user_agent.get_or_insert(value);
+        } else if name.eq_ignore_ascii_case("host") {
+            request.host.get_or_insert(value);
+        } else if name.eq_ignore_ascii_case("x-forwarded-for") {
+            // repeated headers are one comma separated list
+            match &mut request.forwarded_for {
+                Some(xff) => {
+                    xff.push_str(", ");
+                    xff.push_str(&value);
+                }
+                None => request.forwarded_for = Some(value),
+            }
+        } else if name.eq_ignore_ascii_case("content-length") {
+            body = value.parse().unwrap_or(0);
+        }
+    }
+    Some((request, body))
+}
+
+#[derive(Debug, Default)]
+struct Stream {
+    next_seq: Option<u32>,
+    pending: Vec<(u32, Vec<u8>)>,
+    buffer: Vec<u8>,
+    /// Body bytes of the last request still to be skipped
+    body_left: usize,
+    /// Bytes of the request head at the start of `buffer` searched for its end
+    scanned: usize,
+    /// The server side of a connection, whose payload is ignored
+    server: bool,
+    /// Timestamp of the last segment
+    last_seen: f64,
+}
+
+impl Stream {
+    /// Append in-order data, skipping what was already received
+    fn append(&mut self, seq: u32, payload: &[u8]) -> bool {
+        let next = *self.next_seq.get_or_insert(seq);
+        let offset = seq.wrapping_sub(next) as i32;
+        if offset > 0 {
+            return false;
+        }
+        let skip = offset.unsigned_abs() as usize;
+        if skip < payload.len() {
+            self.buffer.extend_from_slice(&payload[skip..]);
+            self.next_seq = Some(next.wrapping_add((payload.len() - skip) as u32));
+        }
+        true
+    }
+
+    fn push(&mut self, seq: u32, payload: &[u8]) {
+        if !self.append(seq, payload) {
+            self.pending.push((seq, payload.to_vec()));
+            if self.pending.len() > MAX_PENDING_SEGMENTS {
+                // the gap won't be filled, continue after it
+                let next = self.next_seq.unwrap_or_default();
+                let i = self
+                    .pending
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, (seq, _))| seq.wrapping_sub(next))
+                    .map_or(0, |(i, _)| i);
+                let (seq, payload) = self.pending.swap_remove(i);
+                self.buffer.clear();
+                self.body_left = 0;
+                self.next_seq = Some(seq);
+                self.append(seq, &payload);
+            }
+        }
+        // drain the segments the new data made contiguous
+        while let Some(i) = self
+            .pending
+            .iter()
+            .position(|(seq, _)| seq.wrapping_sub(self.next_seq.unwrap_or_default()) as i32 <= 0)
+        {
+            let (seq, payload) = self.pending.swap_remove(i);
+            self.append(seq, &payload);
+        }
+    }
+
+    /// Drop the first `len` bytes of the buffer
+    fn consume(&mut self, len: usize) {
+        if len > 0 {
+            self.buffer.drain(..len);
+            self.scanned = 0;
+        }
+    }
+
+    /// Take the complete request heads out of the buffer
+    fn extract(&mut self, requests: &mut Vec<HttpRequest>) {
+        loop {
+            let skip = self.body_left.min(self.buffer.len());
+            self.consume(skip);
+            self.body_left -= skip;
+            if self.body_left > 0 {
+                return;
+            }
+
+            match find_request_line(&self.buffer) {
+                Some(start) => self.consume(start),
+                None => {
+                    // keep the last line only if it may be the start of a request line
+                    let last = self
+                        .buffer
+                        .iter()
+                        .rposition(|b| *b == b'\n')
+                        .map_or(0, |i| i + 1);
+                    let tail = &self.buffer[last..];
+                    let keep = match METHODS.iter().any(|method| method.starts_with(tail)) {
+                        true => last,
+                        false => self.buffer.len(),
+                    };
+                    self.consume(keep);
+                    return;
+                }
+            }
+
+            // the bytes already searched can't hold the end of the head, but
+            // their last 3 may start it
+            let from = self.scanned.saturating_sub(3);
+            let rest = &self.buffer[from..];
+            let (end, head_len) = match (find(rest, b"\r\n\r\n"), find(rest, b"\n\n")) {
+                (Some(crlf), Some(lf)) if lf + 1 < crlf => (from + lf + 2, from + lf),
+                (Some(crlf), _) => (from + crlf + 4, from + crlf),
+                (None, Some(lf)) => (from + lf + 2, from + lf),
+                (None, None) => {
+                    self.scanned = self.buffer.len();
+                    if self.buffer.len() > MAX_BUFFER_SIZE {
+                        self.consume(self.buffer.len());
+                    }
+                    return;
+                }
+            };
+            match parse_head(&self.buffer[..head_len]) {
+                Some((request, body)) => {
+                    requests.push(request);
+                    self.body_left = body;
+                    self.consume(end);
+                }
+                // not a request after all, look for the next one
+                None => self.consume(1),
+            }
+        }
+    }
+}
+
+/// Reassembles TCP streams and extracts the HTTP requests sent on them
+#[derive(Debug, Default)]
+pub struct HttpExtractor {
+    streams: HashMap<(SocketAddr, SocketAddr), Stream>,
+}
+
+impl HttpExtractor {
+    pub fn new() -> HttpExtractor {
+        HttpExtractor::default()
+    }
+
+    /// Feed a segment, returns the requests it completed
+    pub fn push(&mut self, timestamp: f64, segment: &Segment) -> Vec<HttpRequest> {
+        let key = (segment.src, segment.dst);
+        let reverse = (segment.dst, segment.src);
+        let mut requests = Vec::new();
+        if !self.streams.contains_key(&key) {
+            self.evict(timestamp);
+        }
+
+        if segment.syn {
+            // the SYN opens the client side, the SYN-ACK the server side
+            self.streams.insert(
+                key,
+                Stream {
+                    next_seq: Some(segment.seq.wrapping_add(1)),
+                    server: segment.ack,
+                    last_seen: timestamp,
+                    ..Default::default()
+                },
+            );
+            if !segment.ack {
+                self.set_server(reverse, timestamp);
+            }
+        } else if !segment.payload.is_empty() {
+            let stream = self.streams.entry(key).or_default();
+            stream.last_seen = timestamp;
+            // a connection joined after its handshake, on a response
+            if stream.next_seq.is_none() && segment.payload.starts_with(b"HTTP/") {
+                stream.server = true;
+            }
+            if !stream.server {
+                stream.push(segment.seq, segment.payload);
+                stream.extract(&mut requests);
+                if !requests.is_empty() {
+                    self.set_server(reverse, timestamp);
+                }
+            }
+        }
+        if segment.fin {
+            self.streams.remove(&key);
+        }
+
+        for request in &mut requests {
+            request.timestamp = timestamp;
+            request.client = Some(segment.src);
+            request.server = Some(segment.dst);
+        }
+        requests
+    }
+
+    /// Ignore the payload of the direction `key` from now on
+    fn set_server(&mut self, key: (SocketAddr, SocketAddr), timestamp: f64) {
+        let stream = self.streams.entry(key).or_default();
+        if !stream.server {
+            *stream = Stream {
+                server: true,
+                last_seen: timestamp,
+                ..Default::default()
+            };
+        }
+    }
+
+    /// Make room for a new direction: once `MAX_STREAMS` is reached, forget
+    /// the idle directions, then if still more than half full, the least
+    /// recently seen half
+    fn evict(&mut self, now: f64) {
+        if self.streams.len() < MAX_STREAMS {
+            return;
+        }
+        self.streams
+            .retain(|_, stream| now - stream.last_seen < STREAM_IDLE_TIMEOUT);
+        if self.streams.len() >= MAX_STREAMS / 2 {
+            let mut seen: Vec<f64> = self.streams.values().map(|s| s.last_seen).collect();
+            let middle = seen.len() / 2;
+            let (_, median, _) = seen.select_nth_unstable_by(middle, f64::total_cmp);
+            let median = *median;
+            self.streams.retain(|_, stream| stream.last_seen > median);
+        }
+    }
+
+    /// Feed a captured packet, returns the requests it completed
+    pub fn push_packet(&mut self, packet: &Packet) -> Vec<HttpRequest> {
+        match decode_tcp(packet.link_type, &packet.data) {
+            None => Vec::new(),
+            Some(segment) => self.push(packet.timestamp, &segment),
+        }
+    }
+}
+
+/// All the HTTP requests of a capture, in capture order
+pub fn http_requests<R: Read>(reader: R) -> Result<Vec<HttpRequest>> {
+    let mut extractor = HttpExtractor::new();
+    let mut requests = Vec::new();
+    for packet in CaptureReader::new(reader)? {
+        requests.extend(extractor.push_packet(&packet?));
+    }
+    Ok(requests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: [u8; 4] = [192, 0, 2, 1];
+    const SERVER: [u8; 4] = [198, 51, 100, 2];
+
+    /// Ethernet frame of an IPv4 TCP segment
+    fn frame(
+        src: [u8; 4],
+        dst: [u8; 4],
+        ports: (u16, u16),
+        seq: u32,
+        flags: u8,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&[0x08, 0x00]);
+        let total = (20 + 20 + payload.len()) as u16;
+        frame.extend_from_slice(&[
+            0x45,
+            0,
+            (total >> 8) as u8,
+            total as u8,
+            0,
+            0,
+            0x40,
+            0,
+            64,
+            6,
+            0,
+            0,
+        ]);
+        frame.extend_from_slice(&src);
+        frame.extend_from_slice(&dst);
+        frame.extend_from_slice(&ports.0.to_be_bytes());
+        frame.extend_from_slice(&ports.1.to_be_bytes());
+        frame.extend_from_slice(&seq.to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0, 0, 0x50, flags, 0xFF, 0xFF, 0, 0, 0, 0]);
+        frame.extend_from_slice(payload);
+        // link layer padding
+        frame.extend_from_slice(&[0; 4]);
+        frame
+    }
+
+    fn pcap(frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for word in [0xA1B2C3D4u32, 0x0004_0002, 0, 0, 65535, 1] {
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+        for (i, frame) in frames.iter().enumerate() {
+            for word in [
+                1_700_000_000,
+                i as u32 * 1000,
+                frame.len() as u32,
+                frame.len() as u32,
+            ] {
+                out.extend_from_slice(&word.to_le_bytes());
+            }
+            out.extend_from_slice(frame);
+        }
+        out
+    }
+
+    fn pcapng(frames: &[Vec<u8>]) -> Vec<u8> {
+        fn block(out: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+            let padded = body.len().div_ceil(4) * 4;
+            let length = (padded + 12) as u32;
+            out.extend_from_slice(&block_type.to_be_bytes());
+            out.extend_from_slice(&length.to_be_bytes());
+            out.extend_from_slice(body);
+            out.resize(out.len() + padded - body.len(), 0);
+            out.extend_from_slice(&length.to_be_bytes());
+        }
+
+        let mut out = Vec::new();
+        block(
+            &mut out,
+            0x0A0D0D0A,
+            &[
+                0x1A, 0x2B, 0x3C, 0x4D, 0, 1, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+            ],
+        );
+        // nanosecond resolution
+        block(
+            &mut out,
+            1,
+            &[
+                0, 1, 0, 0, 0, 0, 0xFF, 0xFF, 0, 9, 0, 1, 9, 0, 0, 0, 0, 0, 0, 0,
+            ],
+        );
+        // a block to skip
+        block(&mut out, 0x0BAD, b"skip");
+        for frame in frames {
+            let mut body = Vec::new();
+            let ts: u64 = 1_700_000_000_500_000_000;
+            for word in [
+                0,
+                (ts >> 32) as u32,
+                ts as u32,
+                frame.len() as u32,
+                frame.len() as u32,
+            ] {
+                body.extend_from_slice(&word.to_be_bytes());
+            }
+            body.extend_from_slice(frame);
+            block(&mut out, 6, &body);
+        }
+        out
+    }
+
+    fn session() -> Vec<Vec<u8>> {
+        let c = (40000, 80);
+        let s = (80, 40000);
+        vec![
+            frame(CLIENT, SERVER, c, 999, 0x02, b""),
+            frame(SERVER, CLIENT, s, 4999, 0x12, b""),
+            frame(
+                CLIENT,
+                SERVER,
+                c,
+                1000,
+                0x18,
+                b"POST /a HTTP/1.1\r\nHost: example.com\r\nUser-",
+            ),
+            // retransmission overlapping the previous segment
+            frame(
+                CLIENT,
+                SERVER,
+                c,
+                1024,
+                0x18,
+                b"example.com\r\nUser-Agent: curl/8.0\r\n",
+            ),
+            frame(
+                CLIENT,
+                SERVER,
+                c,
+                1059,
+                0x18,
+                b"X-Forwarded-For: 203.0.113.9\r\nContent-Length: 16\r\n\r\nGET / HTTP/1.1\r\n",
+            ),
+            frame(
+                SERVER,
+                CLIENT,
+                s,
+                5000,
+                0x18,
+                b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n",
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_pcap_http_requests() {
+        let requests = http_requests(&pcap(&session())[..]).unwrap();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.target, "/a");
+        assert_eq!(request.host.as_deref(), Some("example.com"));
+        assert_eq!(request.user_agent.as_deref(), Some("curl/8.0"));
+        assert_eq!(request.client, Some("192.0.2.1:40000".parse().unwrap()));
+        assert_eq!(request.server, Some("198.51.100.2:80".parse().unwrap()));
+        assert_eq!(request.client_ip(), Some("203.0.113.9".parse().unwrap()));
+        assert!((request.timestamp - 1_700_000_000.004).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_pcapng_http_requests() {
+        let requests = http_requests(&pcapng(&session())[..]).unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].user_agent.as_deref(), Some("curl/8.0"));
+        assert!((requests[0].timestamp - 1_700_000_000.5).abs() < 1e-6);
+
+        assert!(CaptureReader::new(&b"not a capture"[..]).is_err());
+        let truncated = pcapng(&session());
+        assert!(http_requests(&truncated[..truncated.len() - 3]).is_err());
+    }
+
+    #[test]
+    fn test_out_of_order_and_resync() {
+        let c = (40001, 8080);
+        let mut extractor = HttpExtractor::new();
+        let mut push = |seq, payload: &[u8]| {
+            let frame = frame(CLIENT, SERVER, c, seq, 0x18, payload);
+            extractor.push(0.0, &decode_tcp(LINKTYPE_ETHERNET, &frame).unwrap())
+        };
+
+        // joined in the middle of a body
+        assert!(push(
+            100,
+            b"tail of a body\r\nGET /x HTTP/1.1\r\nUser-Agent: a\r\n"
+        )
+        .is_empty());
+        // a later segment, before the gap is filled
+        assert!(push(158, b"\nGET /y HTTP/1.1\n").is_empty());
+        let requests = push(148, b"Host: h\r\n\r");
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].target, "/x");
+        assert_eq!(requests[0].user_agent.as_deref(), Some("a"));
+
+        let requests = push(175, b"user-agent: b\n\tfolded\nUser-Agent: c\n\n");
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].target, "/y");
+        assert_eq!(requests[0].user_agent.as_deref(), Some("b folded"));
+    }
+
+    #[test]
+    fn test_server_side() {
+        let c = (40002, 80);
+        let s = (80, 40002);
+        let mut extractor = HttpExtractor::new();
+        let mut push = |src, dst, ports, seq, flags, payload: &[u8]| {
+            let frame = frame(src, dst, ports, seq, flags, payload);
+            extractor.push(0.0, &decode_tcp(LINKTYPE_ETHERNET, &frame).unwrap())
+        };
+        let fake = b"GET /fake HTTP/1.1\r\nUser-Agent: z\r\n\r\n";
+
+        // the side which sent the SYN is the client
+        push(CLIENT, SERVER, c, 0, 0x02, b"");
+        push(SERVER, CLIENT, s, 0, 0x12, b"");
+        assert!(push(SERVER, CLIENT, s, 1, 0x18, fake).is_empty());
+        assert_eq!(push(CLIENT, SERVER, c, 1, 0x18, fake).len(), 1);
+
+        // joined after the handshake, on a response
+        let c = (40003, 80);
+        let s = (80, 40003);
+        assert!(push(SERVER, CLIENT, s, 0, 0x18, b"HTTP/1.1 200 OK\r\n\r\n").is_empty());
+        assert!(push(SERVER, CLIENT, s, 19, 0x18, fake).is_empty());
+        assert_eq!(push(CLIENT, SERVER, c, 0, 0x18, fake).len(), 1);
+
+        // joined after the handshake, the first request line tells the client
+        let c = (40004, 80);
+        let s = (80, 40004);
+        assert!(push(SERVER, CLIENT, s, 0, 0x18, b"<html>").is_empty());
+        assert_eq!(push(CLIENT, SERVER, c, 0, 0x18, fake).len(), 1);
+        assert!(push(SERVER, CLIENT, s, 6, 0x18, fake).is_empty());
+    }
+
+    #[test]
+    fn test_incremental_head() {
+        let mut extractor = HttpExtractor::new();
+        let key = (
+            SocketAddr::from((CLIENT, 40005)),
+            SocketAddr::from((SERVER, 80)),
+        );
+        let head = b"GET /b HTTP/1.1\r\nUser-Agent: d\r\n\r\n";
+        for (i, byte) in head.iter().enumerate() {
+            let segment = Segment {
+                src: key.0,
+                dst: key.1,
+                seq: i as u32,
+                syn: false,
+                ack: true,
+                fin: false,
+                payload: std::slice::from_ref(byte),
+            };
+            let requests = extractor.push(0.0, &segment);
+            if i + 1 < head.len() {
+                assert!(requests.is_empty());
+                // searched for the end of the head once "GET " is complete
+                let scanned = if i < 3 { 0 } else { i + 1 };
+                assert_eq!(extractor.streams[&key].scanned, scanned);
+            } else {
+                assert_eq!(requests[0].user_agent.as_deref(), Some("d"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_evict_streams() {
+        let mut extractor = HttpExtractor::new();
+        let server = SocketAddr::from((SERVER, 80));
+        for i in 0..=MAX_STREAMS as u32 {
+            let segment = Segment {
+                src: SocketAddr::from((Ipv4Addr::from(i), 1024)),
+                dst: server,
+                seq: 0,
+                syn: false,
+                ack: true,
+                fin: false,
+                payload: b"x",
+            };
+            extractor.push(i as f64, &segment);
+        }
+        assert!(extractor.streams.len() <= MAX_STREAMS / 2);
+        let last = SocketAddr::from((Ipv4Addr::from(MAX_STREAMS as u32), 1024));
+        assert!(extractor.streams.contains_key(&(last, server)));
+    }
+
+    #[test]
+    fn test_decode_link_types() {
+        let ethernet = frame(CLIENT, SERVER, (1, 2), 7, 0x01, b"x");
+        let ip = &ethernet[14..];
+
+        let mut vlan = ethernet[..12].to_vec();
+        vlan.extend_from_slice(&[0x81, 0x00, 0, 5]);
+        vlan.extend_from_slice(&ethernet[12..]);
+        let mut sll = vec![0; 14];
+        sll.extend_from_slice(&[0x08, 0x00]);
+        sll.extend_from_slice(ip);
+        let mut null = vec![2, 0, 0, 0];
+        null.extend_from_slice(ip);
+
+        for (link_type, frame) in [
+            (LINKTYPE_ETHERNET, &ethernet[..]),
+            (LINKTYPE_ETHERNET, &vlan[..]),
+            (LINKTYPE_LINUX_SLL, &sll[..]),
+            (LINKTYPE_NULL, &null[..]),
+            (LINKTYPE_RAW, ip),
+        ] {
+            let segment = decode_tcp(link_type, frame).unwrap();
+            assert_eq!(segment.seq, 7);
+            assert_eq!(segment.payload, b"x");
+            assert!(segment.fin);
+        }
+        assert!(decode_tcp(LINKTYPE_ETHERNET, &ethernet[..30]).is_none());
+        assert!(decode_tcp(147, &ethernet).is_none());
+    }
+}
@@ -0,0 +1,483 @@
+//! Sidecar protocol, to parse User-Agents in a separate process on the same host
+//!
+//! Every frame is a big-endian `u32` length followed by the payload.
+//! A request payload is the UTF-8 User-Agent. A response payload starts with
+//! a status byte:
+//! - `0`: a big-endian `u16` field count, then every `UaInfo` string field in
+//!   declaration order, each as a big-endian `u16` length and UTF-8 bytes, then
+//!   `regex_limit_errors` as a big-endian `u32`
+//! - `1`: the error message, as a `u16` length and UTF-8 bytes
+//!
+//! Responses are sent in request order, so clients may pipeline requests.
+//! Client and server must be built with the same `UaInfo` features, which the
+//! field count verifies.
+
+use std::io::{self, Read, Write};
+
+use anyhow::{anyhow, Result};
+
+use crate::UaInfo;
+
+/// Upper bound of a frame payload
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Status byte of a response carrying a `UaInfo`
+pub const STATUS_OK: u8 = 0;
+/// Status byte of a response carrying an error message
+pub const STATUS_ERROR: u8 = 1;
+
+macro_rules! string_fields {
+    ($info:ident, $($borrow:tt)+) => {{
+        let mut fields = Vec::with_capacity(64);
+        fields.push($($borrow)+ $info.ua_class);
+        fields.push($($borrow)+ $info.ua_class_code);
+        fields.push($($borrow)+ $info.ua);
+        fields.push($($borrow)+ $info.ua_engine);
+        fields.push($($borrow)+ $info.ua_engine_version);
+        fields.push($($borrow)+ $info.ua_engine_version_major);
+        fields.push($($borrow)+ $info.ua_version);
+        fields.push($($borrow)+ $info.ua_version_major);
+        fields.push($($borrow)+ $info.ua_version_minor);
+        fields.push($($borrow)+ $info.crawler_last_seen);
+        fields.push($($borrow)+ $info.crawler_respect_robotstxt);
+        fields.push($($borrow)+ $info.crawler_category);
+        fields.push($($borrow)+ $info.crawler_category_code);
+        fields.push($($borrow)+ $info.ua_uptodate_current_version);
+        fields.push($($borrow)+ $info.ua_family);
+        fields.push($($borrow)+ $info.ua_family_code);
+        fields.push($($borrow)+ $info.ua_family_vendor);
+        fields.push($($borrow)+ $info.ua_family_vendor_code);
+        fields.push($($borrow)+ $info.ua_string);
+        fields.push($($borrow)+ $info.ua_charset);
+        fields.push($($borrow)+ $info.ua_normalization);
+        fields.push($($borrow)+ $info.parse_aborted);
+        fields.push($($borrow)+ $info.os_family);
+        fields.push($($borrow)+ $info.os_family_code);
+        fields.push($($borrow)+ $info.os);
+        fields.push($($borrow)+ $info.os_code);
+        fields.push($($borrow)+ $info.os_version);
+        fields.push($($borrow)+ $info.os_version_major);
+        fields.push($($borrow)+ $info.os_version_minor);
+        fields.push($($borrow)+ $info.os_family_vendor);
+        fields.push($($borrow)+ $info.os_family_vendor_code);
+        fields.push($($borrow)+ $info.os_source);
+        fields.push($($borrow)+ $info.device_class);
+        fields.push($($borrow)+ $info.device_class_code);
+        fields.push($($borrow)+ $info.device_marketname);
+        fields.push($($borrow)+ $info.device_brand);
+        fields.push($($borrow)+ $info.device_brand_code);
+        fields.push($($borrow)+ $info.device_brand_rule);
+        fields.push($($borrow)+ $info.cpu_architecture);
+        fields.push($($borrow)+ $info.bitness);
+        fields.push($($borrow)+ $info.ua_locale);
+        #[cfg(feature = "application")]
+        {
+            fields.push($($borrow)+ $info.application_name);
+            fields.push($($borrow)+ $info.application_version);
+        }
+        #[cfg(feature = "icon")]
+        {
+            fields.push($($borrow)+ $info.ua_family_icon);
+            fields.push($($borrow)+ $info.ua_family_icon_big);
+            fields.push($($borrow)+ $info.os_icon);
+            fields.push($($borrow)+ $info.os_icon_big);
+            fields.push($($borrow)+ $info.device_class_icon);
+            fields.push($($borrow)+ $info.device_class_icon_big);
+            fields.push($($borrow)+ $info.device_brand_icon);
+            fields.push($($borrow)+ $info.device_brand_icon_big);
+        }
+        #[cfg(feature = "homepage")]
+        {
+            fields.push($($borrow)+ $info.ua_family_homepage);
+            fields.push($($borrow)+ $info.ua_family_vendor_homepage);
+            fields.push($($borrow)+ $info.os_homepage);
+            fields.push($($borrow)+ $info.os_family_vendor_homepage);
+            fields.push($($borrow)+ $info.device_brand_homepage);
+        }
+        #[cfg(feature = "url")]
+        {
+            fields.push($($borrow)+ $info.ua_family_info_url);
+            fields.push($($borrow)+ $info.os_info_url);
+            fields.push($($borrow)+ $info.device_class_info_url);
+            fields.push($($borrow)+ $info.device_brand_info_url);
+        }
+        fields
+    }};
+}
+
+fn fields(info: &UaInfo) -> Vec<&String> {
+    string_fields!(info, &)
+}
+
+fn fields_mut(info: &mut UaInfo) -> Vec<&mut String> {
+    string_fields!(info, &mut)
+}
+
+/// Read a frame payload, returns None on a clean end of stream
+pub fn read_frame<R>(reader: &mut R) -> Result<Option<Vec<u8>>>
+where
+    R: Read,
+{
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(anyhow!(err)),
+        Ok(_) => {}
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(anyhow!("frame of {} bytes exceeds the size limit", len));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 4);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Status byte of an encoded response frame, see `encode_response`
+pub fn frame_status(frame: &[u8]) -> Option<u8> {
+    frame.get(4).copied()
+}
+
+/// Write a request frame
+pub fn write_request<W>(writer: &mut W, ua: &str) -> Result<()>
+where
+    W: Write,
+{
+    if ua.len() > MAX_FRAME_SIZE {
+        return Err(anyhow!("User-Agent of {} bytes is too long", ua.len()));
+    }
+    writer.write_all(&frame(ua.as_bytes()))?;
+    Ok(())
+}
+
+/// Read a request frame, returns the User-Agent or None on a clean end of stream
+pub fn read_request<R>(reader: &mut R) -> Result<Option<String>>
+where
+    R: Read,
+{
+    Ok(read_frame(reader)?.map(|ua| match String::from_utf8(ua) {
+        Ok(ua) => ua,
+        Err(err) => String::from_utf8_lossy(err.as_bytes()).into_owned(),
+    }))
+}
+
+/// Append a `u16` length prefixed string, truncated on a char boundary if needed
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    let mut end = s.len().min(u16::MAX as usize);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    buf.extend_from_slice(&(end as u16).to_be_bytes());
+    buf.extend_from_slice(&s.as_bytes()[..end]);
+}
+
+/// Encode a response, as a complete frame ready to be written
+pub fn encode_response(result: std::result::Result<&UaInfo, &str>) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(512);
+    match result {
+        Ok(info) => {
+            let fields = fields(info);
+            payload.push(STATUS_OK);
+            payload.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+            for field in fields {
+                put_str(&mut payload, field);
+            }
+            payload.extend_from_slice(&info.regex_limit_errors.to_be_bytes());
+        }
+        Err(msg) => {
+            payload.push(STATUS_ERROR);
+            put_str(&mut payload, msg);
+        }
+    }
+    frame(&payload)
+}
+
+/// Sequential reader over a response payload
+struct Cursor<'a>(&'a [u8]);
+
+impl Cursor<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        if self.0.len() < n {
+            return Err(anyhow!("truncated sidecar response"));
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn str(&mut self) -> Result<String> {
+        let len = self.u16()? as usize;
+        Ok(String::from_utf8(self.take(len)?.to_vec())?)
+    }
+}
+
+/// Decode a response payload, an error response is returned as Err
+pub fn decode_response(payload: &[u8]) -> Result<UaInfo> {
+    let mut cursor = Cursor(payload);
+    match cursor.take(1)?[0] {
+        STATUS_OK => {
+            let mut info = UaInfo::default();
+            let count = cursor.u16()? as usize;
+            let mut fields = fields_mut(&mut info);
+            if count != fields.len() {
+                return Err(anyhow!(
+                    "sidecar sent {} fields, {} expected, are both built with the same features?",
+                    count,
+                    fields.len()
+                ));
+            }
+            for field in fields.iter_mut() {
+                **field = cursor.str()?;
+            }
+            info.regex_limit_errors = cursor.u32()?;
+            Ok(info)
+        }
+        STATUS_ERROR => Err(anyhow!("sidecar error: {}", cursor.str()?)),
+        status => Err(anyhow!("unknown sidecar response status {}", status)),
+    }
+}
+
+#[cfg(unix)]
+pub use self::client::SidecarClient;
+
+#[cfg(unix)]
+mod client {
+    use std::io::{BufReader, BufWriter, Write};
+    use std::net::Shutdown;
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+    use std::thread;
+
+    use anyhow::{anyhow, Result};
+
+    use super::{decode_response, read_frame, write_request};
+    use crate::UaInfo;
+
+    /// Client of the sidecar daemon, `udger sidecar`
+    pub struct SidecarClient {
+        reader: BufReader<UnixStream>,
+        writer: BufWriter<UnixStream>,
+        in_flight: usize,
+    }
+
+    impl SidecarClient {
+        pub fn connect<P>(path: P) -> Result<SidecarClient>
+        where
+            P: AsRef<Path>,
+        {
+            SidecarClient::from_stream(UnixStream::connect(path)?)
+        }
+
+        pub fn from_stream(stream: UnixStream) -> Result<SidecarClient> {
+            Ok(SidecarClient {
+                reader: BufReader::new(stream.try_clone()?),
+                writer: BufWriter::new(stream),
+                in_flight: 0,
+            })
+        }
+
+        /// Parse a single User-Agent. Requests queued with `send` must have
+        /// been answered first
+        pub fn parse(&mut self, ua: &str) -> Result<UaInfo> {
+            self.check_idle()?;
+            self.send(ua)?;
+            self.recv()
+        }
+
+        /// Refuse a round trip while responses are pending, it would read the
+        /// response of another request
+        fn check_idle(&self) -> Result<()> {
+            if self.in_flight > 0 {
+                return Err(anyhow!(
+                    "{} requests in flight, their responses must be read first",
+                    self.in_flight
+                ));
+            }
+            Ok(())
+        }
+
+        /// Parse many User-Agents, pipelining the requests. They are written by
+        /// a second thread while the responses are read, so that neither side
+        /// blocks on a full socket buffer. Every response is read even if some
+        /// are errors, the first of which is returned. Requests queued with
+        /// `send` must have been answered first
+        pub fn parse_many<S>(&mut self, uas: &[S]) -> Result<Vec<UaInfo>>
+        where
+            S: AsRef<str> + Sync,
+        {
+            self.check_idle()?;
+            let stream = self.writer.get_ref().try_clone()?;
+            let (reader, writer) = (&mut self.reader, &mut self.writer);
+
+            let (sent, received) = thread::scope(|s| {
+                let sender = s.spawn(|| {
+                    let sent = uas
+                        .iter()
+                        .try_for_each(|ua| write_request(writer, ua.as_ref()))
+                        .and_then(|_| Ok(writer.flush()?));
+                    // a reader waiting for the responses of unsent requests
+                    if sent.is_err() {
+                        let _ = stream.shutdown(Shutdown::Both);
+                    }
+                    sent
+                });
+                let received: Result<Vec<_>> = (0..uas.len())
+                    .map(|_| {
+                        read_frame(reader)?.ok_or_else(|| anyhow!("sidecar closed connection"))
+                    })
+                    .collect();
+                // a sender blocked by the responses no longer read
+                if received.is_err() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                let sent = sender
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow!("sidecar sender thread panicked")));
+                (sent, received)
+            });
+            sent?;
+            received?
+                .iter()
+                .map(|frame| decode_response(frame))
+                .collect()
+        }
+
+        /// Queue a request, its response must be read with `recv`
+        pub fn send(&mut self, ua: &str) -> Result<()> {
+            write_request(&mut self.writer, ua)?;
+            self.in_flight += 1;
+            Ok(())
+        }
+
+        /// Read the response of the oldest request in flight
+        pub fn recv(&mut self) -> Result<UaInfo> {
+            if self.in_flight == 0 {
+                return Err(anyhow!("no request in flight"));
+            }
+            self.writer.flush()?;
+            let payload = read_frame(&mut self.reader)?
+                .ok_or_else(|| anyhow!("sidecar closed connection"))?;
+            self.in_flight -= 1;
+            decode_response(&payload)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> UaInfo {
+        UaInfo {
+            ua_class: String::from("Browser"),
+            ua_family: String::from("Firefox"),
+            ua_version: String::from("40.0"),
+            ua_string: String::from("Mozilla/5.0 (Windows NT 10.0; WOW64; rv:40.0) Firefox/40.0"),
+            os_family: String::from("Windows"),
+            device_class: String::from("Desktop"),
+            regex_limit_errors: 2,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_response_round_trip() {
+        let info = info();
+        let frame = encode_response(Ok(&info));
+        let payload = read_frame(&mut &frame[..]).unwrap().unwrap();
+        let decoded = decode_response(&payload).unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            serde_json::to_value(&info).unwrap()
+        );
+
+        let frame = encode_response(Err("boom"));
+        let payload = read_frame(&mut &frame[..]).unwrap().unwrap();
+        let err = decode_response(&payload).unwrap_err();
+        assert_eq!(err.to_string(), "sidecar error: boom");
+    }
+
+    #[test]
+    fn test_bad_frames() {
+        assert!(read_frame(&mut &b""[..]).unwrap().is_none());
+        assert!(read_frame(&mut &b"\x00\x00\x00\x05abc"[..]).is_err());
+        assert!(read_frame(&mut &b"\xff\xff\xff\xff"[..]).is_err());
+        assert!(decode_response(b"").is_err());
+        assert!(decode_response(b"\x00\x00\x01").is_err());
+        assert!(decode_response(b"\x07").is_err());
+    }
+
+    #[test]
+    fn test_put_str_truncates_on_char_boundary() {
+        let mut buf = Vec::new();
+        let long = "é".repeat(40000);
+        put_str(&mut buf, &long);
+        let len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+        assert_eq!(len, 65534);
+        assert!(std::str::from_utf8(&buf[2..]).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_client_pipelining() {
+        use std::io::{BufReader, BufWriter, Write};
+        use std::os::unix::net::UnixStream;
+
+        let (client, server) = UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || {
+            let mut reader = BufReader::new(server.try_clone().unwrap());
+            let mut writer = BufWriter::new(server);
+            while let Some(ua) = read_request(&mut reader).unwrap() {
+                let info = UaInfo {
+                    ua_string: ua,
+                    ..Default::default()
+                };
+                writer.write_all(&encode_response(Ok(&info))).unwrap();
+                writer.flush().unwrap();
+            }
+        });
+
+        let mut client = SidecarClient::from_stream(client).unwrap();
+        assert_eq!(client.parse("single").unwrap().ua_string, "single");
+
+        let uas: Vec<String> = (0..1000).map(|i| format!("ua {}", i)).collect();
+        let infos = client.parse_many(&uas).unwrap();
+        assert_eq!(infos.len(), 1000);
+        assert!(infos
+            .iter()
+            .zip(&uas)
+            .all(|(info, ua)| info.ua_string == *ua));
+
+        // far more than the socket buffers hold in both directions
+        let long: Vec<String> = (0..200).map(|i| format!("{:060000}", i)).collect();
+        let infos = client.parse_many(&long).unwrap();
+        assert_eq!(infos[199].ua_string, long[199]);
+
+        client.send("queued").unwrap();
+        assert!(client.parse_many(&uas).is_err());
+        assert!(client.parse("other").is_err());
+        assert_eq!(client.recv().unwrap().ua_string, "queued");
+        assert_eq!(client.parse("other").unwrap().ua_string, "other");
+
+        drop(client);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,551 @@
+//! Aggregated statistics over parse results
+//!
+//! `UaStats` counts parse results by class, family, OS and device. Those
+//! dimensions are bounded by the udger database, so they are counted exactly.
+//! Raw User-Agent strings are not, so the busiest ones are tracked with the
+//! Space-Saving algorithm and distinct ones are estimated with HyperLogLog.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::UaInfo;
+
+const UNKNOWN: &str = "unknown";
+
+/// Default number of User-Agents tracked for the top-N
+pub const DEFAULT_TOP_CAPACITY: usize = 1000;
+
+/// Counters over a stream of parse results, mergeable across threads
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UaStats {
+    pub total: u64,
+    pub ua_class: BTreeMap<String, u64>,
+    pub ua_family: BTreeMap<String, u64>,
+    /// Keyed by family and major version, e.g. "Firefox 40"
+    pub ua_version_major: BTreeMap<String, u64>,
+    pub os: BTreeMap<String, u64>,
+    pub os_family: BTreeMap<String, u64>,
+    pub device_class: BTreeMap<String, u64>,
+    pub device_brand: BTreeMap<String, u64>,
+    /// Crawlers only
+    pub crawler_category: BTreeMap<String, u64>,
+    pub top_ua: TopK,
+    pub distinct_ua: HyperLogLog,
+}
+
+impl Default for UaStats {
+    fn default() -> UaStats {
+        UaStats::new(DEFAULT_TOP_CAPACITY)
+    }
+}
+
+fn count(map: &mut BTreeMap<String, u64>, key: &str) {
+    let key = if key.is_empty() { UNKNOWN } else { key };
+    match map.get_mut(key) {
+        Some(count) => *count += 1,
+        None => {
+            map.insert(key.to_string(), 1);
+        }
+    }
+}
+
+fn merge_counts(map: &mut BTreeMap<String, u64>, other: &BTreeMap<String, u64>) {
+    for (key, count) in other {
+        *map.entry(key.clone()).or_default() += count;
+    }
+}
+
+impl UaStats {
+    /// Create an accumulator tracking up to `top_capacity` User-Agents for the top-N
+    ///
+    /// The top-N is exact as long as fewer distinct User-Agents were seen,
+    /// and reliable for those seen more than `total / top_capacity` times.
+    pub fn new(top_capacity: usize) -> UaStats {
+        UaStats {
+            total: 0,
+            ua_class: BTreeMap::new(),
+            ua_family: BTreeMap::new(),
+            ua_version_major: BTreeMap::new(),
+            os: BTreeMap::new(),
+            os_family: BTreeMap::new(),
+            device_class: BTreeMap::new(),
+            device_brand: BTreeMap::new(),
+            crawler_category: BTreeMap::new(),
+            top_ua: TopK::new(top_capacity),
+            distinct_ua: HyperLogLog::default(),
+        }
+    }
+
+    /// Account a parse result
+    pub fn add(&mut self, info: &UaInfo) {
+        self.total += 1;
+        count(&mut self.ua_class, &info.ua_class);
+        count(&mut self.ua_family, &info.ua_family);
+        if info.ua_family.is_empty() {
+            count(&mut self.ua_version_major, "");
+        } else {
+            let version = format!("{} {}", info.ua_family, info.ua_version_major);
+            count(&mut self.ua_version_major, version.trim_end());
+        }
+        count(&mut self.os, &info.os);
+        count(&mut self.os_family, &info.os_family);
+        count(&mut self.device_class, &info.device_class);
+        count(&mut self.device_brand, &info.device_brand);
+        if !info.crawler_category.is_empty() {
+            count(&mut self.crawler_category, &info.crawler_category);
+        }
+        self.top_ua.insert(&info.ua_string);
+        self.distinct_ua.insert(info.ua_string.as_bytes());
+    }
+
+    /// Merge the statistics gathered by another thread
+    pub fn merge(&mut self, other: &UaStats) {
+        self.total += other.total;
+        merge_counts(&mut self.ua_class, &other.ua_class);
+        merge_counts(&mut self.ua_family, &other.ua_family);
+        merge_counts(&mut self.ua_version_major, &other.ua_version_major);
+        merge_counts(&mut self.os, &other.os);
+        merge_counts(&mut self.os_family, &other.os_family);
+        merge_counts(&mut self.device_class, &other.device_class);
+        merge_counts(&mut self.device_brand, &other.device_brand);
+        merge_counts(&mut self.crawler_category, &other.crawler_category);
+        self.top_ua.merge(&other.top_ua);
+        self.distinct_ua.merge(&other.distinct_ua);
+    }
+
+    /// Estimated number of distinct User-Agent strings
+    pub fn distinct_ua_count(&self) -> u64 {
+        self.distinct_ua.estimate()
+    }
+
+    /// The `n` most frequent User-Agent strings
+    pub fn top(&self, n: usize) -> Vec<TopEntry> {
+        self.top_ua.top(n)
+    }
+
+    /// Dashboard friendly view, with the top-N and distinct estimate resolved
+    pub fn report(&self, n: usize) -> StatsReport<'_> {
+        StatsReport {
+            total: self.total,
+            distinct_ua: self.distinct_ua_count(),
+            top_ua: self.top(n),
+            ua_class: &self.ua_class,
+            ua_family: &self.ua_family,
+            ua_version_major: &self.ua_version_major,
+            os: &self.os,
+            os_family: &self.os_family,
+            device_class: &self.device_class,
+            device_brand: &self.device_brand,
+            crawler_category: &self.crawler_category,
+        }
+    }
+}
+
+/// Serializable summary of `UaStats`
+#[derive(Debug, Serialize)]
+pub struct StatsReport<'a> {
+    pub total: u64,
+    pub distinct_ua: u64,
+    pub top_ua: Vec<TopEntry>,
+    pub ua_class: &'a BTreeMap<String, u64>,
+    pub ua_family: &'a BTreeMap<String, u64>,
+    pub ua_version_major: &'a BTreeMap<String, u64>,
+    pub os: &'a BTreeMap<String, u64>,
+    pub os_family: &'a BTreeMap<String, u64>,
+    pub device_class: &'a BTreeMap<String, u64>,
+    pub device_brand: &'a BTreeMap<String, u64>,
+    pub crawler_category: &'a BTreeMap<String, u64>,
+}
+
+/// A User-Agent and its approximate count
+///
+/// The real count lies within `count - error ..= count`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TopEntry {
+    pub ua: String,
+    pub count: u64,
+    pub error: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+struct TopKRepr {
+    capacity: usize,
+    entries: Vec<TopEntry>,
+}
+
+/// Space-Saving heavy hitters summary
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(from = "TopKRepr", into = "TopKRepr")]
+pub struct TopK {
+    capacity: usize,
+    /// ua -> (count, overestimation)
+    counters: HashMap<String, (u64, u64)>,
+    /// Min-heap of a (count, ua) per counter, whose count may lag behind the
+    /// counter's, see `pop_min`
+    heap: BinaryHeap<Reverse<(u64, String)>>,
+}
+
+impl From<TopKRepr> for TopK {
+    fn from(repr: TopKRepr) -> TopK {
+        TopK::with_counters(
+            repr.capacity,
+            repr.entries
+                .into_iter()
+                .map(|entry| (entry.ua, (entry.count, entry.error)))
+                .collect(),
+        )
+    }
+}
+
+impl From<TopK> for TopKRepr {
+    fn from(top: TopK) -> TopKRepr {
+        TopKRepr {
+            capacity: top.capacity,
+            entries: top.top(top.capacity),
+        }
+    }
+}
+
+impl TopK {
+    pub fn new(capacity: usize) -> TopK {
+        TopK::with_counters(capacity, HashMap::new())
+    }
+
+    fn with_counters(capacity: usize, counters: HashMap<String, (u64, u64)>) -> TopK {
+        let heap = counters
+            .iter()
+            .map(|(ua, (count, _))| Reverse((*count, ua.clone())))
+            .collect();
+        TopK {
+            capacity: capacity.max(1),
+            counters,
+            heap,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.counters.len() >= self.capacity
+    }
+
+    fn min_count(&self) -> u64 {
+        self.counters
+            .values()
+            .map(|(count, _)| *count)
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Remove the least frequent entry, returns its count
+    ///
+    /// Counts only grow, so a heap entry is at most its counter's count: the
+    /// lagging ones met on the way are pushed back with their current count,
+    /// at most once per increment.
+    fn pop_min(&mut self) -> Option<u64> {
+        while let Some(Reverse((count, ua))) = self.heap.pop() {
+            match self.counters.get(&ua) {
+                Some((current, _)) if *current == count => {
+                    self.counters.remove(&ua);
+                    return Some(count);
+                }
+                Some((current, _)) => self.heap.push(Reverse((*current, ua))),
+                None => {}
+            }
+        }
+        None
+    }
+
+    pub fn insert(&mut self, ua: &str) {
+        if let Some((count, _)) = self.counters.get_mut(ua) {
+            *count += 1;
+            return;
+        }
+        if !self.is_full() {
+            self.counters.insert(ua.to_string(), (1, 0));
+            self.heap.push(Reverse((1, ua.to_string())));
+            return;
+        }
+
+        // replace the least frequent entry, which bounds the new one's error
+        if let Some(min) = self.pop_min() {
+            self.counters.insert(ua.to_string(), (min + 1, min));
+            self.heap.push(Reverse((min + 1, ua.to_string())));
+        }
+    }
+
+    /// Merge two summaries, following Agarwal et al., "Mergeable Summaries"
+    pub fn merge(&mut self, other: &TopK) {
+        // an entry missing from a full summary may have occurred up to its minimum
+        let self_min = if self.is_full() { self.min_count() } else { 0 };
+        let other_min = if other.is_full() {
+            other.min_count()
+        } else {
+            0
+        };
+
+        let mut merged: Vec<(String, (u64, u64))> = Vec::new();
+        for (ua, (count, error)) in &self.counters {
+            let (other_count, other_error) = other
+                .counters
+                .get(ua)
+                .copied()
+                .unwrap_or((other_min, other_min));
+            merged.push((ua.clone(), (count + other_count, error + other_error)));
+        }
+        for (ua, (count, error)) in &other.counters {
+            if !self.counters.contains_key(ua) {
+                merged.push((ua.clone(), (count + self_min, error + self_min)));
+            }
+        }
+
+        merged.sort_by(|a, b| b.1 .0.cmp(&a.1 .0).then_with(|| a.0.cmp(&b.0)));
+        merged.truncate(self.capacity);
+        *self = TopK::with_counters(self.capacity, merged.into_iter().collect());
+    }
+
+    /// The `n` entries with the highest counts
+    pub fn top(&self, n: usize) -> Vec<TopEntry> {
+        let mut entries: Vec<TopEntry> = self
+            .counters
+            .iter()
+            .map(|(ua, (count, error))| TopEntry {
+                ua: ua.clone(),
+                count: *count,
+                error: *error,
+            })
+            .collect();
+        entries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.ua.cmp(&b.ua)));
+        entries.truncate(n);
+        entries
+    }
+}
+
+/// Number of index bits, 2^12 registers give a standard error of about 1.6%
+const HLL_PRECISION: u32 = 12;
+const HLL_REGISTERS: usize = 1 << HLL_PRECISION;
+
+/// HyperLogLog distinct count estimator
+///
+/// Uses a fixed hash function, so estimators serialized by different
+/// processes can still be merged.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HyperLogLog {
+    #[serde(with = "hex_registers")]
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> HyperLogLog {
+        HyperLogLog {
+            registers: vec![0; HLL_REGISTERS],
+        }
+    }
+}
+
+/// FNV-1a, finalized with the SplitMix64 mixer for better high bits
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in bytes {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+impl HyperLogLog {
+    pub fn insert(&mut self, value: &[u8]) {
+        let h = hash(value);
+        let index = (h >> (64 - HLL_PRECISION)) as usize;
+        let rank = ((h << HLL_PRECISION).leading_zeros() + 1).min(64 - HLL_PRECISION + 1) as u8;
+        if self.registers[index] < rank {
+            self.registers[index] = rank;
+        }
+    }
+
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (register, other) in self.registers.iter_mut().zip(other.registers.iter()) {
+            *register = (*register).max(*other);
+        }
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+
+        // small range correction
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            return (m * (m / zeros as f64).ln()).round() as u64;
+        }
+        estimate.round() as u64
+    }
+}
+
+/// Registers are serialized as a hex string, rather than a 4096 elements array
+mod hex_registers {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::HLL_REGISTERS;
+
+    pub fn serialize<S>(registers: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let hex: String = registers.iter().map(|r| format!("{:02x}", r)).collect();
+        serializer.serialize_str(&hex)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() != HLL_REGISTERS * 2 || !hex.is_ascii() {
+            return Err(D::Error::custom("invalid HyperLogLog registers"));
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(ua: &str, family: &str, major: &str, os_family: &str) -> UaInfo {
+        UaInfo {
+            ua_class: String::from("Browser"),
+            ua_family: String::from(family),
+            ua_version_major: String::from(major),
+            os_family: String::from(os_family),
+            ua_string: String::from(ua),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_add() {
+        let mut stats = UaStats::default();
+        stats.add(&info("a", "Firefox", "40", "Windows"));
+        stats.add(&info("a", "Firefox", "40", "Windows"));
+        stats.add(&info("b", "Chrome", "79", ""));
+        stats.add(&UaInfo::default());
+
+        assert_eq!(stats.total, 4);
+        assert_eq!(stats.ua_family["Firefox"], 2);
+        assert_eq!(stats.ua_version_major["Firefox 40"], 2);
+        assert_eq!(stats.ua_version_major["unknown"], 1);
+        assert_eq!(stats.os_family["unknown"], 2);
+        assert!(stats.crawler_category.is_empty());
+        assert_eq!(stats.distinct_ua_count(), 3);
+        assert_eq!(
+            stats.top(1),
+            vec![TopEntry {
+                ua: String::from("a"),
+                count: 2,
+                error: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn test_top_k_heavy_hitters() {
+        let mut top = TopK::new(10);
+        for i in 0..10000 {
+            top.insert("heavy");
+            if i % 2 == 0 {
+                top.insert("medium");
+            }
+            top.insert(&format!("noise {}", i));
+        }
+
+        let entries = top.top(2);
+        assert_eq!(entries[0].ua, "heavy");
+        assert_eq!(entries[1].ua, "medium");
+        assert!(entries[0].count - entries[0].error <= 10000);
+        assert!(entries[0].count >= 10000);
+    }
+
+    #[test]
+    fn test_top_k_eviction() {
+        let mut top = TopK::new(2);
+        for ua in ["a", "a", "a", "b", "c", "c", "d"] {
+            top.insert(ua);
+        }
+        // b then a were the least frequent when c and d came
+        let entry = |ua: &str, count, error| TopEntry {
+            ua: String::from(ua),
+            count,
+            error,
+        };
+        assert_eq!(top.top(2), vec![entry("d", 4, 3), entry("c", 3, 1)]);
+        assert_eq!(top.heap.len(), 2);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut left = UaStats::new(5);
+        let mut right = UaStats::new(5);
+        let mut all = UaStats::new(5);
+        for i in 0..1000 {
+            let ua = format!("ua {}", i % 300);
+            let item = info(&ua, "Firefox", "40", "Windows");
+            if i % 3 == 0 {
+                left.add(&item);
+            } else {
+                right.add(&item);
+            }
+            all.add(&item);
+        }
+        for _ in 0..200 {
+            let item = info("hot", "Chrome", "79", "Linux");
+            left.add(&item);
+            all.add(&item);
+        }
+
+        left.merge(&right);
+        assert_eq!(left.total, 1200);
+        assert_eq!(left.ua_family["Firefox"], 1000);
+        assert_eq!(left.ua_family["Chrome"], 200);
+        assert_eq!(left.top(1)[0].ua, "hot");
+        assert_eq!(left.distinct_ua, all.distinct_ua);
+    }
+
+    #[test]
+    fn test_hyperloglog_estimate() {
+        let mut hll = HyperLogLog::default();
+        assert_eq!(hll.estimate(), 0);
+
+        for i in 0..100_000 {
+            hll.insert(format!("Mozilla/5.0 ({})", i).as_bytes());
+        }
+        let estimate = hll.estimate() as f64;
+        assert!((estimate - 100_000.0).abs() / 100_000.0 < 0.05);
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let mut stats = UaStats::new(3);
+        for ua in ["a", "b", "b", "c", "d"] {
+            stats.add(&info(ua, "Firefox", "40", "Windows"));
+        }
+
+        let json = serde_json::to_string(&stats).unwrap();
+        let restored: UaStats = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.total, stats.total);
+        assert_eq!(restored.top(3), stats.top(3));
+        assert_eq!(restored.distinct_ua, stats.distinct_ua);
+
+        let report = serde_json::to_value(stats.report(1)).unwrap();
+        assert_eq!(report["distinct_ua"], 4);
+        assert_eq!(report["top_ua"][0]["ua"], "b");
+    }
+}
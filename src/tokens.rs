@@ -0,0 +1,212 @@
+//! Structure of a User-Agent following the RFC 9110 product grammar
+//!
+//! ```text
+//! User-Agent = product *( RWS ( product / comment ) )
+//! product    = token [ "/" product-version ]
+//! comment    = "(" *( ctext / quoted-pair / comment ) ")"
+//! ```
+//!
+//! Parsing is lenient: spans which don't follow the grammar are kept apart in
+//! `UaTokens::invalid` and an unterminated comment runs to the end of the string.
+
+/// Whether `c` is a `tchar` of RFC 9110
+fn is_tchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+/// `name/version` product token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Product<'a> {
+    pub name: &'a str,
+    pub version: Option<&'a str>,
+}
+
+/// Parenthesized comment, `text` being inside the parentheses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Comment<'a> {
+    pub text: &'a str,
+}
+
+impl<'a> Comment<'a> {
+    /// The `;` separated items of the comment, trimmed, nested comments kept whole
+    pub fn items(&self) -> Vec<&'a str> {
+        let mut items = Vec::new();
+        let (mut depth, mut start, mut escaped) = (0, 0, false);
+        for (i, c) in self.text.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                ';' if depth <= 0 => {
+                    items.push(self.text[start..i].trim());
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        items.push(self.text[start..].trim());
+        items.retain(|item| !item.is_empty());
+        items
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UaToken<'a> {
+    Product(Product<'a>),
+    Comment(Comment<'a>),
+}
+
+/// Products and comments of a User-Agent, in order, borrowing from it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UaTokens<'a> {
+    pub tokens: Vec<UaToken<'a>>,
+    /// Spans not following the grammar
+    pub invalid: Vec<&'a str>,
+}
+
+impl<'a> UaTokens<'a> {
+    pub fn parse(ua: &'a str) -> UaTokens<'a> {
+        let mut tokens = UaTokens::default();
+        let mut rest = ua.trim_start();
+        while !rest.is_empty() {
+            if let Some(text) = rest.strip_prefix('(') {
+                let (comment, next) = UaTokens::comment(text);
+                tokens
+                    .tokens
+                    .push(UaToken::Comment(Comment { text: comment }));
+                rest = next;
+            } else {
+                let len = rest.find(|c| !is_tchar(c)).unwrap_or(rest.len());
+                let (name, next) = rest.split_at(len);
+                if name.is_empty() {
+                    // skip up to the next product or comment
+                    let skip = rest.chars().next().map_or(0, char::len_utf8);
+                    let len = rest[skip..]
+                        .find(|c: char| c.is_whitespace() || c == '(')
+                        .map_or(rest.len(), |i| i + skip);
+                    tokens.invalid.push(&rest[..len]);
+                    rest = &rest[len..];
+                } else if let Some(next) = next.strip_prefix('/') {
+                    let len = next.find(|c| !is_tchar(c)).unwrap_or(next.len());
+                    let version = &next[..len];
+                    tokens.tokens.push(UaToken::Product(Product {
+                        name,
+                        version: (!version.is_empty()).then_some(version),
+                    }));
+                    rest = &next[len..];
+                } else {
+                    tokens.tokens.push(UaToken::Product(Product {
+                        name,
+                        version: None,
+                    }));
+                    rest = next;
+                }
+            }
+            rest = rest.trim_start();
+        }
+        tokens
+    }
+
+    /// Split `text` following an opening parenthesis at its matching closing one
+    fn comment(text: &'a str) -> (&'a str, &'a str) {
+        let (mut depth, mut escaped) = (0, false);
+        for (i, c) in text.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '(' => depth += 1,
+                ')' if depth == 0 => return (&text[..i], &text[i + 1..]),
+                ')' => depth -= 1,
+                _ => {}
+            }
+        }
+        (text, "")
+    }
+
+    pub fn products(&self) -> impl Iterator<Item = &Product<'a>> {
+        self.tokens.iter().filter_map(|token| match token {
+            UaToken::Product(product) => Some(product),
+            UaToken::Comment(_) => None,
+        })
+    }
+
+    pub fn comments(&self) -> impl Iterator<Item = &Comment<'a>> {
+        self.tokens.iter().filter_map(|token| match token {
+            UaToken::Product(_) => None,
+            UaToken::Comment(comment) => Some(comment),
+        })
+    }
+
+    /// First product named `name`, ignoring case
+    pub fn product(&self, name: &str) -> Option<&Product<'a>> {
+        self.products()
+            .find(|product| product.name.eq_ignore_ascii_case(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let ua = "Mozilla/5.0 (Linux; Android 10; K) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Mobile Safari/537.36";
+        let tokens = UaTokens::parse(ua);
+        let products = tokens
+            .products()
+            .map(|product| (product.name, product.version))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            products,
+            [
+                ("Mozilla", Some("5.0")),
+                ("AppleWebKit", Some("537.36")),
+                ("Chrome", Some("120.0.0.0")),
+                ("Mobile", None),
+                ("Safari", Some("537.36")),
+            ]
+        );
+        let comments = tokens.comments().map(Comment::items).collect::<Vec<_>>();
+        assert_eq!(
+            comments,
+            [vec!["Linux", "Android 10", "K"], vec!["KHTML, like Gecko"]]
+        );
+        assert_eq!(
+            tokens.tokens[1],
+            UaToken::Comment(Comment {
+                text: "Linux; Android 10; K"
+            })
+        );
+        assert!(tokens.invalid.is_empty());
+        assert_eq!(tokens.product("chrome").unwrap().version, Some("120.0.0.0"));
+    }
+
+    #[test]
+    fn test_parse_lenient() {
+        let tokens = UaTokens::parse("  curl/ , (a (nested; b) \\) c; d) [en] Opera (x; y");
+        assert_eq!(
+            tokens.tokens,
+            [
+                UaToken::Product(Product {
+                    name: "curl",
+                    version: None
+                }),
+                UaToken::Comment(Comment {
+                    text: "a (nested; b) \\) c; d"
+                }),
+                UaToken::Product(Product {
+                    name: "Opera",
+                    version: None
+                }),
+                UaToken::Comment(Comment { text: "x; y" }),
+            ]
+        );
+        assert_eq!(tokens.invalid, [",", "[en]"]);
+        let comment = tokens.comments().next().unwrap();
+        assert_eq!(comment.items(), ["a (nested; b) \\) c", "d"]);
+
+        assert_eq!(UaTokens::parse(""), UaTokens::default());
+        assert_eq!(UaTokens::parse("ä/1").invalid, ["ä/1"]);
+    }
+}
@@ -1,4 +1,5 @@
 #include <stdbool.h>
+#include <stdint.h>
 
 struct ua_info;
 struct udger;
@@ -20,6 +21,9 @@ void udger_drop(udger_t *udger);
 int udger_parse_ua(const udger_t *udger, const udger_data_t *udger_data, const char *ua,
                    udger_callback cb, void *data);
 
+int udger_parse_ua_bytes(const udger_t *udger, const udger_data_t *udger_data,
+                         const unsigned char *ua, size_t ua_len, udger_callback cb, void *data);
+
 int udger_data_alloc(udger_t *udger, udger_data_t **data);
 
 void udger_data_drop(udger_data_t *data);
@@ -33,6 +37,8 @@ void ua_info_get_ua_class_code(const ua_info_t *info, char **buf, size_t *buf_le
 void ua_info_get_ua(const ua_info_t *info, char **buf, size_t *buf_len);
 
 void ua_info_get_ua_engine(const ua_info_t *info, char **buf, size_t *buf_len);
+void ua_info_get_ua_engine_version(const ua_info_t *info, char **buf, size_t *buf_len);
+void ua_info_get_ua_engine_version_major(const ua_info_t *info, char **buf, size_t *buf_len);
 
 void ua_info_get_ua_version(const ua_info_t *info, char **buf, size_t *buf_len);
 
@@ -60,6 +66,14 @@ void ua_info_get_ua_family_vendor_code(const ua_info_t *info, char **buf, size_t
 
 void ua_info_get_ua_string(const ua_info_t *info, char **buf, size_t *buf_len);
 
+void ua_info_get_ua_charset(const ua_info_t *info, char **buf, size_t *buf_len);
+
+void ua_info_get_ua_normalization(const ua_info_t *info, char **buf, size_t *buf_len);
+
+uint32_t ua_info_get_regex_limit_errors(const ua_info_t *info);
+
+void ua_info_get_parse_aborted(const ua_info_t *info, char **buf, size_t *buf_len);
+
 void ua_info_get_os_family(const ua_info_t *info, char **buf, size_t *buf_len);
 
 void ua_info_get_os_family_code(const ua_info_t *info, char **buf, size_t *buf_len);
@@ -67,11 +81,16 @@ void ua_info_get_os_family_code(const ua_info_t *info, char **buf, size_t *buf_l
 void ua_info_get_os(const ua_info_t *info, char **buf, size_t *buf_len);
 
 void ua_info_get_os_code(const ua_info_t *info, char **buf, size_t *buf_len);
+void ua_info_get_os_version(const ua_info_t *info, char **buf, size_t *buf_len);
+void ua_info_get_os_version_major(const ua_info_t *info, char **buf, size_t *buf_len);
+void ua_info_get_os_version_minor(const ua_info_t *info, char **buf, size_t *buf_len);
 
 void ua_info_get_os_family_vendor(const ua_info_t *info, char **buf, size_t *buf_len);
 
 void ua_info_get_os_family_vendor_code(const ua_info_t *info, char **buf, size_t *buf_len);
 
+void ua_info_get_os_source(const ua_info_t *info, char **buf, size_t *buf_len);
+
 void ua_info_get_device_class(const ua_info_t *info, char **buf, size_t *buf_len);
 
 void ua_info_get_device_class_code(const ua_info_t *info, char **buf, size_t *buf_len);
@@ -81,6 +100,11 @@ void ua_info_get_device_marketname(const ua_info_t *info, char **buf, size_t *bu
 void ua_info_get_device_brand(const ua_info_t *info, char **buf, size_t *buf_len);
 
 void ua_info_get_device_brand_code(const ua_info_t *info, char **buf, size_t *buf_len);
+void ua_info_get_device_brand_rule(const ua_info_t *info, char **buf, size_t *buf_len);
+
+void ua_info_get_cpu_architecture(const ua_info_t *info, char **buf, size_t *buf_len);
+void ua_info_get_bitness(const ua_info_t *info, char **buf, size_t *buf_len);
+void ua_info_get_ua_locale(const ua_info_t *info, char **buf, size_t *buf_len);
 
 void ua_info_get_application_name(const ua_info_t *info, char **buf, size_t *buf_len);
 
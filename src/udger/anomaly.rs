@@ -0,0 +1,261 @@
+use anyhow::{anyhow, Result};
+use rusqlite::{params, Error};
+use serde::Serialize;
+
+use super::{sql, Udger, UdgerData};
+use crate::tokens::UaTokens;
+use crate::UaInfo;
+
+/// The client isn't available on the OS
+pub const CLIENT_OS: &str = "client_os";
+/// The device class isn't one the client class runs on
+pub const CLIENT_DEVICE_CLASS: &str = "client_device_class";
+/// An Apple device runs another vendor's OS
+pub const DEVICE_OS: &str = "device_os";
+/// The UA lacks the token of the client's rendering engine
+pub const ENGINE: &str = "engine";
+
+/// Device classes of handheld devices, which desktop clients don't run on
+const HANDHELD_CLASSES: &[&str] = &[
+    "smartphone",
+    "tablet",
+    "feature_phone",
+    "wearable_computer",
+    "pda",
+];
+
+/// Apple device tokens found in UA comments
+const APPLE_DEVICES: &[&str] = &["iPhone", "iPad", "iPod", "Macintosh"];
+const APPLE_OS_FAMILIES: &[&str] = &["ios", "osx"];
+
+/// Engines whose token all their clients send, see `ua_engine_version`
+const ENGINES_WITH_TOKEN: &[&str] = &["WebKit", "WebKit/Blink", "Gecko", "EdgeHTML"];
+
+/// Inconsistency between the fields of a parse result, a hint of a spoofed UA
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Anomaly {
+    pub flag: &'static str,
+    pub reason: String,
+}
+
+/// Apple device told by the UA comments while the OS isn't Apple's
+fn device_os(info: &UaInfo) -> Option<Anomaly> {
+    if info.os_family_code.is_empty() || APPLE_OS_FAMILIES.contains(&info.os_family_code.as_str()) {
+        return None;
+    }
+    let tokens = UaTokens::parse(&info.ua_string);
+    let device = tokens
+        .comments()
+        .flat_map(|comment| comment.items())
+        .find_map(|item| {
+            APPLE_DEVICES
+                .iter()
+                .find(|device| item.split_whitespace().next() == Some(**device))
+        })?;
+    Some(Anomaly {
+        flag: DEVICE_OS,
+        reason: format!("{} device running {}", device, info.os_family),
+    })
+}
+
+/// Client engine whose token is missing from the UA
+///
+/// `ua_engine` is the engine of the client family in `udger_client_list`, so
+/// engine vs family can only be told apart through the UA: the family's engine
+/// is one of `ENGINES_WITH_TOKEN` and the UA lacks its token
+fn engine(info: &UaInfo) -> Option<Anomaly> {
+    if !ENGINES_WITH_TOKEN.contains(&info.ua_engine.as_str()) || !info.ua_engine_version.is_empty()
+    {
+        return None;
+    }
+    Some(Anomaly {
+        flag: ENGINE,
+        reason: format!(
+            "{} uses {} but the UA has no {} token",
+            info.ua_family, info.ua_engine, info.ua_engine
+        ),
+    })
+}
+
+impl Udger {
+    /// Check the combinations of the fields of a parse result: client vs OS
+    /// using `udger_client_os_relation`, client class vs device class, device
+    /// vs OS and client family engine vs UA tokens
+    pub fn check_anomalies(&self, info: &UaInfo, data: &mut UdgerData) -> Result<Vec<Anomaly>> {
+        let mut anomalies = Vec::new();
+
+        if let (Some(client_id), false) = (info.client_id, info.os_family_code.is_empty()) {
+            let stmt = &mut data.conn.prepare(sql::SQL_CLIENT_OS_CODES)?;
+            let codes = stmt
+                .query_map(params![client_id], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+            // clients without relations may run anywhere
+            if !codes.is_empty()
+                && !codes.iter().any(|(os_code, os_family_code)| {
+                    *os_code == info.os_code || *os_family_code == info.os_family_code
+                })
+            {
+                anomalies.push(Anomaly {
+                    flag: CLIENT_OS,
+                    reason: format!("{} is not available on {}", info.ua_family, info.os),
+                });
+            }
+        }
+
+        if let (Some(class_id), false) = (info.class_id, info.device_class_code.is_empty()) {
+            let stmt = &mut data.conn.prepare(&sql::SQL_CLIENT_CLASS)?;
+            match stmt.query_row(params![class_id], |row| row.get::<_, String>(1)) {
+                Ok(expected) => {
+                    let handheld = |code: &str| HANDHELD_CLASSES.contains(&code);
+                    if (expected == "desktop" && handheld(&info.device_class_code))
+                        || (handheld(&expected) && info.device_class_code == "desktop")
+                    {
+                        anomalies.push(Anomaly {
+                            flag: CLIENT_DEVICE_CLASS,
+                            reason: format!(
+                                "{} clients run on {}, not {}",
+                                info.ua_class, expected, info.device_class_code
+                            ),
+                        });
+                    }
+                }
+                Err(Error::QueryReturnedNoRows) => {}
+                Err(err) => return Err(anyhow!(err)),
+            };
+        }
+
+        anomalies.extend(device_os(info));
+        anomalies.extend(engine(info));
+        Ok(anomalies)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_device_os() {
+        let info = UaInfo {
+            ua_string: String::from("Mozilla/5.0 (iPad; U; Android 4.4; en-us) AppleWebKit/537.36"),
+            os_family: String::from("Android"),
+            os_family_code: String::from("android"),
+            ..Default::default()
+        };
+        assert_eq!(
+            device_os(&info).unwrap().reason,
+            "iPad device running Android"
+        );
+
+        let info = UaInfo {
+            os_family_code: String::from("ios"),
+            ..info
+        };
+        assert_eq!(device_os(&info), None);
+    }
+
+    #[test]
+    fn test_engine() {
+        let mut info = UaInfo {
+            ua_family: String::from("Safari"),
+            ua_engine: String::from("WebKit"),
+            ..Default::default()
+        };
+        assert_eq!(engine(&info).unwrap().flag, ENGINE);
+        info.ua_engine_version = String::from("605.1.15");
+        assert_eq!(engine(&info), None);
+        info.ua_engine = String::from("Trident");
+        info.ua_engine_version.clear();
+        assert_eq!(engine(&info), None);
+        info.ua_engine = String::from("WebKit/Blink");
+        assert_eq!(engine(&info).unwrap().flag, ENGINE);
+    }
+
+    #[test]
+    fn test_client_os() {
+        let mut udger = Udger::new();
+        udger
+            .init(PathBuf::from("./data/udgerdb_v3_test.dat"), 10000)
+            .unwrap();
+
+        let mut data = udger.alloc_udger_data().unwrap();
+        // a client and an OS family it isn't available on
+        let (client_id, os_family_code): (u32, String) = data
+            .conn
+            .query_row(
+                "SELECT client_id, family_code FROM udger_client_os_relation, udger_os_list \
+                WHERE family_code NOT IN (SELECT family_code FROM udger_client_os_relation r \
+                JOIN udger_os_list o ON o.id = r.os_id WHERE r.client_id = udger_client_os_relation.client_id) \
+                LIMIT 1",
+                params![],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+
+        let mut info = UaInfo {
+            client_id: Some(client_id),
+            os_family_code,
+            ..Default::default()
+        };
+        let anomalies = udger.check_anomalies(&info, &mut data).unwrap();
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].flag, CLIENT_OS);
+
+        info.client_id = None;
+        assert!(udger.check_anomalies(&info, &mut data).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_client_device_class() {
+        let mut udger = Udger::new();
+        udger
+            .init(PathBuf::from("./data/udgerdb_v3_test.dat"), 10000)
+            .unwrap();
+
+        let mut data = udger.alloc_udger_data().unwrap();
+        let class_of = |data: &mut UdgerData, codes: &str| -> u32 {
+            data.conn
+                .query_row(
+                    &format!(
+                        "SELECT c.id FROM udger_client_class c \
+                        JOIN udger_deviceclass_list d ON d.id = c.deviceclass_id \
+                        WHERE d.name_code IN ({}) LIMIT 1",
+                        codes
+                    ),
+                    params![],
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+
+        // a desktop client on a smartphone
+        let mut info = UaInfo {
+            class_id: Some(class_of(&mut data, "'desktop'")),
+            device_class_code: String::from("smartphone"),
+            ..Default::default()
+        };
+        let anomalies = udger.check_anomalies(&info, &mut data).unwrap();
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].flag, CLIENT_DEVICE_CLASS);
+        info.device_class_code = String::from("desktop");
+        assert!(udger.check_anomalies(&info, &mut data).unwrap().is_empty());
+        // neither desktop nor handheld
+        info.device_class_code = String::from("tv");
+        assert!(udger.check_anomalies(&info, &mut data).unwrap().is_empty());
+
+        // a handheld client on a desktop
+        let mut info = UaInfo {
+            class_id: Some(class_of(&mut data, "'smartphone', 'tablet'")),
+            device_class_code: String::from("desktop"),
+            ..Default::default()
+        };
+        let anomalies = udger.check_anomalies(&info, &mut data).unwrap();
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].flag, CLIENT_DEVICE_CLASS);
+        info.device_class_code = String::from("tablet");
+        assert!(udger.check_anomalies(&info, &mut data).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,232 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+/// Built-in releases, see the header of the file for its format
+const BUILTIN: &str = include_str!("darwin.txt");
+
+/// Mac models and architectures telling a macOS app
+const MACOS_HINTS: &[&str] = &[
+    "(x86_64)",
+    "(arm64)",
+    "Macintosh",
+    "MacBook",
+    "iMac",
+    "Macmini",
+    "MacPro",
+];
+
+/// Apple platform of a native app
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    Ios,
+    Ipados,
+    Macos,
+}
+
+/// Apple OS release of a CFNetwork or Darwin version prefix
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DarwinRelease {
+    /// `CFNetwork` or `Darwin`
+    pub token: String,
+    pub version: String,
+    pub platform: Platform,
+    pub os_code: String,
+    pub os_version: String,
+}
+
+/// Mapping of the CFNetwork and Darwin versions of native app User-Agents to
+/// iOS and macOS releases
+#[derive(Debug, Clone)]
+pub struct DarwinVersions {
+    releases: Vec<DarwinRelease>,
+}
+
+impl Default for DarwinVersions {
+    fn default() -> Self {
+        let mut versions = DarwinVersions {
+            releases: Vec::new(),
+        };
+        versions
+            .extend(BUILTIN)
+            .expect("invalid built-in Darwin releases");
+        versions
+    }
+}
+
+/// Dotted version following `<token>/` in `ua`
+fn token_version<'a>(ua: &'a str, token: &str) -> Option<&'a str> {
+    ua.split_whitespace().find_map(|product| {
+        let version = product.strip_prefix(token)?.strip_prefix('/')?;
+        let len = version
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(version.len());
+        let version = version[..len].trim_end_matches('.');
+        (!version.is_empty()).then_some(version)
+    })
+}
+
+/// Whether the dotted `version` starts with the components of `prefix`
+fn has_prefix(version: &str, prefix: &str) -> bool {
+    match version.strip_prefix(prefix) {
+        None => false,
+        Some(rest) => rest.is_empty() || rest.starts_with('.'),
+    }
+}
+
+impl DarwinVersions {
+    /// Add the releases of a mapping, those added last win over the same version
+    pub fn extend(&mut self, mapping: &str) -> Result<()> {
+        for (i, line) in mapping.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = line.split_whitespace().collect::<Vec<&str>>();
+            let (token, version, platform, os_code, os_version) = match fields[..] {
+                [token, version, platform, os_code, os_version] => {
+                    (token, version, platform, os_code, os_version)
+                }
+                _ => return Err(anyhow!("line {}: expected 5 fields", i + 1)),
+            };
+            if token != "CFNetwork" && token != "Darwin" {
+                return Err(anyhow!("line {}: unknown token {}", i + 1, token));
+            }
+            let platform = match platform {
+                "ios" => Platform::Ios,
+                "ipados" => Platform::Ipados,
+                "macos" => Platform::Macos,
+                _ => return Err(anyhow!("line {}: unknown platform {}", i + 1, platform)),
+            };
+            self.releases.push(DarwinRelease {
+                token: String::from(token),
+                version: String::from(version),
+                platform,
+                os_code: String::from(os_code),
+                os_version: String::from(os_version),
+            });
+        }
+        Ok(())
+    }
+
+    /// Add the releases of a mapping file
+    pub fn load(&mut self, path: &Path) -> Result<()> {
+        let mapping =
+            fs::read_to_string(path).map_err(|err| anyhow!("{}: {}", path.display(), err))?;
+        self.extend(&mapping)
+            .map_err(|err| anyhow!("{}: {}", path.display(), err))
+    }
+
+    /// Release of a native app User-Agent, by its CFNetwork version first.
+    /// iPads fall back to the iOS releases
+    pub fn find(&self, ua: &str) -> Option<&DarwinRelease> {
+        let platforms: &[Platform] = if MACOS_HINTS.iter().any(|hint| ua.contains(hint)) {
+            &[Platform::Macos]
+        } else if ua.contains("iPad") {
+            &[Platform::Ipados, Platform::Ios]
+        } else {
+            &[Platform::Ios]
+        };
+        platforms.iter().find_map(|platform| {
+            ["CFNetwork", "Darwin"].iter().find_map(|token| {
+                let version = token_version(ua, token)?;
+                // max_by_key returns the last of the longest prefixes
+                self.releases
+                    .iter()
+                    .filter(|release| release.token == *token && release.platform == *platform)
+                    .filter(|release| has_prefix(version, &release.version))
+                    .max_by_key(|release| release.version.len())
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// OS code and version of the release found for `ua`
+    fn find<'a>(versions: &'a DarwinVersions, ua: &str) -> Option<(&'a str, &'a str)> {
+        versions
+            .find(ua)
+            .map(|release| (release.os_code.as_str(), release.os_version.as_str()))
+    }
+
+    #[test]
+    fn test_find() {
+        let mut versions = DarwinVersions::default();
+        assert_eq!(
+            find(&versions, "MyApp/3.2 CFNetwork/1404.0.5 Darwin/22.3.0"),
+            Some(("ios_16", "16.3"))
+        );
+        assert_eq!(
+            find(
+                &versions,
+                "MyApp/1.0 CFNetwork/1220.1 Darwin/20.3.0 (x86_64)"
+            ),
+            Some(("macos_11", "11.2"))
+        );
+        assert_eq!(
+            find(
+                &versions,
+                "MyApp/1.0 CFNetwork/1474 Darwin/23.0.0 (Macmini9,1)"
+            ),
+            Some(("macos_14", "14.0"))
+        );
+        // CFNetwork-only User-Agents
+        assert_eq!(
+            find(&versions, "MyApp/1.0 CFNetwork/1404.0.5"),
+            Some(("ios_16", "16.3"))
+        );
+        assert_eq!(
+            find(&versions, "MyApp/1.0 CFNetwork/1498.700.2 (arm64)"),
+            Some(("macos_14", "14.6"))
+        );
+        // a CFNetwork build of several point releases, left to Darwin
+        assert_eq!(
+            find(&versions, "MyApp/1.0 CFNetwork/1399 Darwin/22.2.0"),
+            Some(("ios_16", "16.2"))
+        );
+        assert_eq!(find(&versions, "MyApp/1.0 CFNetwork/1399"), None);
+        assert_eq!(
+            find(&versions, "MyApp/1.0 CFNetwork/1128.0.1 Darwin/19.6.0"),
+            Some(("ios_13", "13"))
+        );
+        // iPads run iPadOS since 13
+        assert_eq!(
+            find(
+                &versions,
+                "MyApp/1.0 (iPad13,4) CFNetwork/1404.0.5 Darwin/22.3.0"
+            ),
+            Some(("ipados_16", "16.3"))
+        );
+        assert_eq!(
+            find(
+                &versions,
+                "MyApp/1.0 (iPad7,5) CFNetwork/975.0.3 Darwin/18.2.0"
+            ),
+            Some(("ios_12", "12"))
+        );
+        assert_eq!(find(&versions, "MyApp/1.0 Darwin/220"), None);
+
+        versions
+            .extend("CFNetwork 1404.0.5 ios ios_16 16.3.1\nDarwin 22 ios ios_16 16.0\n")
+            .unwrap();
+        assert_eq!(
+            find(&versions, "MyApp/3.2 CFNetwork/1404.0.5 Darwin/22.3.0"),
+            Some(("ios_16", "16.3.1"))
+        );
+        assert_eq!(
+            find(&versions, "MyApp/3.2 CFNetwork/1404.1 Darwin/22.3.0"),
+            Some(("ios_16", "16.3"))
+        );
+        assert_eq!(
+            find(&versions, "MyApp/3.2 CFNetwork/1404.1 Darwin/22"),
+            Some(("ios_16", "16.0"))
+        );
+
+        assert!(versions.extend("Darwin 22 watchos watchos_9 9").is_err());
+        assert!(versions.extend("Darwin 22 ios").is_err());
+    }
+}
@@ -0,0 +1,30 @@
+use std::ops::Range;
+
+use serde::Serialize;
+
+use super::regex_sequence::RegexTrace;
+
+/// The device class came from the device class regexes
+pub const DEVICE_CLASS_REGEX: &str = "regex";
+/// The device class is the default one of the client class, `SQL_CLIENT_CLASS`
+pub const DEVICE_CLASS_CLIENT: &str = "client_class";
+
+/// How the detectors came to a parse result, see `Udger::parse_ua_explain`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Explanation {
+    /// Whether `SQL_CRAWLER` matched, skipping the client regexes
+    pub crawler: bool,
+    pub client: RegexTrace,
+    pub os: RegexTrace,
+    pub device_class: RegexTrace,
+    /// Rules of the device names, whose word ids are OS family and OS codes
+    pub device_name: RegexTrace,
+    /// Span of the User-Agent the client version was taken from
+    pub client_version_capture: Option<Range<usize>>,
+    /// Span of the User-Agent the OS version was taken from
+    pub os_version_capture: Option<Range<usize>>,
+    /// Span of the User-Agent the device code was taken from
+    pub device_code_capture: Option<Range<usize>>,
+    /// `DEVICE_CLASS_REGEX`, `DEVICE_CLASS_CLIENT`, or empty without device class
+    pub device_class_source: &'static str,
+}
@@ -0,0 +1,393 @@
+use std::borrow::Cow;
+use std::net::IpAddr;
+use std::rc::Rc;
+
+use crate::UaInfo;
+
+/// Headers relevant to classification, borrowed from a raw HTTP/1.x header block
+///
+/// Header names are matched case-insensitively and obsolete line folding is
+/// undone. A repeated `User-Agent` keeps its first value, repeated list headers
+/// (`X-Forwarded-For`, `Forwarded`, `Sec-CH-UA*`) are joined with `, `.
+///
+/// Proxies and transcoders like Opera Mini send the User-Agent of the device
+/// behind them in one of `DEVICE_USER_AGENT_HEADERS`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HttpHeaders<'a> {
+    pub user_agent: Option<Cow<'a, str>>,
+    /// `user_agent` as sent, before its lossy UTF-8 decoding
+    pub user_agent_bytes: Option<Cow<'a, [u8]>>,
+    /// `Sec-CH-UA*` client hints, with their names as sent
+    pub client_hints: Vec<(Cow<'a, str>, Cow<'a, str>)>,
+    /// Device User-Agent headers, with their names as sent
+    pub device_user_agents: Vec<(Cow<'a, str>, Cow<'a, str>)>,
+    /// Values of `device_user_agents` as sent
+    pub device_user_agent_bytes: Vec<Cow<'a, [u8]>>,
+    pub forwarded_for: Option<Cow<'a, str>>,
+    pub forwarded: Option<Cow<'a, str>>,
+}
+
+/// Result of `Udger::parse_http_headers`
+#[derive(Debug, Clone)]
+pub struct HeadersInfo {
+    /// Classification of the User-Agent, None without a `User-Agent` header
+    ///
+    /// With a device User-Agent, the OS and device fields are those of the
+    /// device, see `Udger::parse_proxied_ua`.
+    pub ua_info: Option<Rc<UaInfo>>,
+    pub device_user_agent: Option<String>,
+    /// Originating client from `Forwarded` or `X-Forwarded-For`
+    pub client_ip: Option<IpAddr>,
+    /// `Sec-CH-UA*` client hints, with lowercase names
+    pub client_hints: Vec<(String, String)>,
+}
+
+fn trim(mut bytes: &[u8]) -> &[u8] {
+    while let [b' ' | b'\t', rest @ ..] = bytes {
+        bytes = rest;
+    }
+    while let [rest @ .., b' ' | b'\t' | b'\r'] = bytes {
+        bytes = rest;
+    }
+    bytes
+}
+
+/// Append a folded line or a repeated list header to a value
+fn append<'a>(value: &mut Cow<'a, str>, separator: &str, more: &[u8]) {
+    let value = value.to_mut();
+    value.push_str(separator);
+    value.push_str(&String::from_utf8_lossy(more));
+}
+
+/// Append a folded line to a raw value
+fn append_bytes(value: &mut Cow<'_, [u8]>, more: &[u8]) {
+    let value = value.to_mut();
+    value.push(b' ');
+    value.extend_from_slice(more);
+}
+
+/// Which field of `HttpHeaders` a header goes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    UserAgent,
+    ClientHint(usize),
+    DeviceUserAgent(usize),
+    ForwardedFor,
+    Forwarded,
+    Ignored,
+}
+
+impl<'a> HttpHeaders<'a> {
+    /// Headers carrying the original device User-Agent, by precedence
+    pub const DEVICE_USER_AGENT_HEADERS: [&'static str; 5] = [
+        "X-OperaMini-Phone-UA",
+        "X-Device-User-Agent",
+        "X-Original-User-Agent",
+        "Device-Stock-UA",
+        "X-UCBrowser-Device-UA",
+    ];
+
+    /// Find the headers in a raw block, with or without its request line
+    ///
+    /// Parsing stops at the first empty line, lines which aren't headers are
+    /// skipped.
+    pub fn parse(block: &'a [u8]) -> HttpHeaders<'a> {
+        let mut headers = HttpHeaders::default();
+        let mut last = Slot::Ignored;
+
+        for (i, line) in block.split(|b| *b == b'\n').enumerate() {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.is_empty() {
+                if i == 0 {
+                    continue;
+                }
+                break;
+            }
+
+            // obsolete line folding, RFC 9112 section 5.2
+            if line[0] == b' ' || line[0] == b'\t' {
+                let value = match last {
+                    Slot::UserAgent => headers.user_agent.as_mut(),
+                    Slot::ClientHint(i) => headers.client_hints.get_mut(i).map(|(_, v)| v),
+                    Slot::DeviceUserAgent(i) => {
+                        headers.device_user_agents.get_mut(i).map(|(_, v)| v)
+                    }
+                    Slot::ForwardedFor => headers.forwarded_for.as_mut(),
+                    Slot::Forwarded => headers.forwarded.as_mut(),
+                    Slot::Ignored => None,
+                };
+                if let Some(value) = value {
+                    append(value, " ", trim(line));
+                }
+                let bytes = match last {
+                    Slot::UserAgent => headers.user_agent_bytes.as_mut(),
+                    Slot::DeviceUserAgent(i) => headers.device_user_agent_bytes.get_mut(i),
+                    _ => None,
+                };
+                if let Some(bytes) = bytes {
+                    append_bytes(bytes, trim(line));
+                }
+                continue;
+            }
+
+            let (name, value) = match line.iter().position(|b| *b == b':') {
+                Some(colon) if colon > 0 && !line[..colon].contains(&b' ') => {
+                    (&line[..colon], trim(&line[colon + 1..]))
+                }
+                // request line or garbage
+                _ => {
+                    last = Slot::Ignored;
+                    continue;
+                }
+            };
+            last = headers.add(name, value);
+        }
+        headers
+    }
+
+    fn add(&mut self, name: &'a [u8], value: &'a [u8]) -> Slot {
+        let (slot, field) = if name.eq_ignore_ascii_case(b"user-agent") {
+            if self.user_agent.is_some() {
+                // the first one wins, folded lines of the others are ignored
+                return Slot::Ignored;
+            }
+            self.user_agent_bytes = Some(Cow::Borrowed(value));
+            (Slot::UserAgent, &mut self.user_agent)
+        } else if name.eq_ignore_ascii_case(b"x-forwarded-for") {
+            (Slot::ForwardedFor, &mut self.forwarded_for)
+        } else if name.eq_ignore_ascii_case(b"forwarded") {
+            (Slot::Forwarded, &mut self.forwarded)
+        } else if name.len() >= 9 && name[..9].eq_ignore_ascii_case(b"sec-ch-ua") {
+            let name = String::from_utf8_lossy(name);
+            return match self
+                .client_hints
+                .iter()
+                .position(|(hint, _)| hint.eq_ignore_ascii_case(&name))
+            {
+                Some(i) => {
+                    append(&mut self.client_hints[i].1, ", ", value);
+                    Slot::ClientHint(i)
+                }
+                None => {
+                    self.client_hints
+                        .push((name, String::from_utf8_lossy(value)));
+                    Slot::ClientHint(self.client_hints.len() - 1)
+                }
+            };
+        } else if Self::DEVICE_USER_AGENT_HEADERS
+            .iter()
+            .any(|header| name.eq_ignore_ascii_case(header.as_bytes()))
+        {
+            let name = String::from_utf8_lossy(name);
+            if self.device_user_agent_header(&name).is_some() {
+                return Slot::Ignored;
+            }
+            self.device_user_agents
+                .push((name, String::from_utf8_lossy(value)));
+            self.device_user_agent_bytes.push(Cow::Borrowed(value));
+            return Slot::DeviceUserAgent(self.device_user_agents.len() - 1);
+        } else {
+            return Slot::Ignored;
+        };
+
+        match field {
+            Some(field) => append(field, ", ", value),
+            None => *field = Some(String::from_utf8_lossy(value)),
+        }
+        slot
+    }
+
+    /// Value of a client hint, names are compared case-insensitively
+    pub fn client_hint(&self, name: &str) -> Option<&str> {
+        self.client_hints
+            .iter()
+            .find(|(hint, _)| hint.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_ref())
+    }
+
+    fn device_user_agent_header(&self, name: &str) -> Option<usize> {
+        self.device_user_agents
+            .iter()
+            .position(|(header, _)| header.eq_ignore_ascii_case(name))
+    }
+
+    /// Index in `device_user_agents` of the first of `DEVICE_USER_AGENT_HEADERS` sent
+    fn device_user_agent_index(&self) -> Option<usize> {
+        Self::DEVICE_USER_AGENT_HEADERS
+            .iter()
+            .find_map(|name| self.device_user_agent_header(name))
+    }
+
+    /// Original device User-Agent, from the first of `DEVICE_USER_AGENT_HEADERS` sent
+    pub fn device_user_agent(&self) -> Option<&str> {
+        self.device_user_agent_index()
+            .map(|i| self.device_user_agents[i].1.as_ref())
+            .filter(|ua| !ua.is_empty())
+    }
+
+    /// `device_user_agent` as sent
+    pub fn device_user_agent_bytes(&self) -> Option<&[u8]> {
+        self.device_user_agent_index()
+            .map(|i| self.device_user_agent_bytes[i].as_ref())
+            .filter(|ua| !ua.is_empty())
+    }
+
+    /// Originating client, from the first `Forwarded` element with a usable
+    /// `for` parameter, or else the first `X-Forwarded-For` address
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        let forwarded = self.forwarded.as_deref().and_then(|forwarded| {
+            let first = forwarded.split(',').next()?;
+            first.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                if key.trim().eq_ignore_ascii_case("for") {
+                    parse_node(value)
+                } else {
+                    None
+                }
+            })
+        });
+        forwarded.or_else(|| {
+            self.forwarded_for
+                .as_deref()
+                .and_then(|xff| xff.split(',').next())
+                .and_then(parse_node)
+        })
+    }
+}
+
+/// Address of a node, RFC 7239 section 6, as `ip`, `ip:port` or `"[ip6]:port"`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    let host = match node.strip_prefix('[') {
+        Some(v6) => v6.split(']').next()?,
+        None => node.rsplit_once(':')?.0,
+    };
+    host.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_headers() {
+        let block = b"GET /page HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            user-AGENT: Mozilla/5.0 (X11;\r\n\
+            \t Linux x86_64)\r\n\
+            User-Agent: second\r\n\
+            \tfolded\r\n\
+            Sec-CH-UA: \"Chromium\";v=\"124\"\r\n\
+            sec-ch-ua-platform: \"Linux\"\r\n\
+            sec-ch-ua: \"Not-A.Brand\";v=\"99\"\r\n\
+            X-Forwarded-For: 203.0.113.7:4711, 10.0.0.1\r\n\
+            x-forwarded-for: 10.0.0.2\r\n\
+            \r\n\
+            User-Agent: body";
+        let headers = HttpHeaders::parse(block);
+
+        assert_eq!(
+            headers.user_agent.as_deref(),
+            Some("Mozilla/5.0 (X11; Linux x86_64)")
+        );
+        assert_eq!(
+            headers.client_hint("SEC-CH-UA"),
+            Some("\"Chromium\";v=\"124\", \"Not-A.Brand\";v=\"99\"")
+        );
+        assert_eq!(headers.client_hint("Sec-CH-UA-Platform"), Some("\"Linux\""));
+        assert_eq!(headers.client_hints.len(), 2);
+        assert_eq!(
+            headers.forwarded_for.as_deref(),
+            Some("203.0.113.7:4711, 10.0.0.1, 10.0.0.2")
+        );
+        assert_eq!(headers.client_ip(), Some("203.0.113.7".parse().unwrap()));
+
+        // unfolded single values are borrowed
+        assert!(matches!(
+            headers.client_hints[1].1,
+            Cow::Borrowed("\"Linux\"")
+        ));
+    }
+
+    #[test]
+    fn test_device_user_agent() {
+        let headers = HttpHeaders::parse(
+            b"User-Agent: Opera/9.80 (J2ME/MIDP; Opera Mini/9.80; U; en) Presto/2.5.25\r\n\
+              Device-Stock-UA: stock\r\n\
+              x-operamini-phone-ua: Nokia3120classic/2.0\r\n\
+              \t(10.00) Profile/MIDP-2.1\r\n\
+              X-OperaMini-Phone-UA: duplicate\r\n",
+        );
+        assert_eq!(
+            headers.device_user_agent(),
+            Some("Nokia3120classic/2.0 (10.00) Profile/MIDP-2.1")
+        );
+        assert_eq!(headers.device_user_agents.len(), 2);
+
+        let headers = HttpHeaders::parse(b"User-Agent: a\r\nX-Device-User-Agent:\r\n");
+        assert_eq!(headers.device_user_agent(), None);
+        assert_eq!(headers.device_user_agent_bytes(), None);
+    }
+
+    #[test]
+    fn test_raw_user_agents() {
+        let headers = HttpHeaders::parse(
+            b"User-Agent: Nokia \xc4\xe7\r\n\
+              \tfolded\r\n\
+              X-Device-User-Agent: Ger\xe4t\r\n",
+        );
+        assert_eq!(
+            headers.user_agent.as_deref(),
+            Some("Nokia \u{fffd}\u{fffd} folded")
+        );
+        assert_eq!(
+            headers.user_agent_bytes.as_deref(),
+            Some(&b"Nokia \xc4\xe7 folded"[..])
+        );
+        assert_eq!(headers.device_user_agent(), Some("Ger\u{fffd}t"));
+        assert_eq!(headers.device_user_agent_bytes(), Some(&b"Ger\xe4t"[..]));
+        assert!(matches!(
+            headers.device_user_agent_bytes[0],
+            Cow::Borrowed(b"Ger\xe4t")
+        ));
+    }
+
+    #[test]
+    fn test_client_ip() {
+        for (block, ip) in [
+            (
+                &b"Forwarded: for=192.0.2.60;proto=http, for=198.51.100.17\n"[..],
+                Some("192.0.2.60"),
+            ),
+            (
+                b"Forwarded: proto=https;For=\"[2001:db8:cafe::17]:4711\"\n",
+                Some("2001:db8:cafe::17"),
+            ),
+            (
+                b"Forwarded: for=unknown\nX-Forwarded-For: 192.0.2.1\n",
+                Some("192.0.2.1"),
+            ),
+            (
+                b"X-Forwarded-For: [2001:db8::1]:80\nForwarded: for=_hidden\n",
+                Some("2001:db8::1"),
+            ),
+            (b"X-Forwarded-For: 2001:db8::2\n", Some("2001:db8::2")),
+            (b"X-Forwarded-For: garbage\n", None),
+            (b"User-Agent: x\n", None),
+        ] {
+            let headers = HttpHeaders::parse(block);
+            assert_eq!(headers.client_ip(), ip.map(|ip| ip.parse().unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_parse_bad_headers() {
+        let headers =
+            HttpHeaders::parse(b"\r\n\tfolded\r\nno colon\r\n: empty\r\nUser-Agent:\xff x\r\n");
+        assert_eq!(headers.user_agent.as_deref(), Some("\u{fffd} x"));
+        assert_eq!(HttpHeaders::parse(b""), HttpHeaders::default());
+    }
+}
@@ -0,0 +1,135 @@
+use std::borrow::Cow;
+use std::ops::Range;
+
+/// User-Agent given to the detectors, whose regexes scan its bytes
+pub trait UaInput {
+    /// Bytes scanned by the regexes, capture ranges index them
+    fn bytes(&self) -> &[u8];
+    /// The User-Agent decoded to UTF-8
+    fn text(&self) -> &str;
+    /// Charset `text` was decoded from
+    fn charset(&self) -> &str;
+    /// Decode the bytes of a capture, `None` when out of bounds
+    fn capture(&self, range: Range<usize>) -> Option<Cow<'_, str>>;
+}
+
+impl<T: AsRef<str> + ?Sized> UaInput for T {
+    fn bytes(&self) -> &[u8] {
+        self.as_ref().as_bytes()
+    }
+
+    fn text(&self) -> &str {
+        self.as_ref()
+    }
+
+    fn charset(&self) -> &str {
+        "utf-8"
+    }
+
+    fn capture(&self, range: Range<usize>) -> Option<Cow<'_, str>> {
+        self.as_ref().get(range).map(Cow::Borrowed)
+    }
+}
+
+/// Legacy charset of a User-Agent which isn't UTF-8
+#[derive(Debug, Clone, Copy)]
+enum Charset {
+    /// Fallback mapping every byte to the same code point
+    Latin1,
+    #[cfg(feature = "charset")]
+    Detected(&'static encoding_rs::Encoding),
+}
+
+impl Charset {
+    #[cfg(not(feature = "charset"))]
+    fn detect(_bytes: &[u8]) -> Charset {
+        Charset::Latin1
+    }
+
+    /// Guess the charset with uchardet, falling back to Latin-1
+    #[cfg(feature = "charset")]
+    fn detect(bytes: &[u8]) -> Charset {
+        uchardet::detect_encoding_name(bytes)
+            .ok()
+            .and_then(|name| encoding_rs::Encoding::for_label(name.as_bytes()))
+            .map_or(Charset::Latin1, Charset::Detected)
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Charset::Latin1 => "iso-8859-1",
+            #[cfg(feature = "charset")]
+            Charset::Detected(encoding) => encoding.name(),
+        }
+    }
+
+    fn decode(&self, bytes: &[u8]) -> String {
+        match self {
+            Charset::Latin1 => bytes.iter().map(|b| char::from(*b)).collect(),
+            #[cfg(feature = "charset")]
+            Charset::Detected(encoding) => {
+                encoding.decode_without_bom_handling(bytes).0.into_owned()
+            }
+        }
+    }
+}
+
+/// User-Agent in a legacy charset, matched on its raw bytes
+#[derive(Debug, Clone)]
+pub struct RawUa<'a> {
+    bytes: &'a [u8],
+    charset: Charset,
+    text: String,
+}
+
+impl<'a> RawUa<'a> {
+    pub fn new(bytes: &'a [u8]) -> RawUa<'a> {
+        let charset = Charset::detect(bytes);
+        RawUa {
+            bytes,
+            charset,
+            text: charset.decode(bytes),
+        }
+    }
+}
+
+impl UaInput for RawUa<'_> {
+    fn bytes(&self) -> &[u8] {
+        self.bytes
+    }
+
+    fn text(&self) -> &str {
+        &self.text
+    }
+
+    fn charset(&self) -> &str {
+        self.charset.name()
+    }
+
+    fn capture(&self, range: Range<usize>) -> Option<Cow<'_, str>> {
+        let bytes = self.bytes.get(range)?;
+        Some(Cow::Owned(self.charset.decode(bytes)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture() {
+        let ua = "Mozilla/5.0 (Linux; Android 4.0; 小米 Build/IMM76D)";
+        assert_eq!(ua.capture(33..39).as_deref(), Some("小米"));
+        assert_eq!(ua.capture(33..35), None);
+        assert_eq!(ua.capture(33..100), None);
+        assert_eq!(ua.charset(), "utf-8");
+
+        #[cfg(not(feature = "charset"))]
+        {
+            let raw = RawUa::new(b"Nokia/1.0 (Caf\xe9)");
+            assert_eq!(raw.text(), "Nokia/1.0 (Caf\u{e9})");
+            assert_eq!(raw.charset(), "iso-8859-1");
+            assert_eq!(raw.capture(11..15).as_deref(), Some("Caf\u{e9}"));
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// Upper bounds of the latency histogram buckets, in microseconds
+const LATENCY_BUCKETS: [u64; 12] = [
+    5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 100_000,
+];
+
+/// Timed stages of a parse
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Detector {
+    /// The `SQL_CRAWLER` lookup
+    Crawler,
+    Client,
+    Os,
+    DeviceClass,
+    DeviceBrand,
+}
+
+impl Detector {
+    const ALL: [Detector; 5] = [
+        Detector::Crawler,
+        Detector::Client,
+        Detector::Os,
+        Detector::DeviceClass,
+        Detector::DeviceBrand,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Detector::Crawler => "crawler",
+            Detector::Client => "client",
+            Detector::Os => "os",
+            Detector::DeviceClass => "device_class",
+            Detector::DeviceBrand => "device_brand",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Non-cumulative counts, the last bucket being `+Inf`
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let micros = elapsed.as_micros();
+        let i = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| micros <= u128::from(*bound))
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Counters of the parses of an `Udger` and its `UdgerData`, updated with
+/// relaxed atomics so that they can be left on
+#[derive(Debug, Default)]
+pub struct Metrics {
+    parses: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    cache_evictions: AtomicU64,
+    sql_errors: AtomicU64,
+    unrecognized: AtomicU64,
+    latency: [Histogram; Detector::ALL.len()],
+}
+
+impl Metrics {
+    fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_parse(&self, unrecognized: bool) {
+        Metrics::inc(&self.parses);
+        if unrecognized {
+            Metrics::inc(&self.unrecognized);
+        }
+    }
+
+    pub(crate) fn count_cache(&self, hit: bool) {
+        Metrics::inc(if hit {
+            &self.cache_hits
+        } else {
+            &self.cache_misses
+        });
+    }
+
+    pub(crate) fn count_eviction(&self) {
+        Metrics::inc(&self.cache_evictions);
+    }
+
+    pub(crate) fn count_error(&self, err: &anyhow::Error) {
+        if err.is::<rusqlite::Error>() {
+            Metrics::inc(&self.sql_errors);
+        }
+    }
+
+    /// Run a detector, recording its latency, in a span with the `tracing` feature
+    pub(crate) fn time<R>(&self, detector: Detector, detect: impl FnOnce() -> R) -> R {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("detector", name = detector.name()).entered();
+        let started = Instant::now();
+        let result = detect();
+        self.latency[detector as usize].observe(started.elapsed());
+        result
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        MetricsSnapshot {
+            parses: load(&self.parses),
+            cache_hits: load(&self.cache_hits),
+            cache_misses: load(&self.cache_misses),
+            cache_evictions: load(&self.cache_evictions),
+            sql_errors: load(&self.sql_errors),
+            unrecognized: load(&self.unrecognized),
+            latency: Detector::ALL
+                .iter()
+                .zip(self.latency.iter())
+                .map(|(detector, histogram)| {
+                    let mut cumulative = 0;
+                    LatencySnapshot {
+                        detector: detector.name(),
+                        count: load(&histogram.count),
+                        sum_seconds: load(&histogram.sum_nanos) as f64 / 1e9,
+                        buckets: LATENCY_BUCKETS
+                            .iter()
+                            .zip(histogram.buckets.iter())
+                            .map(|(bound, count)| {
+                                cumulative += load(count);
+                                (*bound as f64 / 1e6, cumulative)
+                            })
+                            .collect(),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Latency histogram of a detector
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LatencySnapshot {
+    pub detector: &'static str,
+    pub count: u64,
+    pub sum_seconds: f64,
+    /// Upper bound in seconds and cumulative count, `count` being the `+Inf` one
+    pub buckets: Vec<(f64, u64)>,
+}
+
+/// Point-in-time copy of `Metrics`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MetricsSnapshot {
+    pub parses: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub cache_evictions: u64,
+    pub sql_errors: u64,
+    /// Parses whose client is unrecognized
+    pub unrecognized: u64,
+    pub latency: Vec<LatencySnapshot>,
+}
+
+impl MetricsSnapshot {
+    pub fn unrecognized_ratio(&self) -> f64 {
+        match self.parses {
+            0 => 0.0,
+            parses => self.unrecognized as f64 / parses as f64,
+        }
+    }
+
+    pub fn cache_hit_ratio(&self) -> f64 {
+        match self.cache_hits + self.cache_misses {
+            0 => 0.0,
+            lookups => self.cache_hits as f64 / lookups as f64,
+        }
+    }
+
+    /// Prometheus text exposition format
+    pub fn prometheus(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("udger_parses_total", "User-Agents parsed.", self.parses),
+            (
+                "udger_cache_hits_total",
+                "Parses answered from the cache.",
+                self.cache_hits,
+            ),
+            (
+                "udger_cache_misses_total",
+                "Cache lookups which missed.",
+                self.cache_misses,
+            ),
+            (
+                "udger_cache_evictions_total",
+                "Cached results evicted to make room.",
+                self.cache_evictions,
+            ),
+            (
+                "udger_sql_errors_total",
+                "Parses which failed on an SQL error.",
+                self.sql_errors,
+            ),
+            (
+                "udger_unrecognized_total",
+                "Parses whose client is unrecognized.",
+                self.unrecognized,
+            ),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        let _ = writeln!(
+            out,
+            "# HELP udger_unrecognized_ratio Share of the parses whose client is unrecognized."
+        );
+        let _ = writeln!(out, "# TYPE udger_unrecognized_ratio gauge");
+        let _ = writeln!(
+            out,
+            "udger_unrecognized_ratio {}",
+            self.unrecognized_ratio()
+        );
+
+        let name = "udger_detector_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Latency of the detectors.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for latency in &self.latency {
+            for (bound, count) in &latency.buckets {
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{detector=\"{}\",le=\"{}\"}} {}",
+                    name, latency.detector, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{{detector=\"{}\",le=\"+Inf\"}} {}",
+                name, latency.detector, latency.count
+            );
+            let _ = writeln!(
+                out,
+                "{}_sum{{detector=\"{}\"}} {}",
+                name, latency.detector, latency.sum_seconds
+            );
+            let _ = writeln!(
+                out,
+                "{}_count{{detector=\"{}\"}} {}",
+                name, latency.detector, latency.count
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let metrics = Metrics::default();
+        metrics.count_parse(false);
+        metrics.count_parse(true);
+        metrics.count_cache(true);
+        metrics.count_cache(false);
+        metrics.count_cache(false);
+        metrics.count_eviction();
+        metrics.count_error(&anyhow::anyhow!(rusqlite::Error::QueryReturnedNoRows));
+        metrics.count_error(&anyhow::anyhow!("not an SQL error"));
+        assert_eq!(metrics.time(Detector::Os, || 42), 42);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.parses, 2);
+        assert_eq!(snapshot.unrecognized_ratio(), 0.5);
+        assert_eq!((snapshot.cache_hits, snapshot.cache_misses), (1, 2));
+        assert_eq!(snapshot.cache_evictions, 1);
+        assert_eq!(snapshot.sql_errors, 1);
+        let os = &snapshot.latency[Detector::Os as usize];
+        assert_eq!((os.detector, os.count), ("os", 1));
+        assert_eq!(os.buckets.last().unwrap().1, 1);
+        assert_eq!(snapshot.latency[Detector::Client as usize].count, 0);
+
+        let text = snapshot.prometheus();
+        assert!(text.contains("udger_parses_total 2\n"));
+        assert!(text.contains("udger_unrecognized_ratio 0.5\n"));
+        assert!(text
+            .contains("udger_detector_duration_seconds_bucket{detector=\"os\",le=\"+Inf\"} 1\n"));
+        assert!(text.contains("udger_detector_duration_seconds_count{detector=\"client\"} 0\n"));
+    }
+}
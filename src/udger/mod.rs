@@ -1,24 +1,119 @@
 use std::collections::{HashMap, HashSet};
 use std::num::NonZeroUsize;
+use std::ops::Range;
 use std::path::PathBuf;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
 use hyperscan::prelude::{Pattern, Patterns};
 use hyperscan::{ExprExt, PatternFlags};
 use regex::Regex;
-use rusqlite::{params, Connection, Error};
+use rusqlite::{params, Connection, Error, Row};
 
+use crate::tokens::UaTokens;
 use crate::UaInfo;
 
+mod anomaly;
+mod darwin;
+mod explain;
+mod headers;
+mod input;
+mod metrics;
+mod normalize;
+mod platform;
 mod regex_sequence;
 mod sql;
+mod trace;
+mod version;
 mod word_detector;
 
-use self::regex_sequence::{RegexSequence, RegexSequenceScratch};
+pub use self::anomaly::Anomaly;
+use self::darwin::DarwinVersions;
+pub use self::explain::Explanation;
+pub use self::headers::{HeadersInfo, HttpHeaders};
+pub use self::input::{RawUa, UaInput};
+use self::metrics::Detector;
+pub use self::metrics::{LatencySnapshot, Metrics, MetricsSnapshot};
+pub use self::normalize::{Normalization, Rejected};
+pub use self::regex_sequence::{Candidate, Limits, RegexTrace};
+use self::regex_sequence::{RegexSequence, RegexSequenceScratch, Terminated};
+use self::trace::InitStep;
+use self::version::{engine_version, find_os_version, os_version, version_minor};
 use self::word_detector::{WordDetector, WordDetectorScratch};
 
 const UNRECOGNIZED: &str = "unrecognized";
+const OS_MATCHED: &str = "matched";
+const OS_INFERRED: &str = "inferred";
+const OS_DARWIN: &str = "darwin";
+const DEVICE_RULE_OS: &str = "os";
+const DEVICE_RULE_OS_FAMILY: &str = "os_family";
+const DEVICE_RULE_DEGRADED: &str = "degraded";
+
+/// The User-Agent as given, decoded like the detectors decode it
+fn original_text(ua: &[u8]) -> String {
+    match std::str::from_utf8(ua) {
+        Ok(ua) => String::from(ua),
+        Err(_) => RawUa::new(ua).text().to_string(),
+    }
+}
+
+/// Read the `OS_COLUMNS` of a row, starting at column `first`
+fn read_os_columns(row: &Row, first: usize, info: &mut UaInfo) -> rusqlite::Result<()> {
+    info.os_family = row.get(first)?;
+    info.os_family_code = row.get(first + 1)?;
+    info.os = row.get(first + 2)?;
+    info.os_code = row.get(first + 3)?;
+    #[cfg(feature = "homepage")]
+    {
+        info.os_homepage = row.get(first + 4)?;
+        info.os_family_vendor_homepage = row.get(first + 9)?;
+    }
+    #[cfg(feature = "icon")]
+    {
+        info.os_icon = row.get(first + 5)?;
+        info.os_icon_big = row.get(first + 6)?;
+    }
+    info.os_family_vendor = row.get(first + 7)?;
+    info.os_family_vendor_code = row.get(first + 8)?;
+    #[cfg(feature = "url")]
+    {
+        info.os_info_url = row.get(first + 10)?;
+    }
+    Ok(())
+}
+
+/// Internals exercised by the fuzz targets
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing {
+    pub use super::regex_sequence::{RegexSequence, RegexSequenceScratch};
+    pub use super::word_detector::{WordDetector, WordDetectorScratch};
+    use super::Udger;
+
+    impl Udger {
+        pub fn word_detectors(&self) -> Vec<&WordDetector> {
+            vec![
+                #[cfg(feature = "application")]
+                &self.application_words_detector,
+                &self.client_words_detector,
+                &self.device_class_words_detector,
+                &self.os_words_detector,
+            ]
+        }
+
+        pub fn regex_sequences(&self) -> Vec<&RegexSequence> {
+            vec![
+                #[cfg(feature = "application")]
+                &self.application_regexes,
+                &self.client_regexes,
+                &self.device_class_regexes,
+                &self.device_name_regexes,
+                &self.os_regexes,
+            ]
+        }
+    }
+}
 
 pub struct UdgerData {
     conn: Connection,
@@ -36,24 +131,44 @@ pub struct UdgerData {
     pub os_regex_scratch: RegexSequenceScratch,
 
     cache: clru::CLruCache<String, Rc<UaInfo>>,
+    metrics: Arc<Metrics>,
+    /// Trace of the running `parse_ua_explain`
+    explanation: Option<Explanation>,
 }
 
 impl UdgerData {
     #[inline]
     pub fn get(&mut self, ua: &str) -> Option<Rc<UaInfo>> {
-        self.cache.get(ua).cloned()
+        let cached = self.cache.get(ua).cloned();
+        self.metrics.count_cache(cached.is_some());
+        cached
     }
 
     #[inline]
     pub fn set(&mut self, ua: &str, info: Rc<UaInfo>) {
+        if self.cache.is_full() && !self.cache.contains(ua) {
+            self.metrics.count_eviction();
+        }
         self.cache.put(ua.to_string(), info);
     }
+
+    fn regex_scratches(&mut self) -> Vec<&mut RegexSequenceScratch> {
+        vec![
+            #[cfg(feature = "application")]
+            &mut self.app_regex_scratch,
+            &mut self.client_regex_scratch,
+            &mut self.device_class_regex_scratch,
+            &mut self.device_name_regex_scratch,
+            &mut self.os_regex_scratch,
+        ]
+    }
 }
 
 #[derive(Default)]
 pub struct Udger {
     capacity: usize,
     db_fpath: PathBuf,
+    db_version: String,
 
     #[cfg(feature = "application")]
     application_words_detector: WordDetector,
@@ -70,6 +185,13 @@ pub struct Udger {
 
     /// include all os_codes and os_family_codes
     os_codes: HashMap<String, usize>,
+    /// word ids of the os_family_codes only
+    os_family_word_ids: Vec<u16>,
+
+    darwin_versions: DarwinVersions,
+    normalization: Normalization,
+    time_budget: Option<Duration>,
+    metrics: Arc<Metrics>,
 }
 
 impl Udger {
@@ -86,19 +208,30 @@ impl Udger {
         udger.device_name_regexes.name = String::from("device_name_regexes");
         udger.device_name_regexes.need_capture = true;
         udger.os_regexes.name = String::from("os_regexes");
+        udger.os_regexes.need_capture = true;
         #[cfg(feature = "application")]
         {
             udger.application_words_detector.name = String::from("application_words_detector");
             udger.application_regexes.name = String::from("application_regexes");
         }
+        udger.set_limits(Limits::default());
         udger
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "udger_init", skip_all, fields(db = %db_fpath.display(), capacity))
+    )]
     pub fn init(&mut self, db_fpath: PathBuf, capacity: usize) -> Result<()> {
         self.capacity = capacity;
         self.db_fpath = db_fpath;
 
         let conn = Connection::open(&self.db_fpath)?;
+        self.db_version = conn
+            .query_row("SELECT version FROM udger_db_info", params![], |row| {
+                row.get(0)
+            })
+            .unwrap_or_default();
 
         #[cfg(feature = "application")]
         {
@@ -125,6 +258,7 @@ impl Udger {
 
         #[cfg(feature = "application")]
         {
+            self.application_regexes.need_capture = true;
             Udger::init_regex_sequence(
                 &mut self.application_regexes,
                 "udger_application_regex",
@@ -174,11 +308,17 @@ impl Udger {
         Ok(())
     }
 
+    /// Version of the loaded udger database, empty if unknown
+    pub fn db_version(&self) -> &str {
+        &self.db_version
+    }
+
     fn init_word_detector(
         detector: &mut WordDetector,
         table: &str,
         conn: &Connection,
     ) -> Result<()> {
+        let load = InitStep::load(table);
         let mut stmt = conn.prepare(&format!("SELECT id, word, count FROM {}", table))?;
         let mut rows = stmt.query(params![])?;
         let mut words = Vec::new();
@@ -199,8 +339,12 @@ impl Udger {
             ids.push(id as u16);
             counts.push(count as u16);
         }
+        load.rows(ids.len());
+        load.finish();
 
+        let compile = InitStep::compile(table, ids.len());
         detector.init(ids.iter(), Patterns::from(words), counts.iter())?;
+        compile.finish();
         Ok(())
     }
 
@@ -212,6 +356,7 @@ impl Udger {
         column2: &str,
         conn: &Connection,
     ) -> Result<()> {
+        let load = InitStep::load(table);
         let mut stmt = conn.prepare(&format!(
             "SELECT rowid, {}, regstring, sequence, {}, {} FROM {} ORDER BY sequence;",
             id_column_name, column1, column2, table
@@ -250,7 +395,10 @@ impl Udger {
             word1s.push(row.4 as u16);
             word2s.push(row.5 as u16);
         }
+        load.rows(rowids.len());
+        load.finish();
 
+        let compile = InitStep::compile(table, regexes.len());
         seq.init(
             rowids.iter(),
             ids.iter(),
@@ -259,6 +407,7 @@ impl Udger {
             word1s.iter(),
             word2s.iter(),
         )?;
+        compile.finish();
 
         Ok(())
     }
@@ -276,6 +425,7 @@ impl Udger {
         column2: &str,
         conn: &Connection,
     ) -> Result<()> {
+        let load = InitStep::load(table);
         let mut stmt = conn.prepare(&format!(
             "SELECT rowid, {}, regstring, sequence, {}, {} FROM {} ORDER BY sequence;",
             id_column_name, column1, column2, table
@@ -317,26 +467,35 @@ impl Udger {
                 code_set.insert(row.5.clone());
             }
         }
+        load.rows(rowids.len());
+        load.finish();
         // now convert all os_codes and os_family_codes to "word_id"
         for (i, code) in code_set.iter().enumerate() {
             self.os_codes.insert(code.clone(), i + 1);
         }
         let range1 = os_family_codes
             .iter()
-            .map(|code| *self.os_codes.get(code).unwrap() as u16)
+            .map(|code| self.os_codes.get(code).map_or(0, |id| *id as u16))
             .collect::<Vec<u16>>();
+        self.os_family_word_ids = range1
+            .iter()
+            .copied()
+            .collect::<HashSet<u16>>()
+            .into_iter()
+            .collect();
         let range2 = os_codes
             .iter()
             .map(|code| {
                 // if code is "-all-" return 0 which would be filtered then
                 if code != "-all-" {
-                    *self.os_codes.get(code).unwrap() as u16
+                    self.os_codes.get(code).map_or(0, |id| *id as u16)
                 } else {
                     0
                 }
             })
             .collect::<Vec<u16>>();
 
+        let compile = InitStep::compile(table, regexes.len());
         self.device_name_regexes.init(
             rowids.iter(),
             ids.iter(),
@@ -345,10 +504,56 @@ impl Udger {
             range1.iter(),
             range2.iter(),
         )?;
+        compile.finish();
 
         Ok(())
     }
 
+    /// Load CFNetwork and Darwin versions of native app User-Agents over the
+    /// built-in ones, see `src/udger/darwin.txt` for the format
+    pub fn load_darwin_versions(&mut self, path: PathBuf) -> Result<()> {
+        self.darwin_versions.load(&path)
+    }
+
+    /// Set the bounds of the regex evaluation, the match and recursion limits
+    /// apply to the databases built by a later `init`
+    pub fn set_limits(&mut self, limits: Limits) {
+        let pcre = Some((limits.match_limit, limits.match_limit_recursion));
+        #[cfg(feature = "application")]
+        {
+            self.application_regexes.limits = pcre;
+        }
+        self.client_regexes.limits = pcre;
+        self.device_class_regexes.limits = pcre;
+        self.device_name_regexes.limits = pcre;
+        self.os_regexes.limits = pcre;
+        self.time_budget = limits.time_budget;
+    }
+
+    /// Leave the match and recursion limits of the databases built by a later
+    /// `init` to chimera, and drop the time budget
+    pub fn clear_limits(&mut self) {
+        #[cfg(feature = "application")]
+        {
+            self.application_regexes.limits = None;
+        }
+        self.client_regexes.limits = None;
+        self.device_class_regexes.limits = None;
+        self.device_name_regexes.limits = None;
+        self.os_regexes.limits = None;
+        self.time_budget = None;
+    }
+
+    /// Counters of the parses, shared by the `UdgerData` it allocates
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Set the pre-processing of the User-Agents, see `Normalization`
+    pub fn set_normalization(&mut self, normalization: Normalization) {
+        self.normalization = normalization;
+    }
+
     pub fn alloc_udger_data(&self) -> Result<UdgerData> {
         let capacity = NonZeroUsize::new(self.capacity).ok_or_else(|| {
             anyhow!("LRU cache size is zero, please make sure it's greater than zero")
@@ -368,52 +573,62 @@ impl Udger {
             device_name_regex_scratch: self.device_name_regexes.alloc_scratch()?,
             os_regex_scratch: self.os_regexes.alloc_scratch()?,
             cache: clru::CLruCache::new(capacity),
+            metrics: self.metrics.clone(),
+            explanation: None,
         })
     }
 
     fn detect_client<T>(&self, ua: &T, data: &mut UdgerData, info: &mut UaInfo) -> Result<()>
     where
-        T: AsRef<str>,
+        T: UaInput + ?Sized,
     {
-        let stmt = &mut data.conn.prepare(sql::SQL_CRAWLER)?;
-
         // If any rows are returned, is classified as crawler
         // If error is not QueryReturnedNoRows, return the original error
         // Otherwise continue
-        match stmt.query_row(params![ua.as_ref()], |row| {
-            info.class_id = None;
-            info.client_id = None;
-            info.ua_class = row.get(2)?;
-            info.ua_class_code = row.get(3)?;
-            info.ua = row.get(4)?;
-            info.ua_engine = row.get(5).unwrap_or_default();
-            info.ua_version = row.get(6)?;
-            info.ua_version_major = row.get(7)?;
-            info.crawler_last_seen = row.get(8)?;
-            info.crawler_respect_robotstxt = row.get(9)?;
-            info.crawler_category = row.get(10)?;
-            info.crawler_category_code = row.get(11)?;
-            info.ua_uptodate_current_version = row.get(12).unwrap_or_default();
-            info.ua_family = row.get(13)?;
-            info.ua_family_code = row.get(14)?;
-            #[cfg(feature = "homepage")]
-            {
-                info.ua_family_homepage = row.get(15)?;
-                info.ua_family_vendor_homepage = row.get(20)?;
-            }
-            #[cfg(feature = "icon")]
-            {
-                info.ua_family_icon = row.get(16)?;
-                info.ua_family_icon_big = row.get(17)?;
-            }
-            info.ua_family_vendor = row.get(18)?;
-            info.ua_family_vendor_code = row.get(19)?;
-            #[cfg(feature = "url")]
-            {
-                info.ua_family_info_url = row.get(21)?;
-            }
-            Ok(())
-        }) {
+        let crawler = self.metrics.time(Detector::Crawler, || {
+            let stmt = &mut data.conn.prepare(sql::SQL_CRAWLER)?;
+            stmt.query_row(params![ua.text()], |row| {
+                info.class_id = None;
+                info.client_id = None;
+                info.ua_class = row.get(2)?;
+                info.ua_class_code = row.get(3)?;
+                info.ua = row.get(4)?;
+                info.ua_engine = row.get(5).unwrap_or_default();
+                info.ua_version = row.get(6)?;
+                info.ua_version_major = row.get(7)?;
+                info.ua_version_minor = version_minor(&info.ua_version);
+                info.crawler_last_seen = row.get(8)?;
+                info.crawler_respect_robotstxt = row.get(9)?;
+                info.crawler_category = row.get(10)?;
+                info.crawler_category_code = row.get(11)?;
+                info.ua_uptodate_current_version = row.get(12).unwrap_or_default();
+                info.ua_family = row.get(13)?;
+                info.ua_family_code = row.get(14)?;
+                #[cfg(feature = "homepage")]
+                {
+                    info.ua_family_homepage = row.get(15)?;
+                    info.ua_family_vendor_homepage = row.get(20)?;
+                }
+                #[cfg(feature = "icon")]
+                {
+                    info.ua_family_icon = row.get(16)?;
+                    info.ua_family_icon_big = row.get(17)?;
+                }
+                info.ua_family_vendor = row.get(18)?;
+                info.ua_family_vendor_code = row.get(19)?;
+                #[cfg(feature = "url")]
+                {
+                    info.ua_family_info_url = row.get(21)?;
+                }
+                Ok(())
+            })
+        });
+        #[cfg(feature = "tracing")]
+        tracing::debug!(matched = crawler.is_ok(), "crawler lookup");
+        if let Some(explanation) = &mut data.explanation {
+            explanation.crawler = crawler.is_ok();
+        }
+        match crawler {
             Err(err) => {
                 match err {
                     Error::QueryReturnedNoRows => {}
@@ -423,22 +638,38 @@ impl Udger {
             Ok(_) => return Ok(()),
         };
 
+        self.metrics
+            .time(Detector::Client, || self.match_client(ua, data, info))
+    }
+
+    /// Match the client regexes, for the User-Agents which aren't crawlers
+    fn match_client<T>(&self, ua: &T, data: &mut UdgerData, info: &mut UaInfo) -> Result<()>
+    where
+        T: UaInput + ?Sized,
+    {
         let word_ids = self
             .client_words_detector
-            .get_word_ids(&ua.as_ref(), &mut data.client_word_scratch)?;
+            .get_word_ids(&ua.bytes(), &mut data.client_word_scratch)?;
 
-        let (row_id, range) = match self.client_regexes.get_row_id_and_capture(
-            &ua.as_ref(),
+        let (row_id, range) = match self.client_regexes.get_row_id_and_capture_traced(
+            &ua.bytes(),
             &mut data.client_regex_scratch,
             &word_ids.iter(),
+            data.explanation
+                .as_mut()
+                .map(|explanation| &mut explanation.client),
         )? {
             None => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(?word_ids, "no client regex matched");
                 info.ua_class = String::from(UNRECOGNIZED);
                 info.ua_class_code = String::from(UNRECOGNIZED);
                 return Ok(());
             }
             Some(v) => v,
         };
+        #[cfg(feature = "tracing")]
+        tracing::debug!(?word_ids, row_id, ?range, "client regex matched");
 
         let stmt = &mut data.conn.prepare(sql::SQL_CLIENT)?;
         if let Err(err) = stmt.query_row(params![row_id], |row| {
@@ -460,12 +691,12 @@ impl Udger {
             #[cfg(feature = "homepage")]
             {
                 info.ua_family_homepage = row.get(16)?;
+                info.ua_family_vendor_homepage = row.get(21)?;
             }
             #[cfg(feature = "icon")]
             {
                 info.ua_family_icon = row.get(17)?;
                 info.ua_family_icon_big = row.get(18)?;
-                info.ua_family_vendor_homepage = row.get(21)?;
             }
             info.ua_family_vendor = row.get(19)?;
             info.ua_family_vendor_code = row.get(20)?;
@@ -484,56 +715,85 @@ impl Udger {
         match range {
             None => {}
             Some(range) => {
-                info.ua_version = (ua.as_ref()[range]).to_string();
+                if let Some(explanation) = &mut data.explanation {
+                    explanation.client_version_capture = Some(range.clone());
+                }
+                info.ua_version = ua.capture(range).unwrap_or_default().into_owned();
                 info.ua.extend([' '].iter());
                 info.ua.push_str(&info.ua_version);
-                let split: Vec<&str> = info.ua_version.split('.').collect();
-                info.ua_version_major = split[0].to_string();
+                info.ua_version_major = info
+                    .ua_version
+                    .split('.')
+                    .next()
+                    .unwrap_or_default()
+                    .to_string();
+                info.ua_version_minor = version_minor(&info.ua_version);
             }
         };
 
+        if let Some(version) = engine_version(ua.text(), &info.ua_engine) {
+            info.ua_engine_version = version.to_string();
+            info.ua_engine_version_major =
+                version.split('.').next().unwrap_or_default().to_string();
+        }
+
         Ok(())
     }
 
     fn detect_os<T>(&self, ua: &T, data: &mut UdgerData, info: &mut UaInfo) -> Result<()>
     where
-        T: AsRef<str>,
+        T: UaInput + ?Sized,
     {
         let word_ids = self
             .os_words_detector
-            .get_word_ids(&ua.as_ref(), &mut data.os_word_scratch)?;
+            .get_word_ids(&ua.bytes(), &mut data.os_word_scratch)?;
 
-        let (row_id, _) = match self.os_regexes.get_row_id_and_capture(
-            &ua.as_ref(),
+        let (row_id, capture) = match self.os_regexes.get_row_id_and_capture_traced(
+            &ua.bytes(),
             &mut data.os_regex_scratch,
             &word_ids.iter(),
+            data.explanation
+                .as_mut()
+                .map(|explanation| &mut explanation.os),
         )? {
-            None => return Ok(()),
+            None => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(?word_ids, "no OS regex matched");
+                if self.detect_darwin_os(ua.text(), data, info)? {
+                    return Ok(());
+                }
+                // clients available on a single OS tell it
+                match info.client_id {
+                    None => {}
+                    Some(client_id) => {
+                        let stmt = &mut data.conn.prepare(&sql::SQL_CLIENT_OS)?;
+                        if let Err(err) = stmt.query_row(params![client_id], |row| {
+                            read_os_columns(row, 0, info)?;
+                            info.os_source = String::from(OS_INFERRED);
+                            Ok(())
+                        }) {
+                            match err {
+                                Error::QueryReturnedNoRows => {}
+                                _ => return Err(anyhow!(err)),
+                            };
+                        };
+                        Udger::set_os_version(ua, None, info);
+                    }
+                };
+                return Ok(());
+            }
             Some(rid) => rid,
         };
+        #[cfg(feature = "tracing")]
+        tracing::debug!(?word_ids, row_id, ?capture, "OS regex matched");
+        if let Some(explanation) = &mut data.explanation {
+            explanation.os_version_capture = capture.clone();
+        }
 
         let stmt = &mut data.conn.prepare(&sql::SQL_OS)?;
         if let Err(err) = stmt.query_row(params![row_id], |row| {
-            info.os_family = row.get(1)?;
-            info.os_family_code = row.get(2)?;
-            info.os = row.get(3)?;
-            info.os_code = row.get(4)?;
-            #[cfg(feature = "homepage")]
-            {
-                info.os_homepage = row.get(5)?;
-                info.os_family_vendor_homepage = row.get(10)?;
-            }
-            #[cfg(feature = "icon")]
-            {
-                info.os_icon = row.get(6)?;
-                info.os_icon_big = row.get(7)?;
-            }
-            info.os_family_vendor = row.get(8)?;
-            info.os_family_vendor_code = row.get(9)?;
-            #[cfg(feature = "url")]
-            {
-                info.os_info_url = row.get(11)?;
-            }
+            read_os_columns(row, 1, info)?;
+            info.os_source = String::from(OS_MATCHED);
             Ok(())
         }) {
             match err {
@@ -541,24 +801,79 @@ impl Udger {
                 _ => return Err(anyhow!(err)),
             };
         };
+        Udger::set_os_version(ua, capture, info);
 
         Ok(())
     }
 
+    /// Map the CFNetwork or Darwin version of a native app to an iOS or macOS release,
+    /// returns whether one was found
+    fn detect_darwin_os(&self, ua: &str, data: &mut UdgerData, info: &mut UaInfo) -> Result<bool> {
+        let release = match self.darwin_versions.find(ua) {
+            None => return Ok(false),
+            Some(release) => release,
+        };
+
+        // a release of an OS missing from the database tells nothing
+        let stmt = &mut data.conn.prepare(&sql::SQL_OS_CODE)?;
+        match stmt.query_row(params![release.os_code], |row| {
+            read_os_columns(row, 0, info)
+        }) {
+            Ok(_) => {}
+            Err(Error::QueryReturnedNoRows) => return Ok(false),
+            Err(err) => return Err(anyhow!(err)),
+        };
+        info.os_source = String::from(OS_DARWIN);
+        info.os_version = release.os_version.clone();
+        info.os_version_major = release
+            .os_version
+            .split('.')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        info.os_version_minor = version_minor(&release.os_version);
+        Ok(true)
+    }
+
+    /// Set the OS version from the capture of the OS regex, or from the version
+    /// token of the OS family
+    fn set_os_version<T>(ua: &T, capture: Option<Range<usize>>, info: &mut UaInfo)
+    where
+        T: UaInput + ?Sized,
+    {
+        if info.os_family_code.is_empty() {
+            return;
+        }
+        let version = capture
+            .and_then(|range| ua.capture(range))
+            .and_then(|text| os_version(&text))
+            .or_else(|| find_os_version(ua.text(), &info.os_family_code));
+        if let Some(version) = version {
+            info.os_version_major = version.split('.').next().unwrap_or_default().to_string();
+            info.os_version_minor = version_minor(&version);
+            info.os_version = version;
+        }
+    }
+
     fn detect_device_class<T>(&self, ua: &T, data: &mut UdgerData, info: &mut UaInfo) -> Result<()>
     where
-        T: AsRef<str>,
+        T: UaInput + ?Sized,
     {
         let word_ids = self
             .device_class_words_detector
-            .get_word_ids(&ua.as_ref(), &mut data.device_word_scratch)?;
+            .get_word_ids(&ua.bytes(), &mut data.device_word_scratch)?;
 
-        let (row_id, _) = match self.device_class_regexes.get_row_id_and_capture(
-            &ua.as_ref(),
+        let (row_id, _) = match self.device_class_regexes.get_row_id_and_capture_traced(
+            &ua.bytes(),
             &mut data.device_class_regex_scratch,
             &word_ids.iter(),
+            data.explanation
+                .as_mut()
+                .map(|explanation| &mut explanation.device_class),
         )? {
             None => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(?word_ids, class_id = ?info.class_id, "no device class regex matched");
                 match info.class_id {
                     None => {}
                     Some(class_id) => {
@@ -582,12 +897,22 @@ impl Udger {
                                 _ => return Err(anyhow!(err)),
                             };
                         };
+                        if let Some(explanation) = &mut data.explanation {
+                            if !info.device_class_code.is_empty() {
+                                explanation.device_class_source = explain::DEVICE_CLASS_CLIENT;
+                            }
+                        }
                     }
                 };
                 return Ok(());
             }
             Some(rid) => rid,
         };
+        #[cfg(feature = "tracing")]
+        tracing::debug!(?word_ids, row_id, "device class regex matched");
+        if let Some(explanation) = &mut data.explanation {
+            explanation.device_class_source = explain::DEVICE_CLASS_REGEX;
+        }
 
         let stmt = &mut data.conn.prepare(&sql::SQL_DEVICE)?;
         if let Err(err) = stmt.query_row(params![row_id], |row| {
@@ -615,54 +940,86 @@ impl Udger {
 
     fn detect_device_brand<T>(&self, ua: &T, data: &mut UdgerData, info: &mut UaInfo) -> Result<()>
     where
-        T: AsRef<str>,
+        T: UaInput + ?Sized,
     {
-        if info.os_family_code.is_empty() || info.os_code.is_empty() {
-            return Ok(());
-        }
-
+        // rules are keyed by os_family_code and os_code, or by os_family_code only
+        // for the "-all-" os_code. When the OS is unknown, run in a degraded mode
+        // which tries the "-all-" rules of every OS family
+        let degraded = info.os_family_code.is_empty();
         let mut word_ids = Vec::new();
-        match self.os_codes.get(&info.os_family_code) {
-            None => (),
-            Some(code) => word_ids.push(*code as u16),
-        };
-        match self.os_codes.get(&info.os_code) {
-            None => (),
-            Some(code) => word_ids.push(*code as u16),
-        };
+        if degraded {
+            word_ids.extend_from_slice(&self.os_family_word_ids);
+        } else {
+            match self.os_codes.get(&info.os_family_code) {
+                None => (),
+                Some(code) => word_ids.push(*code as u16),
+            };
+            match self.os_codes.get(&info.os_code) {
+                None => (),
+                Some(code) => word_ids.push(*code as u16),
+            };
+        }
 
-        let (row_id, capture) = match self.device_name_regexes.get_row_id_and_capture(
-            &ua.as_ref(),
+        let (row_id, capture) = match self.device_name_regexes.get_row_id_and_capture_traced(
+            &ua.bytes(),
             &mut data.device_name_regex_scratch,
             &word_ids.iter(),
+            data.explanation
+                .as_mut()
+                .map(|explanation| &mut explanation.device_name),
         )? {
             None => return Ok(()),
             Some(rid) => rid,
         };
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            ?word_ids,
+            row_id,
+            ?capture,
+            degraded,
+            "device name regex matched"
+        );
 
         let id = match self.device_name_regexes.get_id(row_id) {
             Some(id) => id,
             None => return Ok(()),
         };
-        let capture = match capture {
+        if let Some(explanation) = &mut data.explanation {
+            explanation.device_code_capture = capture.clone();
+        }
+        let capture = match capture.and_then(|range| ua.capture(range)) {
             None => return Ok(()),
             Some(cap) => cap,
         };
 
+        let rule = if degraded {
+            DEVICE_RULE_DEGRADED
+        } else {
+            match self.device_name_regexes.get_words(row_id) {
+                Some(words) if words.len() > 1 => DEVICE_RULE_OS,
+                _ => DEVICE_RULE_OS_FAMILY,
+            }
+        };
+
         let stmt = &mut data.conn.prepare(sql::SQL_DEVICE_NAME_LIST)?;
-        if let Err(err) = stmt.query_row(params![id, &ua.as_ref()[capture]], |row| {
+        if let Err(err) = stmt.query_row(params![id, &*capture], |row| {
             info.device_marketname = row.get(0).unwrap_or_default();
             info.device_brand_code = row.get(1).unwrap_or_default();
             info.device_brand = row.get(2).unwrap_or_default();
-            #[cfg(feature = "url")]
+            info.device_brand_rule = String::from(rule);
+            #[cfg(feature = "homepage")]
             {
-                info.device_brand_info_url = row.get(3).unwrap_or_default();
+                info.device_brand_homepage = row.get(3).unwrap_or_default();
             }
             #[cfg(feature = "icon")]
             {
                 info.device_brand_icon = row.get(4).unwrap_or_default();
                 info.device_brand_icon_big = row.get(5).unwrap_or_default();
             }
+            #[cfg(feature = "url")]
+            {
+                info.device_brand_info_url = row.get(6).unwrap_or_default();
+            }
             Ok(())
         }) {
             match err {
@@ -673,42 +1030,266 @@ impl Udger {
         Ok(())
     }
 
+    /// Name of the in-app client, and its version from the regex capture
     #[cfg(feature = "application")]
-    fn detect_application<T>(&self, ua: &T, data: &mut UdgerData, _info: &mut UaInfo) -> Result<()>
+    fn detect_application<T>(&self, ua: &T, data: &mut UdgerData, info: &mut UaInfo) -> Result<()>
     where
-        T: AsRef<str>,
+        T: UaInput + ?Sized,
     {
-        self.application_words_detector
-            .get_word_ids(&ua.as_ref(), &mut data.app_word_scratch)?;
+        let word_ids = self
+            .application_words_detector
+            .get_word_ids(&ua.bytes(), &mut data.app_word_scratch)?;
+
+        let (row_id, range) = match self.application_regexes.get_row_id_and_capture(
+            &ua.bytes(),
+            &mut data.app_regex_scratch,
+            &word_ids.iter(),
+        )? {
+            None => return Ok(()),
+            Some(v) => v,
+        };
+
+        let stmt = &mut data.conn.prepare(sql::SQL_APPLICATION)?;
+        match stmt.query_row(params![row_id], |row| row.get(0)) {
+            Ok(name) => info.application_name = name,
+            Err(Error::QueryReturnedNoRows) => return Ok(()),
+            Err(err) => return Err(anyhow!(err)),
+        };
+        if let Some(range) = range {
+            info.application_version = ua.capture(range).unwrap_or_default().into_owned();
+        }
         Ok(())
     }
 
-    pub fn parse_ua<T>(&self, ua: &T, data: &mut UdgerData) -> Result<Rc<UaInfo>>
+    /// Run the detectors on a User-Agent
+    fn detect<T>(&self, ua: &T, data: &mut UdgerData) -> Result<UaInfo>
     where
-        T: AsRef<str>,
+        T: UaInput + ?Sized,
     {
-        // info.ua_string = ua.as_ref().to_string();
+        let mut info = UaInfo {
+            ua_string: ua.text().to_string(),
+            ua_charset: ua.charset().to_string(),
+            ..Default::default()
+        };
+
+        let deadline = self.time_budget.map(|budget| Instant::now() + budget);
+        for scratch in data.regex_scratches() {
+            scratch.deadline = deadline;
+            scratch.limit_errors = 0;
+        }
+
+        'detectors: {
+            let client = self.detect_client(ua, data, &mut info);
+            if Udger::interrupted(client, "client", &mut info)?
+                || Udger::expired(deadline, "os", &mut info)
+            {
+                break 'detectors;
+            }
+            let os = self
+                .metrics
+                .time(Detector::Os, || self.detect_os(ua, data, &mut info));
+            if Udger::interrupted(os, "os", &mut info)? {
+                break 'detectors;
+            }
+            #[cfg(feature = "application")]
+            {
+                if Udger::expired(deadline, "application", &mut info) {
+                    break 'detectors;
+                }
+                let application = self.detect_application(ua, data, &mut info);
+                if Udger::interrupted(application, "application", &mut info)? {
+                    break 'detectors;
+                }
+            }
+            if Udger::expired(deadline, "device_class", &mut info) {
+                break 'detectors;
+            }
+            let device_class = self.metrics.time(Detector::DeviceClass, || {
+                self.detect_device_class(ua, data, &mut info)
+            });
+            if Udger::interrupted(device_class, "device_class", &mut info)?
+                || Udger::expired(deadline, "device_brand", &mut info)
+            {
+                break 'detectors;
+            }
+            let device_brand = self.metrics.time(Detector::DeviceBrand, || {
+                self.detect_device_brand(ua, data, &mut info)
+            });
+            Udger::interrupted(device_brand, "device_brand", &mut info)?;
+        }
+        platform::detect_platform(ua.text(), &mut info);
+
+        info.regex_limit_errors = data
+            .regex_scratches()
+            .iter()
+            .map(|scratch| scratch.limit_errors)
+            .sum();
+        Ok(info)
+    }
+
+    /// Whether the time budget of a parse ran out, recording the first detector
+    /// which won't run
+    fn expired(deadline: Option<Instant>, detector: &str, info: &mut UaInfo) -> bool {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            info.parse_aborted = String::from(detector);
+            return true;
+        }
+        false
+    }
+
+    /// Whether a detector was cut by the time budget, recording it in `parse_aborted`.
+    /// Its regex scan was terminated before it set any field, which stay empty
+    fn interrupted(result: Result<()>, detector: &str, info: &mut UaInfo) -> Result<bool> {
+        match result {
+            Err(err) if err.is::<Terminated>() => {
+                info.parse_aborted = String::from(detector);
+                Ok(true)
+            }
+            result => result.map(|_| false),
+        }
+    }
+
+    /// Parse a normalized User-Agent, from the cache if it's UTF-8
+    fn lookup(&self, ua: &[u8], data: &mut UdgerData) -> Result<Rc<UaInfo>> {
+        let ua = match std::str::from_utf8(ua) {
+            Err(_) => return Ok(Rc::new(self.detect(&RawUa::new(ua), data)?)),
+            Ok(ua) => ua,
+        };
         // try to get cached ua info
-        let ua = ua.as_ref().to_string();
-        if let Some(cached) = data.get(&ua) {
+        if let Some(cached) = data.get(ua) {
             return Ok(cached);
         }
+        let info = Rc::new(self.detect(ua, data)?);
+        // an aborted parse may complete next time
+        if info.parse_aborted.is_empty() {
+            data.set(ua, info.clone());
+        }
+        Ok(info)
+    }
 
-        let mut info = Rc::new(UaInfo::default());
-        Rc::get_mut(&mut info).unwrap().ua_string = ua.clone();
+    /// Parse a User-Agent, normalized first and cached by its normalized form
+    pub fn parse_ua<T>(&self, ua: &T, data: &mut UdgerData) -> Result<Rc<UaInfo>>
+    where
+        T: AsRef<str>,
+    {
+        self.parse_ua_bytes(ua.as_ref().as_bytes(), data)
+    }
 
-        self.detect_client(&ua, data, Rc::get_mut(&mut info).unwrap())?;
-        self.detect_os(&ua, data, Rc::get_mut(&mut info).unwrap())?;
-        #[cfg(feature = "application")]
-        {
-            self.detect_application(&ua, data, Rc::get_mut(&mut info).unwrap())?;
+    /// Parse a User-Agent given as raw bytes, the regexes match the bytes and
+    /// a User-Agent which isn't UTF-8 is decoded from its detected charset,
+    /// see `ua_charset`. Only UTF-8 User-Agents are cached
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "parse_ua", skip_all, fields(len = input.len()))
+    )]
+    pub fn parse_ua_bytes(&self, input: &[u8], data: &mut UdgerData) -> Result<Rc<UaInfo>> {
+        let (ua, steps) = self.normalization.apply(input)?;
+        let info = match self.lookup(&ua, data) {
+            Err(err) => {
+                self.metrics.count_error(&err);
+                return Err(err);
+            }
+            Ok(info) => info,
+        };
+        self.metrics.count_parse(info.ua_class_code == UNRECOGNIZED);
+
+        // cached results are shared by the User-Agents normalized alike
+        if steps.is_empty() {
+            return Ok(info);
+        }
+        let mut info = (*info).clone();
+        info.ua_string = original_text(input);
+        info.ua_normalization = steps.join(",");
+        Ok(Rc::new(info))
+    }
+
+    /// Parse a User-Agent bypassing the cache, along with how the detectors
+    /// matched it, to debug misclassifications
+    pub fn parse_ua_explain<T>(
+        &self,
+        ua: &T,
+        data: &mut UdgerData,
+    ) -> Result<(Rc<UaInfo>, Explanation)>
+    where
+        T: AsRef<str>,
+    {
+        let input = ua.as_ref();
+        let (ua, steps) = self.normalization.apply(input.as_bytes())?;
+        data.explanation = Some(Explanation::default());
+        let detected = match std::str::from_utf8(&ua) {
+            Ok(ua) => self.detect(ua, data),
+            Err(_) => self.detect(&RawUa::new(&ua), data),
+        };
+        let explanation = data.explanation.take().unwrap_or_default();
+        let mut info = detected?;
+        if !steps.is_empty() {
+            info.ua_string = String::from(input);
+            info.ua_normalization = steps.join(",");
         }
-        self.detect_device_class(&ua, data, Rc::get_mut(&mut info).unwrap())?;
-        self.detect_device_brand(&ua, data, Rc::get_mut(&mut info).unwrap())?;
+        Ok((Rc::new(info), explanation))
+    }
 
-        data.set(&ua, info.clone());
+    /// Parse a User-Agent along with its product tokens
+    pub fn parse_ua_tokens<'a>(
+        &self,
+        ua: &'a str,
+        data: &mut UdgerData,
+    ) -> Result<(Rc<UaInfo>, UaTokens<'a>)> {
+        Ok((self.parse_ua(&ua, data)?, UaTokens::parse(ua)))
+    }
 
-        Ok(info)
+    /// Parse the User-Agent of a proxy or transcoder along with the User-Agent
+    /// of the device behind it, the proxy is reported as client while the OS
+    /// and device fields are those of the device
+    pub fn parse_proxied_ua<T, U>(
+        &self,
+        ua: &T,
+        device_ua: &U,
+        data: &mut UdgerData,
+    ) -> Result<Rc<UaInfo>>
+    where
+        T: AsRef<str>,
+        U: AsRef<str>,
+    {
+        self.parse_proxied_ua_bytes(ua.as_ref().as_bytes(), device_ua.as_ref().as_bytes(), data)
+    }
+
+    /// `parse_proxied_ua` of raw User-Agents, see `parse_ua_bytes`
+    pub fn parse_proxied_ua_bytes(
+        &self,
+        ua: &[u8],
+        device_ua: &[u8],
+        data: &mut UdgerData,
+    ) -> Result<Rc<UaInfo>> {
+        let device = self.parse_ua_bytes(device_ua, data)?;
+        let mut info = (*self.parse_ua_bytes(ua, data)?).clone();
+        info.set_device_fields(&device);
+        Ok(Rc::new(info))
+    }
+
+    /// Parse the User-Agent of a raw HTTP/1.x header block, see `HttpHeaders`
+    pub fn parse_http_headers(&self, headers: &[u8], data: &mut UdgerData) -> Result<HeadersInfo> {
+        let headers = HttpHeaders::parse(headers);
+        let device_ua = headers.device_user_agent();
+        // the regexes match the headers as sent, not their lossy decoding
+        let ua_info = match (
+            headers.user_agent_bytes.as_deref(),
+            headers.device_user_agent_bytes(),
+        ) {
+            (None, _) => None,
+            (Some(ua), Some(device_ua)) => Some(self.parse_proxied_ua_bytes(ua, device_ua, data)?),
+            (Some(ua), None) => Some(self.parse_ua_bytes(ua, data)?),
+        };
+        Ok(HeadersInfo {
+            ua_info,
+            device_user_agent: device_ua.map(str::to_string),
+            client_ip: headers.client_ip(),
+            client_hints: headers
+                .client_hints
+                .iter()
+                .map(|(name, value)| (name.to_ascii_lowercase(), value.to_string()))
+                .collect(),
+        })
     }
 }
 
@@ -742,6 +1323,9 @@ mod tests {
         assert_eq!(info.ua_family_code, "firefox");
         assert_eq!(info.ua_version, "40.0");
         assert_eq!(info.ua_version_major, "40");
+        assert_eq!(info.ua_version_minor, "0");
+        assert_eq!(info.ua_engine_version, "20100101");
+        assert_eq!(info.ua_engine_version_major, "20100101");
         #[cfg(feature = "homepage")]
         {
             assert_eq!(info.ua_family_homepage, "http://www.firefox.com/");
@@ -777,6 +1361,7 @@ mod tests {
         assert_eq!(info.ua_family_vendor_code, "google_inc");
         assert_eq!(info.ua_version, "2.1");
         assert_eq!(info.ua_version_major, "2");
+        assert_eq!(info.ua_version_minor, "1");
         #[cfg(feature = "homepage")]
         {
             assert_eq!(info.ua_family_homepage, "http://www.google.com/bot.html");
@@ -790,7 +1375,7 @@ mod tests {
             assert_eq!(info.ua_family_icon, "bot_googlebot.png");
             assert_eq!(info.ua_family_icon_big, "");
         }
-        #[cfg(feature = "icon")]
+        #[cfg(feature = "url")]
         {
             assert_eq!(
                 info.ua_family_info_url,
@@ -819,6 +1404,10 @@ mod tests {
         assert_eq!(info.os_family_code, "windows");
         assert_eq!(info.os_family_vendor, "Microsoft Corporation.");
         assert_eq!(info.os_family_vendor_code, "microsoft_corporation");
+        assert_eq!(info.os_source, "matched");
+        assert_eq!(info.os_version, "10.0");
+        assert_eq!(info.os_version_major, "10");
+        assert_eq!(info.os_version_minor, "0");
         #[cfg(feature = "homepage")]
         {
             assert_eq!(
@@ -841,6 +1430,123 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_infer_os() {
+        let mut udger = Udger::new();
+        udger
+            .init(PathBuf::from("./data/udgerdb_v3_test.dat"), 10000)
+            .unwrap();
+
+        let mut data = udger.alloc_udger_data().unwrap();
+        let (client_id, os_code): (u32, String) = data
+            .conn
+            .query_row(
+                "SELECT client_id, MIN(name_code) FROM udger_client_os_relation \
+                JOIN udger_os_list ON udger_os_list.id = os_id \
+                GROUP BY client_id HAVING COUNT(*) = 1 LIMIT 1",
+                params![],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+
+        let mut info = UaInfo {
+            client_id: Some(client_id),
+            ..Default::default()
+        };
+        udger
+            .detect_os(&"no OS token", &mut data, &mut info)
+            .unwrap();
+        assert_eq!(info.os_code, os_code);
+        assert_eq!(info.os_source, "inferred");
+
+        // a client available on several OSes doesn't tell which
+        let client_id: u32 = data
+            .conn
+            .query_row(
+                "SELECT client_id FROM udger_client_os_relation \
+                GROUP BY client_id HAVING COUNT(*) > 1 LIMIT 1",
+                params![],
+                |row| row.get(0),
+            )
+            .unwrap();
+        let mut info = UaInfo {
+            client_id: Some(client_id),
+            ..Default::default()
+        };
+        udger
+            .detect_os(&"no OS token", &mut data, &mut info)
+            .unwrap();
+        assert_eq!(info.os_code, "");
+        assert_eq!(info.os_family, "");
+        assert_eq!(info.os_source, "");
+
+        // nothing to infer from without a client
+        let mut info = UaInfo::default();
+        udger
+            .detect_os(&"no OS token", &mut data, &mut info)
+            .unwrap();
+        assert_eq!(info.os_code, "");
+        assert_eq!(info.os_source, "");
+    }
+
+    #[test]
+    fn test_detect_darwin_os() {
+        let mut udger = Udger::new();
+        udger
+            .init(PathBuf::from("./data/udgerdb_v3_test.dat"), 10000)
+            .unwrap();
+
+        let mut data = udger.alloc_udger_data().unwrap();
+        let os_code: String = data
+            .conn
+            .query_row(
+                "SELECT name_code FROM udger_os_list LIMIT 1",
+                params![],
+                |row| row.get(0),
+            )
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("udger-test-{}.darwin", std::process::id()));
+        std::fs::write(
+            &path,
+            format!(
+                "CFNetwork 1404 ios {} 16.3\nCFNetwork 9999 ios no_such_os 99.0\n",
+                os_code
+            ),
+        )
+        .unwrap();
+        udger.load_darwin_versions(path.clone()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut info = UaInfo::default();
+        let ua = "MyApp/3.2 CFNetwork/1404.0.5 Darwin/22.3.0";
+        assert!(udger.detect_darwin_os(ua, &mut data, &mut info).unwrap());
+        assert_eq!(info.os_code, os_code);
+        assert_eq!(info.os_source, "darwin");
+        assert_eq!(info.os_version, "16.3");
+        assert_eq!(info.os_version_major, "16");
+        assert_eq!(info.os_version_minor, "3");
+
+        // built-in Darwin release
+        let mut info = UaInfo::default();
+        let ua = "MyApp/1.0 CFNetwork/1220.1 Darwin/20.3.0 (x86_64)";
+        assert!(udger.detect_darwin_os(ua, &mut data, &mut info).unwrap());
+        assert_eq!(info.os_version, "11.2");
+
+        let mut info = UaInfo::default();
+        assert!(!udger
+            .detect_darwin_os("curl/7.58.0", &mut data, &mut info)
+            .unwrap());
+        assert_eq!(info.os_source, "");
+
+        // a release whose OS isn't in the database
+        let mut info = UaInfo::default();
+        let ua = "MyApp/1.0 CFNetwork/9999 Darwin/99.0.0";
+        assert!(!udger.detect_darwin_os(ua, &mut data, &mut info).unwrap());
+        assert_eq!(info.os_code, "");
+        assert_eq!(info.os_source, "");
+        assert_eq!(info.os_version, "");
+    }
+
     #[test]
     fn test_detect_device_class() {
         // some regular expressions in udgerdb_v3_test.data's udger_deviceclass_regex_words table
@@ -906,6 +1612,7 @@ mod tests {
         udger.detect_client(&ua, &mut data, &mut info).unwrap();
         udger.detect_os(&ua, &mut data, &mut info).unwrap();
         assert_eq!(info.os_family_code, "ios");
+        assert_eq!(info.os_version, "7.0");
         udger
             .detect_device_class(&ua, &mut data, &mut info)
             .unwrap();
@@ -915,6 +1622,8 @@ mod tests {
 
         assert_eq!(info.device_brand, "Apple");
         assert_eq!(info.device_brand_code, "apple");
+        assert_ne!(info.device_brand_rule, "");
+        assert_ne!(info.device_brand_rule, "degraded");
         #[cfg(feature = "homepage")]
         {
             assert_eq!(info.device_brand_homepage, "http://www.apple.com/");
@@ -933,6 +1642,281 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "application")]
+    #[test]
+    fn test_detect_application() {
+        let mut udger = Udger::new();
+        udger
+            .init(PathBuf::from("./data/udgerdb_v3_full.dat"), 10000)
+            .unwrap();
+
+        let mut data = udger.alloc_udger_data().unwrap();
+        let ua = "Mozilla/5.0 (iPhone; CPU iPhone OS 16_3 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Mobile/15E148 [FBAN/FBIOS;FBAV/402.0.0.27.102;FBBV/475203496;FBDV/iPhone14,5;FBMD/iPhone;FBSN/iOS;FBSV/16.3;FBSS/3;FBCR/;FBID/phone;FBLC/en_US;FBOP/80]";
+        let mut info = UaInfo::default();
+        udger.detect_application(&ua, &mut data, &mut info).unwrap();
+        assert_ne!(info.application_name, "");
+        assert_eq!(info.application_version, "402.0.0.27.102");
+
+        let mut info = UaInfo::default();
+        udger
+            .detect_application(&"curl/7.58.0", &mut data, &mut info)
+            .unwrap();
+        assert_eq!(info.application_name, "");
+        assert_eq!(info.application_version, "");
+    }
+
+    #[test]
+    fn test_populated_fields() {
+        // run under every combination of the application, icon, homepage and
+        // url features (see ci/features), the serialized fields are those enabled
+        let mut udger = Udger::new();
+        udger
+            .init(PathBuf::from("./data/udgerdb_v3_full.dat"), 10000)
+            .unwrap();
+
+        let mut data = udger.alloc_udger_data().unwrap();
+        let ua = String::from(
+            "Mozilla/5.0 (iPad; CPU OS 7_0 like Mac OS X) AppleWebKit/537.51.1 (KHTML, like Gecko) Version/7.0 Mobile/11A465 Safari/9537.53",
+        );
+        let info = udger.parse_ua(&ua, &mut data).unwrap();
+
+        // fields which only apply to other kinds of User-Agents
+        let unset = [
+            "crawler_last_seen",
+            "crawler_respect_robotstxt",
+            "crawler_category",
+            "crawler_category_code",
+            "ua_uptodate_current_version",
+            // set for in-app browsers, see test_detect_application
+            "application_name",
+            "application_version",
+            "cpu_architecture",
+            "bitness",
+            "ua_locale",
+            "ua_normalization",
+            "parse_aborted",
+        ];
+        let fields = serde_json::to_value(&*info).unwrap();
+        for (name, value) in fields.as_object().unwrap() {
+            if unset.contains(&name.as_str()) {
+                continue;
+            }
+            assert_ne!(value, "", "{} is not populated", name);
+        }
+    }
+
+    #[test]
+    fn test_detect_device_brand_degraded() {
+        let mut udger = Udger::new();
+        udger
+            .init(PathBuf::from("./data/udgerdb_v3_full.dat"), 10000)
+            .unwrap();
+
+        let mut data = udger.alloc_udger_data().unwrap();
+        let ua = String::from(
+            "Mozilla/5.0 (Linux; Android 4.4.2; SM-G900F Build/KOT49H) AppleWebKit/537.36 (KHTML, like Gecko) Version/4.0 Chrome/30.0.0.0 Mobile Safari/537.36",
+        );
+
+        // no OS, only the "-all-" rules can match
+        let mut info = UaInfo::default();
+        udger
+            .detect_device_brand(&ua, &mut data, &mut info)
+            .unwrap();
+        assert_ne!(info.device_brand_code, "");
+        assert_eq!(info.device_brand_rule, "degraded");
+
+        // a UA without any model token still matches nothing
+        let mut info = UaInfo::default();
+        udger
+            .detect_device_brand(&"curl/7.58.0", &mut data, &mut info)
+            .unwrap();
+        assert_eq!(info.device_brand_code, "");
+        assert_eq!(info.device_brand_rule, "");
+    }
+
+    #[test]
+    fn test_parse_ua_engine() {
+        let mut udger = Udger::new();
+        udger
+            .init(PathBuf::from("./data/udgerdb_v3_full.dat"), 10000)
+            .unwrap();
+
+        let mut data = udger.alloc_udger_data().unwrap();
+        let ua = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.6099.71 Safari/537.36";
+        let info = udger.parse_ua(&ua, &mut data).unwrap();
+        assert_eq!(info.ua_family_code, "chrome");
+        assert_eq!(info.ua_engine, "WebKit/Blink");
+        assert_eq!(info.ua_engine_version, "120.0.6099.71");
+        assert_eq!(info.ua_engine_version_major, "120");
+    }
+
+    #[test]
+    fn test_parse_ua_bytes() {
+        let mut udger = Udger::new();
+        udger
+            .init(PathBuf::from("./data/udgerdb_v3_full.dat"), 10000)
+            .unwrap();
+
+        let mut data = udger.alloc_udger_data().unwrap();
+        let ua = "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:109.0) Gecko/20100101 Firefox/115.0";
+        let info = udger.parse_ua_bytes(ua.as_bytes(), &mut data).unwrap();
+        assert_eq!(info.ua_charset, "utf-8");
+        assert_eq!(info.ua, "Firefox 115.0");
+
+        // the regexes match the bytes around the invalid UTF-8
+        let mut bytes = ua.as_bytes().to_vec();
+        bytes.extend_from_slice(b" (Caf\xe9)");
+        let info = udger.parse_ua_bytes(&bytes, &mut data).unwrap();
+        assert_ne!(info.ua_charset, "utf-8");
+        assert_eq!(info.ua, "Firefox 115.0");
+        assert_eq!(info.os_code, "windows_10");
+        assert!(info.ua_string.ends_with("(Caf\u{e9})"));
+    }
+
+    #[test]
+    fn test_parse_ua_normalized() {
+        let mut udger = Udger::new();
+        udger
+            .init(PathBuf::from("./data/udgerdb_v3_full.dat"), 10000)
+            .unwrap();
+        let mut data = udger.alloc_udger_data().unwrap();
+        let ua = "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:109.0) Gecko/20100101 Firefox/115.0";
+        // the default normalization leaves the User-Agents as given
+        let spaced = format!(" {}\u{7}", ua);
+        let info = udger.parse_ua(&spaced, &mut data).unwrap();
+        assert_eq!(info.ua_string, spaced);
+        assert_eq!(info.ua_normalization, "");
+
+        udger.set_normalization(Normalization {
+            plus_decode: true,
+            reject_control: true,
+            collapse_whitespace: true,
+            max_length: 1024,
+            ..Default::default()
+        });
+        let info = udger.parse_ua(&ua, &mut data).unwrap();
+        assert_eq!(info.ua_normalization, "");
+
+        // the same User-Agent from a log, served from the cache
+        let logged = format!(" {} ", ua.replace(' ', "+"));
+        let normalized = udger.parse_ua(&logged, &mut data).unwrap();
+        // the input is kept, the detectors matched its normalized form
+        assert_eq!(normalized.ua_string, logged);
+        assert_eq!(normalized.ua, info.ua);
+        assert_eq!(normalized.ua_normalization, "plus_decode,whitespace");
+        assert_eq!(info.ua_normalization, "");
+
+        let long = format!("{} {}", ua, "x".repeat(2000));
+        let truncated = udger.parse_ua(&long, &mut data).unwrap();
+        assert_eq!(truncated.ua_string, long);
+        assert_eq!(truncated.ua_normalization, "truncate");
+
+        assert!(udger.parse_ua(&"Firefox/115.0\u{7}", &mut data).is_err());
+    }
+
+    #[test]
+    fn test_default_limits() {
+        let mut udger = Udger::new();
+        let defaults = Limits::default();
+        let pcre = Some((defaults.match_limit, defaults.match_limit_recursion));
+        assert_eq!(udger.client_regexes.limits, pcre);
+        assert_eq!(udger.os_regexes.limits, pcre);
+        assert_eq!(udger.time_budget, None);
+
+        udger.clear_limits();
+        assert_eq!(udger.client_regexes.limits, None);
+        assert_eq!(udger.device_name_regexes.limits, None);
+    }
+
+    #[test]
+    fn test_parse_ua_limits() {
+        let mut udger = Udger::new();
+        udger.set_limits(Limits {
+            time_budget: Some(Duration::ZERO),
+            ..Default::default()
+        });
+        udger
+            .init(PathBuf::from("./data/udgerdb_v3_full.dat"), 10000)
+            .unwrap();
+
+        let mut data = udger.alloc_udger_data().unwrap();
+        let ua = "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:109.0) Gecko/20100101 Firefox/115.0";
+        let info = udger.parse_ua(&ua, &mut data).unwrap();
+        // the client regex scan is cut, no partial result is kept
+        assert_eq!(info.parse_aborted, "client");
+        assert_eq!(info.ua_class_code, "");
+        assert_eq!(info.os_code, "");
+        assert_eq!(info.regex_limit_errors, 0);
+        // aborted parses aren't cached
+        assert!(data.get(ua).is_none());
+
+        udger.set_limits(Limits::default());
+        let info = udger.parse_ua(&ua, &mut data).unwrap();
+        assert_eq!(info.parse_aborted, "");
+        assert_eq!(info.os_code, "windows_10");
+        assert!(data.get(ua).is_some());
+    }
+
+    #[test]
+    fn test_parse_ua_explain() {
+        let mut udger = Udger::new();
+        udger
+            .init(PathBuf::from("./data/udgerdb_v3_full.dat"), 10000)
+            .unwrap();
+
+        let mut data = udger.alloc_udger_data().unwrap();
+        let ua = "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:109.0) Gecko/20100101 Firefox/115.0";
+        let (info, explanation) = udger.parse_ua_explain(&ua, &mut data).unwrap();
+        assert_eq!(info.ua, "Firefox 115.0");
+        assert!(!explanation.crawler);
+        assert!(!explanation.client.word_ids.is_empty());
+        let winner = explanation.client.winner.unwrap();
+        assert!(explanation
+            .client
+            .candidates
+            .iter()
+            .any(|candidate| candidate.rowid == winner && candidate.words_found));
+        assert_eq!(
+            ua.get(explanation.client_version_capture.unwrap()),
+            Some("115.0")
+        );
+        assert!(explanation.os.winner.is_some());
+        assert_eq!(explanation.device_class_source, explain::DEVICE_CLASS_REGEX);
+        // explained parses bypass the cache
+        assert!(data.get(ua).is_none());
+
+        let ua = "Googlebot/2.1 (+http://www.google.com/bot.html)";
+        let (info, explanation) = udger.parse_ua_explain(&ua, &mut data).unwrap();
+        assert_eq!(info.ua, "Googlebot/2.1");
+        assert!(explanation.crawler);
+        assert!(explanation.client.candidates.is_empty());
+    }
+
+    #[test]
+    fn test_parse_ua_hostile() {
+        let mut udger = Udger::new();
+        udger
+            .init(PathBuf::from("./data/udgerdb_v3_full.dat"), 10000)
+            .unwrap();
+
+        let mut data = udger.alloc_udger_data().unwrap();
+        let uas: &[&[u8]] = &[
+            b"",
+            b" ",
+            b"(",
+            b"Firefox/",
+            b"\xff\xfe\xfd",
+            b"Mozilla/5.0 (Linux; Android \xe9; SM-G900\xe9) Firefox/1\xe9.0",
+            "Mozilla/5.0 (Linux; Android 4.4; SM-G900F\u{301}) Chrome/3\u{663}.0 Safari/537.36"
+                .as_bytes(),
+            "Mozilla/5.0 (iPhone; CPU iPhone OS 1\u{fe0f}_0 like Mac OS X) CFNetwork/\u{1f4a9}"
+                .as_bytes(),
+        ];
+        for ua in uas {
+            udger.parse_ua_bytes(ua, &mut data).unwrap();
+        }
+    }
+
     #[test]
     fn test_unrecognized() {
         let mut udger = Udger::new();
@@ -978,4 +1962,63 @@ mod tests {
         assert_eq!(info.ua_version_major, "");
         assert_eq!(info.ua_version_minor, "");
     }
+
+    #[test]
+    fn test_parse_http_headers() {
+        let mut udger = Udger::new();
+        udger
+            .init(PathBuf::from("./data/udgerdb_v3_test.dat"), 10000)
+            .unwrap();
+
+        let mut data = udger.alloc_udger_data().unwrap();
+        let headers = b"GET / HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            user-agent: Mozilla/5.0 (Windows NT 10.0; WOW64; rv:40.0)\r\n \
+            Gecko/20100101 Firefox/40.0\r\n\
+            Sec-CH-UA-Mobile: ?0\r\n\
+            X-Forwarded-For: 192.0.2.7, 10.0.0.1\r\n\r\n";
+        let parsed = udger.parse_http_headers(headers, &mut data).unwrap();
+        let info = parsed.ua_info.unwrap();
+        assert_eq!(info.ua_family, "Firefox");
+        assert_eq!(info.ua_version, "40.0");
+        assert_eq!(parsed.client_ip, Some("192.0.2.7".parse().unwrap()));
+        assert_eq!(
+            parsed.client_hints,
+            [(String::from("sec-ch-ua-mobile"), String::from("?0"))]
+        );
+
+        // a User-Agent which isn't UTF-8 is parsed as sent
+        let ua = b"Nokia6300/2.0 (05.00) Profile/MIDP-2.0 \xc4\xe7";
+        let mut headers = b"User-Agent: ".to_vec();
+        headers.extend_from_slice(ua);
+        let parsed = udger.parse_http_headers(&headers, &mut data).unwrap();
+        let info = udger.parse_ua_bytes(ua, &mut data).unwrap();
+        assert_eq!(parsed.ua_info.unwrap().ua_string, info.ua_string);
+
+        // nothing to parse, and nothing cached
+        let metrics = udger.metrics().snapshot();
+        let headers = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let parsed = udger.parse_http_headers(headers, &mut data).unwrap();
+        assert!(parsed.ua_info.is_none());
+        assert_eq!(udger.metrics().snapshot(), metrics);
+    }
+
+    #[test]
+    fn test_parse_proxied_ua() {
+        let mut udger = Udger::new();
+        udger
+            .init(PathBuf::from("./data/udgerdb_v3_full.dat"), 10000)
+            .unwrap();
+
+        let mut data = udger.alloc_udger_data().unwrap();
+        let headers = b"User-Agent: Opera/9.80 (J2ME/MIDP; Opera Mini/9.80 (S60; SymbOS; Opera Mobi/23.348; U; en) Presto/2.5.25 Version/10.54\r\n\
+            X-OperaMini-Phone-UA: Mozilla/5.0 (Linux; Android 4.4.2; SM-G900F Build/KOT49H) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/35.0.1916.141 Mobile Safari/537.36\r\n\r\n";
+        let parsed = udger.parse_http_headers(headers, &mut data).unwrap();
+        let info = parsed.ua_info.unwrap();
+        assert_eq!(info.ua_family, "Opera Mini");
+        assert!(info.ua_string.starts_with("Opera/9.80"));
+        assert_eq!(info.os_family, "Android");
+        assert_eq!(info.device_class, "Smartphone");
+        assert!(parsed.device_user_agent.unwrap().contains("SM-G900F"));
+    }
 }
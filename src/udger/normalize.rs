@@ -0,0 +1,200 @@
+use std::borrow::Cow;
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+
+/// `+` decoded to spaces
+pub const PLUS_DECODE: &str = "plus_decode";
+/// `%XX` escapes decoded
+pub const URL_DECODE: &str = "url_decode";
+/// Trimmed and whitespace runs collapsed to a single space
+pub const WHITESPACE: &str = "whitespace";
+/// Cut to `max_length` bytes
+pub const TRUNCATE: &str = "truncate";
+
+/// Error of a User-Agent refused by the normalization, a client error
+#[derive(Debug)]
+pub struct Rejected(String);
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// Pre-processing of the User-Agents before they're matched and cached, the
+/// steps run in the order of the fields. The default doesn't change them
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Normalization {
+    /// Decode `+` to spaces, as in query strings and some log formats
+    pub plus_decode: bool,
+    /// Decode `%XX` escapes, after `+` so that `%2B` stays a `+`
+    pub url_decode: bool,
+    /// Reject User-Agents with control characters other than whitespace
+    pub reject_control: bool,
+    /// Trim and collapse whitespace runs to a single space
+    pub collapse_whitespace: bool,
+    /// Maximum length in bytes, 0 for no limit
+    pub max_length: usize,
+}
+
+fn hex(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+fn url_decode(ua: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(ua.len());
+    let mut i = 0;
+    while i < ua.len() {
+        let escape = match ua.get(i..i + 3) {
+            Some([b'%', high, low]) => hex(*high).zip(hex(*low)),
+            _ => None,
+        };
+        match escape {
+            Some((high, low)) => {
+                decoded.push((high << 4) | low);
+                i += 3;
+            }
+            None => {
+                decoded.push(ua[i]);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+fn collapse_whitespace(ua: &[u8]) -> Vec<u8> {
+    let mut collapsed = Vec::with_capacity(ua.len());
+    for word in ua
+        .split(u8::is_ascii_whitespace)
+        .filter(|word| !word.is_empty())
+    {
+        if !collapsed.is_empty() {
+            collapsed.push(b' ');
+        }
+        collapsed.extend_from_slice(word);
+    }
+    collapsed
+}
+
+/// Length of at most `max_length` bytes not splitting a UTF-8 character
+fn truncated_len(ua: &[u8], max_length: usize) -> usize {
+    match std::str::from_utf8(ua) {
+        Ok(text) => (0..=max_length)
+            .rev()
+            .find(|i| text.is_char_boundary(*i))
+            .unwrap_or(0),
+        Err(_) => max_length,
+    }
+}
+
+impl Normalization {
+    /// Normalize a User-Agent, returns it along with the steps which changed it
+    pub fn apply<'a>(&self, ua: &'a [u8]) -> Result<(Cow<'a, [u8]>, Vec<&'static str>)> {
+        let mut ua = Cow::Borrowed(ua);
+        let mut steps = Vec::new();
+
+        if self.plus_decode && ua.contains(&b'+') {
+            ua = Cow::Owned(
+                ua.iter()
+                    .map(|b| if *b == b'+' { b' ' } else { *b })
+                    .collect(),
+            );
+            steps.push(PLUS_DECODE);
+        }
+        if self.url_decode {
+            let decoded = url_decode(&ua);
+            if decoded != *ua {
+                ua = Cow::Owned(decoded);
+                steps.push(URL_DECODE);
+            }
+        }
+        if self.reject_control {
+            if let Some(i) = ua
+                .iter()
+                .position(|b| b.is_ascii_control() && !b.is_ascii_whitespace())
+            {
+                return Err(anyhow!(Rejected(format!(
+                    "control character {:#04x} at byte {} of the User-Agent",
+                    ua[i], i
+                ))));
+            }
+        }
+        if self.collapse_whitespace {
+            let collapsed = collapse_whitespace(&ua);
+            if collapsed != *ua {
+                ua = Cow::Owned(collapsed);
+                steps.push(WHITESPACE);
+            }
+        }
+        if self.max_length > 0 && ua.len() > self.max_length {
+            let len = truncated_len(&ua, self.max_length);
+            ua = Cow::Owned(ua[..len].to_vec());
+            steps.push(TRUNCATE);
+        }
+
+        Ok((ua, steps))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let normalization = Normalization::default();
+        let raw = format!("  Mozilla/5.0\x00 {}", "x".repeat(2000));
+        let (ua, steps) = normalization.apply(raw.as_bytes()).unwrap();
+        assert!(matches!(ua, Cow::Borrowed(_)));
+        assert_eq!(*ua, *raw.as_bytes());
+        assert!(steps.is_empty());
+
+        let normalization = Normalization {
+            reject_control: true,
+            collapse_whitespace: true,
+            ..Default::default()
+        };
+        let (ua, steps) = normalization.apply(b"Mozilla/5.0 (X11)").unwrap();
+        assert!(matches!(ua, Cow::Borrowed(_)));
+        assert!(steps.is_empty());
+
+        let (ua, steps) = normalization
+            .apply(b"  Mozilla/5.0 \t(X11;\r\n Linux) ")
+            .unwrap();
+        assert_eq!(*ua, *b"Mozilla/5.0 (X11; Linux)");
+        assert_eq!(steps, [WHITESPACE]);
+
+        let err = normalization.apply(b"Mozilla/5.0\x00(X11)").unwrap_err();
+        assert!(err.is::<Rejected>());
+        assert!(normalization.apply(b"Mozilla/5.0\x1b[31m").is_err());
+
+        let normalization = Normalization {
+            plus_decode: true,
+            url_decode: true,
+            reject_control: true,
+            max_length: 20,
+            ..Default::default()
+        };
+        let (ua, steps) = normalization
+            .apply(b"Mozilla/5.0+(X11;%20Linux%2B%zz)")
+            .unwrap();
+        assert_eq!(*ua, *b"Mozilla/5.0 (X11; Li");
+        assert_eq!(steps, [PLUS_DECODE, URL_DECODE, TRUNCATE]);
+        // decoded control characters are rejected as well
+        assert!(normalization.apply(b"Mozilla%00(X11)").is_err());
+
+        let normalization = Normalization {
+            max_length: 5,
+            ..Default::default()
+        };
+        let (ua, steps) = normalization.apply("Caf\u{e9}s".as_bytes()).unwrap();
+        assert_eq!(*ua, *"Caf\u{e9}".as_bytes());
+        assert_eq!(steps, [TRUNCATE]);
+        let (ua, _) = normalization.apply("Nokia 小米".as_bytes()).unwrap();
+        assert_eq!(*ua, *b"Nokia");
+    }
+}
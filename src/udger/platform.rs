@@ -0,0 +1,133 @@
+use crate::UaInfo;
+
+/// Lowercased UA tokens giving the CPU architecture and bitness, in order of preference.
+/// `WOW64` is a 32-bit browser running on 64-bit Windows
+const ARCHITECTURES: &[(&str, &str, &str)] = &[
+    ("aarch64", "arm64", "64"),
+    ("arm64", "arm64", "64"),
+    ("armv8", "arm64", "64"),
+    ("armv8l", "arm64", "64"),
+    ("x86_64", "x86_64", "64"),
+    ("x64", "x86_64", "64"),
+    ("amd64", "x86_64", "64"),
+    ("wow64", "x86_64", "64"),
+    ("ia64", "ia64", "64"),
+    ("ppc64", "ppc64", "64"),
+    ("sparc64", "sparc64", "64"),
+    ("armv7", "arm", "32"),
+    ("armv7l", "arm", "32"),
+    ("armv6", "arm", "32"),
+    ("armv6l", "arm", "32"),
+    ("armv5", "arm", "32"),
+    ("armv5tel", "arm", "32"),
+    ("arm", "arm", "32"),
+    ("i686", "x86", "32"),
+    ("i586", "x86", "32"),
+    ("i486", "x86", "32"),
+    ("i386", "x86", "32"),
+    ("x86", "x86", "32"),
+    ("ppc", "ppc", "32"),
+    ("powerpc", "ppc", "32"),
+];
+
+/// Platforms which tell the architecture but not the bitness
+const PLATFORMS: &[(&str, &str)] = &[("Intel Mac OS X", "x86"), ("PPC Mac OS X", "ppc")];
+
+/// CPU architecture and bitness, empty when the UA doesn't tell
+fn architecture(ua: &str) -> (&'static str, &'static str) {
+    let tokens = ua
+        .split(|c: char| c.is_whitespace() || "();,/".contains(c))
+        .filter(|token| !token.is_empty())
+        .map(str::to_ascii_lowercase)
+        .collect::<Vec<String>>();
+    ARCHITECTURES
+        .iter()
+        .find(|(name, _, _)| tokens.iter().any(|token| token == name))
+        .map(|(_, architecture, bitness)| (*architecture, *bitness))
+        .or_else(|| {
+            PLATFORMS
+                .iter()
+                .find(|(name, _)| ua.contains(name))
+                .map(|(_, architecture)| (*architecture, ""))
+        })
+        .unwrap_or_default()
+}
+
+/// Locale tag like `en-US` or `pt_br` found among the `;` separated items of the
+/// UA comments, normalized to `pt-BR`
+fn locale(ua: &str) -> Option<String> {
+    ua.split(['(', ')', ';', '[', ']'])
+        .map(str::trim)
+        .find_map(|item| {
+            let (language, region) = item.split_once(['-', '_'])?;
+            let alphabetic = |s: &str, lens: &[usize]| {
+                lens.contains(&s.len()) && s.bytes().all(|b| b.is_ascii_alphabetic())
+            };
+            (alphabetic(language, &[2, 3]) && alphabetic(region, &[2])).then(|| {
+                format!(
+                    "{}-{}",
+                    language.to_ascii_lowercase(),
+                    region.to_ascii_uppercase()
+                )
+            })
+        })
+}
+
+/// Fill the `cpu_architecture`, `bitness` and `ua_locale` of `info` from UA tokens
+pub fn detect_platform(ua: &str, info: &mut UaInfo) {
+    let (architecture, bitness) = architecture(ua);
+    info.cpu_architecture = String::from(architecture);
+    info.bitness = String::from(bitness);
+    info.ua_locale = locale(ua).unwrap_or_default();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_architecture() {
+        let uas = [
+            ("Mozilla/5.0 (Windows NT 10.0; WOW64; rv:40.0) Gecko/20100101 Firefox/40.0", ("x86_64", "64")),
+            ("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36", ("x86_64", "64")),
+            ("Mozilla/5.0 (X11; Linux aarch64; rv:109.0) Gecko/20100101 Firefox/115.0", ("arm64", "64")),
+            ("Mozilla/5.0 (X11; CrOS armv7l 13597.84.0) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/88.0.4324.186 Safari/537.36", ("arm", "32")),
+            ("Mozilla/5.0 (X11; Ubuntu; Linux i686; rv:52.0) Gecko/20100101 Firefox/52.0", ("x86", "32")),
+            ("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.1 Safari/605.1.15", ("x86", "")),
+            ("Mozilla/5.0 (Windows NT 6.1) AppleWebKit/537.36 (KHTML, like Gecko)", ("", "")),
+        ];
+        for (ua, expected) in uas {
+            assert_eq!(architecture(ua), expected, "{}", ua);
+        }
+    }
+
+    #[test]
+    fn test_locale() {
+        assert_eq!(
+            locale("Mozilla/5.0 (Windows; U; Windows NT 5.1; en-US; rv:1.8.1.6) Gecko/20070725 Firefox/2.0.0.6").as_deref(),
+            Some("en-US")
+        );
+        assert_eq!(
+            locale("Mozilla/5.0 (Linux; U; Android 2.3.6; pt_br; GT-S5360B Build/GINGERBREAD)")
+                .as_deref(),
+            Some("pt-BR")
+        );
+        assert_eq!(
+            locale("Opera/9.80 (Windows NT 6.1; U; [de-DE]) Presto/2.10.229").as_deref(),
+            Some("de-DE")
+        );
+        assert_eq!(
+            locale("Mozilla/5.0 (X11; Linux x86_64) Chrome/120.0.0.0"),
+            None
+        );
+
+        let mut info = UaInfo::default();
+        detect_platform(
+            "Mozilla/5.0 (X11; U; Linux x86_64; fr-FR) Firefox/3.6",
+            &mut info,
+        );
+        assert_eq!(info.cpu_architecture, "x86_64");
+        assert_eq!(info.bitness, "64");
+        assert_eq!(info.ua_locale, "fr-FR");
+    }
+}
@@ -1,20 +1,162 @@
 use std::collections::HashMap;
+use std::ffi::CString;
+use std::fmt;
 use std::ops::Range;
+use std::ptr;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
+use foreign_types::ForeignType;
 use hyperscan::chimera::prelude::*;
-use hyperscan::chimera::Capture;
+use hyperscan::chimera::{ffi, Capture, CompileError, Mode};
+use libc::{c_uint, c_ulong};
+use serde::Serialize;
+
+/// Bounds of the regex evaluation of a User-Agent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// PCRE match limit of the chimera databases
+    pub match_limit: u64,
+    /// PCRE recursion limit of the chimera databases
+    pub match_limit_recursion: u64,
+    /// Time after which a parse stops running the remaining detectors, checked
+    /// between the regex matches: a long match is only bounded by the PCRE limits
+    pub time_budget: Option<Duration>,
+}
+
+impl Default for Limits {
+    /// PCRE's defaults, without time budget, those of `Udger::new`
+    fn default() -> Self {
+        Limits {
+            match_limit: 10_000_000,
+            match_limit_recursion: 10_000_000,
+            time_budget: None,
+        }
+    }
+}
+
+/// Compile a chimera database with PCRE match limits. hyperscan 0.3 takes them
+/// in `Builder::for_platform` as a `MatchLimit` it doesn't export, so call
+/// `ch_compile_ext_multi` the same way it does
+fn build_with_limits(
+    patterns: &Patterns,
+    mode: Mode,
+    match_limit: u64,
+    match_limit_recursion: u64,
+) -> Result<Database> {
+    let expressions = patterns
+        .iter()
+        .map(|pattern| CString::new(pattern.expression.as_str()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let ptrs = expressions
+        .iter()
+        .map(|expression| expression.as_ptr())
+        .collect::<Vec<_>>();
+    let flags = patterns
+        .iter()
+        .map(|pattern| pattern.flags.bits() as c_uint)
+        .collect::<Vec<_>>();
+    let ids = patterns
+        .iter()
+        .enumerate()
+        .map(|(i, pattern)| pattern.id.unwrap_or(i) as c_uint)
+        .collect::<Vec<_>>();
+
+    let mut db = ptr::null_mut();
+    let mut err = ptr::null_mut();
+    unsafe {
+        let ret = ffi::ch_compile_ext_multi(
+            ptrs.as_ptr(),
+            flags.as_ptr(),
+            ids.as_ptr(),
+            ptrs.len() as c_uint,
+            mode as c_uint,
+            match_limit as c_ulong,
+            match_limit_recursion as c_ulong,
+            ptr::null(),
+            &mut db,
+            &mut err,
+        );
+        if ret != ffi::CH_SUCCESS as ffi::ch_error_t || db.is_null() {
+            return Err(match err.is_null() {
+                true => anyhow!("chimera compile error {}", ret),
+                // freed when dropped
+                false => anyhow!("{}", CompileError::from_ptr(err)),
+            });
+        }
+        Ok(Database::from_ptr(db))
+    }
+}
+
+/// Error of a scan cut by the deadline of its scratch, whose matches are partial
+#[derive(Debug)]
+pub struct Terminated;
+
+impl fmt::Display for Terminated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("regex scan terminated past the deadline")
+    }
+}
+
+impl std::error::Error for Terminated {}
+
+/// Rule whose regex matched a User-Agent
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Candidate {
+    pub rowid: u16,
+    pub sequence: u16,
+    /// Word ids the rule requires
+    pub words: Vec<u16>,
+    /// Whether the User-Agent has all the required words
+    pub words_found: bool,
+    /// Span of the first capture group
+    pub capture: Option<Range<usize>>,
+}
+
+/// Rules considered by a `RegexSequence`, in sequence order
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RegexTrace {
+    /// Word ids given to the sequence
+    pub word_ids: Vec<u16>,
+    pub candidates: Vec<Candidate>,
+    /// Rowid of the first candidate which has its words
+    pub winner: Option<u16>,
+}
+
+/// Whether all the words of a rule are among the word ids
+fn has_words<'a, I>(word_vec: &[u16], word_ids: &I) -> bool
+where
+    I: Iterator<Item = &'a u16> + Clone,
+{
+    let mut found_word_count = 0;
+    for wid in word_ids.clone() {
+        match word_vec.iter().find(|id| **id == *wid) {
+            None => {}
+            Some(_) => found_word_count += 1,
+        }
+    }
+    found_word_count >= word_vec.len()
+}
 
 pub struct RegexSequenceScratch {
     /// Scratch name
     pub name: String,
     raw: Scratch,
+    /// Scans stop matching past the deadline
+    pub deadline: Option<Instant>,
+    /// Number of rules skipped because they hit a match or recursion limit
+    pub limit_errors: u32,
 }
 
 impl RegexSequenceScratch {
     pub fn new(name: String, scratch: Scratch) -> RegexSequenceScratch {
-        RegexSequenceScratch { name, raw: scratch }
+        RegexSequenceScratch {
+            name,
+            raw: scratch,
+            deadline: None,
+            limit_errors: 0,
+        }
     }
 
     pub fn raw(&mut self) -> &mut Scratch {
@@ -26,6 +168,8 @@ impl RegexSequenceScratch {
 pub struct RegexSequence {
     pub name: String,
     pub need_capture: bool,
+    /// PCRE match and recursion limits, chimera's defaults if `None`
+    pub limits: Option<(u64, u64)>,
     db: Option<Database>,
     id_word_map: HashMap<u16, Vec<u16>>,
     rowid_sequence_map: HashMap<u16, u16>,
@@ -50,11 +194,19 @@ impl RegexSequence {
         T: AsRef<[u8]>,
     {
         let mut id_seqs = Vec::new();
+        let deadline = scratch.deadline;
+        let expired = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        let mut limit_errors = 0;
+        let mut terminated = false;
         if let Some(db) = &self.db() {
-            db.scan(
+            let scanned = db.scan(
                 ua.as_ref(),
                 scratch.raw(),
                 |id, _from, _to, _size, captured| {
+                    if expired() {
+                        terminated = true;
+                        return Matching::Terminate;
+                    }
                     let seq = match self.rowid_sequence_map().get(&(id as u16)) {
                         // if no matching id is found, continue matching
                         None => return Matching::Continue,
@@ -74,8 +226,18 @@ impl RegexSequence {
 
                     Matching::Continue
                 },
-                |_err_type, _id| Matching::Continue,
-            )?;
+                // the rule hit a PCRE limit, skip it
+                |_err_type, _id| {
+                    limit_errors += 1;
+                    Matching::Continue
+                },
+            );
+            scratch.limit_errors += limit_errors;
+            // a lower sequence rule may have been cut, the matches can't be trusted
+            if terminated {
+                return Err(anyhow!(Terminated));
+            }
+            scanned?;
         }
 
         // sort ids by sequence
@@ -131,11 +293,18 @@ impl RegexSequence {
             self.rowid_id_map.insert(*rowid, *id);
         });
 
-        if self.need_capture {
-            self.db = Some(Patterns::from(patterns).with_groups()?);
-        } else {
-            self.db = Some(Patterns::from(patterns).build()?);
-        }
+        let patterns = Patterns::from(patterns);
+        self.db = Some(match self.limits {
+            Some((match_limit, match_limit_recursion)) => {
+                let mode = match self.need_capture {
+                    true => Mode::Groups,
+                    false => Mode::NoGroups,
+                };
+                build_with_limits(&patterns, mode, match_limit, match_limit_recursion)?
+            }
+            None if self.need_capture => patterns.with_groups()?,
+            None => patterns.build()?,
+        });
 
         Ok(())
     }
@@ -174,15 +343,7 @@ impl RegexSequence {
                 None => continue,
                 Some(vec) => vec,
             };
-
-            let mut found_word_count = 0;
-            for wid in word_ids.clone() {
-                match (*word_vec).iter().find(|id| **id == *wid) {
-                    None => {}
-                    Some(_) => found_word_count += 1,
-                }
-            }
-            if found_word_count >= word_vec.len() {
+            if has_words(word_vec, word_ids) {
                 return Ok(Some((*id, range.clone())));
             }
         }
@@ -190,10 +351,63 @@ impl RegexSequence {
         Ok(None)
     }
 
+    /// Same as `get_row_id_and_capture`, recording every candidate in `trace`
+    pub fn get_row_id_and_capture_traced<'a, T, I>(
+        &self,
+        ua: &T,
+        scratch: &mut RegexSequenceScratch,
+        word_ids: &I,
+        trace: Option<&mut RegexTrace>,
+    ) -> Result<Option<(u16, Option<Range<usize>>)>>
+    where
+        T: AsRef<[u8]>,
+        I: Iterator<Item = &'a u16> + Clone,
+    {
+        let trace = match trace {
+            None => return self.get_row_id_and_capture(ua, scratch, word_ids),
+            Some(trace) => trace,
+        };
+        trace.word_ids = word_ids.clone().copied().collect();
+        if trace.word_ids.is_empty() {
+            return Ok(None);
+        }
+
+        let mut found = None;
+        for (id, range) in self.get_ids(ua, scratch)? {
+            let word_vec = match self.id_word_map.get(&id) {
+                None => continue,
+                Some(vec) => vec,
+            };
+            let words_found = has_words(word_vec, word_ids);
+            if words_found && found.is_none() {
+                found = Some((id, range.clone()));
+            }
+            trace.candidates.push(Candidate {
+                rowid: id,
+                sequence: self
+                    .rowid_sequence_map
+                    .get(&id)
+                    .copied()
+                    .unwrap_or_default(),
+                words: word_vec.clone(),
+                words_found,
+                capture: range,
+            });
+        }
+        trace.winner = found.as_ref().map(|(id, _)| *id);
+
+        Ok(found)
+    }
+
     /// Get the actual id column of the sqlite table
     pub fn get_id(&self, rowid: u16) -> Option<u16> {
         self.rowid_id_map.get(&rowid).copied()
     }
+
+    /// Get the word ids a row requires to match
+    pub fn get_words(&self, rowid: u16) -> Option<&[u16]> {
+        self.id_word_map.get(&rowid).map(Vec::as_slice)
+    }
 }
 
 #[cfg(test)]
@@ -308,4 +522,94 @@ mod tests {
 
         assert!(matches!(id, Some(_)));
     }
+
+    #[test]
+    fn test_get_row_id_traced() {
+        let mut regex_seq = RegexSequence {
+            need_capture: true,
+            ..Default::default()
+        };
+
+        let rowids: Vec<u16> = vec![0, 1, 2];
+        let ids: Vec<u16> = vec![1, 2, 3];
+        let regexes = [r"word (regex)", r"(regex)", r"(sentence)"];
+        let sequences: Vec<u16> = vec![10, 20, 30];
+        let word1s: Vec<u16> = vec![4, 1, 1];
+        let word2s: Vec<u16> = vec![0, 0, 0];
+
+        regex_seq
+            .init(
+                rowids.iter(),
+                ids.iter(),
+                regexes.iter(),
+                sequences.iter(),
+                word1s.iter(),
+                word2s.iter(),
+            )
+            .unwrap();
+
+        let mut scratch = regex_seq.alloc_scratch().unwrap();
+        let ua = "This is a sentence contains the word regex";
+        let word_ids: Vec<u16> = vec![1, 2];
+        let mut trace = RegexTrace::default();
+        let id = regex_seq
+            .get_row_id_and_capture_traced(&ua, &mut scratch, &word_ids.iter(), Some(&mut trace))
+            .unwrap();
+        assert_eq!(id, Some((1, Some(37..42))));
+        assert_eq!(trace.word_ids, word_ids);
+        assert_eq!(trace.winner, Some(1));
+        let candidates = trace
+            .candidates
+            .iter()
+            .map(|c| (c.rowid, c.sequence, c.words_found))
+            .collect::<Vec<_>>();
+        assert_eq!(candidates, [(0, 10, false), (1, 20, true), (2, 30, true)]);
+        assert_eq!(trace.candidates[0].words, [4]);
+
+        let untraced = regex_seq
+            .get_row_id_and_capture_traced(&ua, &mut scratch, &word_ids.iter(), None)
+            .unwrap();
+        assert_eq!(untraced, id);
+    }
+
+    #[test]
+    fn test_get_row_id_past_deadline() {
+        let mut regex_seq = RegexSequence {
+            limits: Some((1000, 100)),
+            ..Default::default()
+        };
+
+        let rowids: Vec<u16> = vec![0];
+        let ids: Vec<u16> = vec![1];
+        let regexes = [r"(regex)"];
+        let sequences: Vec<u16> = vec![10];
+        let word1s: Vec<u16> = vec![1];
+        let word2s: Vec<u16> = vec![0];
+
+        regex_seq
+            .init(
+                rowids.iter(),
+                ids.iter(),
+                regexes.iter(),
+                sequences.iter(),
+                word1s.iter(),
+                word2s.iter(),
+            )
+            .unwrap();
+
+        let mut scratch = regex_seq.alloc_scratch().unwrap();
+        let ua = "This is a sentence contains the word regex";
+        let word_ids: Vec<u16> = vec![1];
+        let id = regex_seq
+            .get_row_id_and_capture(&ua, &mut scratch, &word_ids.iter())
+            .unwrap();
+        assert_eq!(id.map(|id| id.0), Some(0));
+        assert_eq!(scratch.limit_errors, 0);
+
+        scratch.deadline = Some(Instant::now());
+        let err = regex_seq
+            .get_row_id_and_capture(&ua, &mut scratch, &word_ids.iter())
+            .unwrap_err();
+        assert!(err.is::<Terminated>());
+    }
 }
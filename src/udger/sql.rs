@@ -68,7 +68,7 @@ const OS_COLUMNS: &str = "family AS os_family, \
     family_code AS os_family_code, \
     name AS os, \
     name_code AS os_code, \
-    homepage AS os_home_page, \
+    homepage AS os_homepage, \
     icon AS os_icon, \
     icon_big AS os_icon_big, \
     vendor AS os_family_vendor, \
@@ -87,9 +87,12 @@ pub const SQL_DEVICE_NAME_LIST: &str = "SELECT \
     marketname, \
     brand_code,\
     brand, \
-    brand_url, \
+    brand_url AS device_brand_homepage, \
     icon, \
-    icon_big \
+    icon_big, \
+    'https://udger.com/resources/ua-list/devices-brand-detail?brand=' || REPLACE(brand_code, ' ', \
+    '%20') \
+    AS device_brand_info_url \
     FROM \
     udger_devicename_list \
     JOIN \
@@ -97,6 +100,26 @@ pub const SQL_DEVICE_NAME_LIST: &str = "SELECT \
     WHERE \
     regex_id = ? AND code = ?";
 
+#[cfg(feature = "application")]
+pub const SQL_APPLICATION: &str = "SELECT \
+    name AS application_name \
+    FROM \
+    udger_application_regex ar \
+    JOIN \
+    udger_application_list ON udger_application_list.id = ar.application_id \
+    WHERE \
+    ar.rowid = ?";
+
+pub const SQL_CLIENT_OS_CODES: &str = "SELECT \
+    name_code, \
+    family_code \
+    FROM \
+    udger_client_os_relation \
+    JOIN \
+    udger_os_list ON udger_os_list.id = udger_client_os_relation.os_id \
+    WHERE \
+    client_id = ?";
+
 lazy_static! {
     pub static ref SQL_OS: String = format!(
         "{}{}{}{}{}{}{}{}{}",
@@ -110,8 +133,9 @@ lazy_static! {
         "WHERE ",
         "ur.rowid=?"
     );
+    /// The OS of a client available on that single OS
     pub static ref SQL_CLIENT_OS: String = format!(
-        "{}{}{}{}{}{}{}{}",
+        "{}{}{}{}{}{}{}{}{}",
         "SELECT ",
         OS_COLUMNS,
         "FROM ",
@@ -119,7 +143,17 @@ lazy_static! {
         "JOIN ",
         "udger_os_list ON udger_os_list.id = udger_client_os_relation.os_id ",
         "WHERE ",
-        "client_id = ?"
+        "client_id = ?1 ",
+        "AND (SELECT COUNT(*) FROM udger_client_os_relation r WHERE r.client_id = ?1) = 1"
+    );
+    pub static ref SQL_OS_CODE: String = format!(
+        "{}{}{}{}{}{}",
+        "SELECT ",
+        OS_COLUMNS,
+        "FROM ",
+        "udger_os_list ",
+        "WHERE ",
+        "name_code = ?"
     );
     pub static ref SQL_DEVICE: String = format!(
         "{}{}{}{}{}{}{}{}{}",
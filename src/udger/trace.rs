@@ -0,0 +1,67 @@
+#[cfg(feature = "tracing")]
+use std::time::Instant;
+
+/// Span of a table load or a hyperscan compile of `Udger::init`, a no-op
+/// without the `tracing` feature
+pub(crate) struct InitStep {
+    #[cfg(feature = "tracing")]
+    span: tracing::span::EnteredSpan,
+    #[cfg(feature = "tracing")]
+    started: Instant,
+}
+
+impl InitStep {
+    #[cfg(feature = "tracing")]
+    fn enter(span: tracing::Span) -> InitStep {
+        InitStep {
+            span: span.entered(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Loading the rows of a table
+    #[allow(unused_variables)]
+    pub fn load(table: &str) -> InitStep {
+        #[cfg(feature = "tracing")]
+        return InitStep::enter(tracing::info_span!(
+            "load",
+            table,
+            rows = tracing::field::Empty,
+            duration_ms = tracing::field::Empty
+        ));
+        #[cfg(not(feature = "tracing"))]
+        InitStep {}
+    }
+
+    /// Compiling the patterns loaded from a table
+    #[allow(unused_variables)]
+    pub fn compile(table: &str, patterns: usize) -> InitStep {
+        #[cfg(feature = "tracing")]
+        return InitStep::enter(tracing::info_span!(
+            "compile",
+            table,
+            patterns,
+            duration_ms = tracing::field::Empty
+        ));
+        #[cfg(not(feature = "tracing"))]
+        InitStep {}
+    }
+
+    #[allow(unused_variables)]
+    pub fn rows(&self, rows: usize) {
+        #[cfg(feature = "tracing")]
+        self.span.record("rows", rows);
+    }
+
+    /// End the step, which also ends when dropped on an error
+    pub fn finish(self) {}
+}
+
+#[cfg(feature = "tracing")]
+impl Drop for InitStep {
+    fn drop(&mut self) {
+        let duration = self.started.elapsed();
+        self.span
+            .record("duration_ms", duration.as_secs_f64() * 1e3);
+    }
+}
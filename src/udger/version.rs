@@ -0,0 +1,100 @@
+/// Minor part of a dotted version, empty if there is none
+pub fn version_minor(version: &str) -> String {
+    version.split('.').nth(1).unwrap_or_default().to_string()
+}
+
+/// UA tokens carrying the version of an engine, by `udger_client_list.engine`, in order of
+/// preference. Engines not listed here are looked up as `<ua_engine>/`
+const ENGINE_TOKENS: &[(&str, &[&str])] = &[
+    ("WebKit", &["AppleWebKit/", "WebKit/"]),
+    ("WebKit/Blink", &["Chrome/", "AppleWebKit/"]),
+    ("EdgeHTML", &["Edge/"]),
+];
+
+/// UA tokens carrying the version of an OS, by `os_family_code`, in order of preference
+const OS_TOKENS: &[(&str, &[&str])] = &[
+    ("android", &["Android "]),
+    ("ios", &["CPU OS ", "CPU iPhone OS ", "iPhone OS ", "iOS "]),
+    ("osx", &["Mac OS X "]),
+    ("windows", &["Windows NT "]),
+    ("windows_phone", &["Windows Phone OS ", "Windows Phone "]),
+];
+
+/// Leading run of `text` made of `accept`ed chars, without trailing separators
+fn prefix(text: &str, accept: fn(char) -> bool) -> Option<&str> {
+    let len = text.find(|c: char| !accept(c)).unwrap_or(text.len());
+    let version = text[..len].trim_end_matches(['.', '_']);
+    (!version.is_empty()).then_some(version)
+}
+
+/// Version of the rendering engine `engine` found in `ua`
+pub fn engine_version<'a>(ua: &'a str, engine: &str) -> Option<&'a str> {
+    if engine.is_empty() {
+        return None;
+    }
+    let generic = format!("{}/", engine);
+    let generic = [generic.as_str()];
+    let tokens = ENGINE_TOKENS
+        .iter()
+        .find(|(name, _)| *name == engine)
+        .map_or(&generic[..], |(_, tokens)| tokens);
+
+    tokens.iter().find_map(|token| {
+        let start = ua.find(token)? + token.len();
+        prefix(&ua[start..], |c| c.is_ascii_alphanumeric() || c == '.')
+    })
+}
+
+/// Normalized OS version at the start of `text`, `10_15_7` becoming `10.15.7`
+pub fn os_version(text: &str) -> Option<String> {
+    if !text.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    prefix(text, |c| c.is_ascii_digit() || c == '.' || c == '_').map(|v| v.replace('_', "."))
+}
+
+/// Version of the OS family `os_family_code` found in `ua`
+pub fn find_os_version(ua: &str, os_family_code: &str) -> Option<String> {
+    let (_, tokens) = OS_TOKENS.iter().find(|(code, _)| *code == os_family_code)?;
+    tokens.iter().find_map(|token| {
+        let start = ua.find(token)? + token.len();
+        os_version(&ua[start..])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_engine_version() {
+        let ua = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.6099.71 Safari/537.36";
+        assert_eq!(engine_version(ua, "WebKit"), Some("537.36"));
+        assert_eq!(engine_version(ua, "WebKit/Blink"), Some("120.0.6099.71"));
+        assert_eq!(engine_version(ua, "Blink"), None);
+        let ua = "Mozilla/5.0 (Windows NT 6.1; Trident/7.0; rv:11.0) like Gecko";
+        assert_eq!(engine_version(ua, "Trident"), Some("7.0"));
+        assert_eq!(engine_version(ua, "Gecko"), None);
+        let ua = "Opera/9.80 (Windows NT 6.1) Presto/2.12.388 Version/12.16";
+        assert_eq!(engine_version(ua, "Presto"), Some("2.12.388"));
+        assert_eq!(engine_version(ua, ""), None);
+    }
+
+    #[test]
+    fn test_os_version() {
+        let ua = "Mozilla/5.0 (iPad; CPU OS 7_0 like Mac OS X) AppleWebKit/537.51.1 (KHTML, like Gecko) Version/7.0 Mobile/11A465 Safari/9537.53";
+        assert_eq!(find_os_version(ua, "ios").as_deref(), Some("7.0"));
+        let ua = "Mozilla/5.0 (Linux; Android 13; Pixel 7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/116.0.0.0 Mobile Safari/537.36";
+        assert_eq!(find_os_version(ua, "android").as_deref(), Some("13"));
+        let ua = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.1 Safari/605.1.15";
+        assert_eq!(find_os_version(ua, "osx").as_deref(), Some("10.15.7"));
+        let ua = "Mozilla/5.0 (Windows NT 6.1; Trident/7.0; rv:11.0) like Gecko";
+        assert_eq!(find_os_version(ua, "windows").as_deref(), Some("6.1"));
+        assert_eq!(find_os_version(ua, "linux"), None);
+
+        assert_eq!(os_version("10_15_ like").as_deref(), Some("10.15"));
+        assert_eq!(os_version("X 10").as_deref(), None);
+        assert_eq!(version_minor("10.15.7"), "15");
+        assert_eq!(version_minor("13"), "");
+    }
+}